@@ -1,10 +1,13 @@
-use tonic::{Code, Request, Response, Status};
+use tonic::{Request, Response, Status};
 
-use metastore::{DatabaseInterface, LockDataRef, ObjectPath, SelfContainedDb, TypedValue};
+use metastore::{DatabaseInterface, LockDataRef, ObjectPath, Savepoint, SelfContainedDb, TypedValue};
+
+use crate::grpc_errors::{network_to_status, to_status};
 
 use crate::grpc_defs;
 use crate::grpc_defs::{
-    Empty, LockDataRefId, ReadRequest, Value, ValueRanged, WriteError, WriteRequest,
+    Empty, LockDataRefId, ReadRequest, RollbackRequest, Value, ValueRanged, WriteError,
+    WriteRequest,
 };
 
 pub struct FollowerGRPCServer(SelfContainedDb);
@@ -65,8 +68,8 @@ impl grpc_defs::replicator_server::Replicator for FollowerGRPCServer {
             .0
             .serve_read(lockdataref, &key)
             .0
-            .map_err(|err| Status::new(Code::Unavailable, "Unavailable"))?
-            .unwrap();
+            .map_err(network_to_status)?
+            .map_err(to_status)?;
 
         let res = grpc_defs::value::Res::Val(res.into_inner().1.to_string());
         let val = Value { res: Some(res) };
@@ -86,15 +89,53 @@ impl grpc_defs::replicator_server::Replicator for FollowerGRPCServer {
     ) -> Result<Response<WriteError>, Status> {
         let (txn, key, value) = request.into_inner().into();
         log::debug!("(Follower) Written {} {}", key, &value);
-        self.0.serve_write(txn, &key, value);
+        self.0
+            .serve_write(txn, &key, value)
+            .0
+            .map_err(network_to_status)?
+            .map_err(to_status)?;
 
         Ok(Response::new(WriteError { res: None }))
     }
 
+    async fn prepare(&self, request: Request<LockDataRefId>) -> Result<Response<Empty>, Status> {
+        let request: LockDataRef = request.into_inner().into();
+
+        self.0
+            .prepare(request)
+            .0
+            .map_err(network_to_status)?
+            .map_err(to_status)?;
+        log::debug!("Prepared {}", request.id);
+
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn rollback_to(
+        &self,
+        request: Request<RollbackRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let request = request.into_inner();
+        let txn: LockDataRef = request.txn.unwrap().into();
+
+        self.0
+            .rollback_to(txn, Savepoint(request.savepoint as usize))
+            .0
+            .map_err(network_to_status)?
+            .map_err(to_status)?;
+        log::debug!("Rolled back {} to {}", txn.id, request.savepoint);
+
+        Ok(Response::new(Empty {}))
+    }
+
     async fn commit(&self, request: Request<LockDataRefId>) -> Result<Response<Empty>, Status> {
         let request: LockDataRef = request.into_inner().into();
 
-        self.0.commit(request);
+        self.0
+            .commit(request)
+            .0
+            .map_err(network_to_status)?
+            .map_err(to_status)?;
         log::debug!("Committed {}", request.id);
 
         Ok(Response::new(Empty {}))
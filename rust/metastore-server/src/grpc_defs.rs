@@ -27,6 +27,13 @@ pub struct WriteRequest {
     pub kv: ::core::option::Option<Kv>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RollbackRequest {
+    #[prost(message, optional, tag = "1")]
+    pub txn: ::core::option::Option<LockDataRefId>,
+    #[prost(uint64, tag = "2")]
+    pub savepoint: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
     #[prost(oneof = "value::Res", tags = "1, 2")]
     pub res: ::core::option::Option<value::Res>,
@@ -208,6 +215,34 @@ pub mod replicator_client {
             let path = http::uri::PathAndQuery::from_static("/grpc_defs.Replicator/serve_write");
             self.inner.unary(request.into_request(), path, codec).await
         }
+        pub async fn prepare(
+            &mut self,
+            request: impl tonic::IntoRequest<super::LockDataRefId>,
+        ) -> Result<tonic::Response<super::Empty>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/grpc_defs.Replicator/prepare");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        pub async fn rollback_to(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RollbackRequest>,
+        ) -> Result<tonic::Response<super::Empty>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/grpc_defs.Replicator/rollback_to");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
         pub async fn commit(
             &mut self,
             request: impl tonic::IntoRequest<super::LockDataRefId>,
@@ -397,6 +432,14 @@ pub mod replicator_server {
             &self,
             request: tonic::Request<super::WriteRequest>,
         ) -> Result<tonic::Response<super::WriteError>, tonic::Status>;
+        async fn prepare(
+            &self,
+            request: tonic::Request<super::LockDataRefId>,
+        ) -> Result<tonic::Response<super::Empty>, tonic::Status>;
+        async fn rollback_to(
+            &self,
+            request: tonic::Request<super::RollbackRequest>,
+        ) -> Result<tonic::Response<super::Empty>, tonic::Status>;
         async fn commit(
             &self,
             request: tonic::Request<super::LockDataRefId>,
@@ -569,6 +612,70 @@ pub mod replicator_server {
                     };
                     Box::pin(fut)
                 }
+                "/grpc_defs.Replicator/prepare" => {
+                    #[allow(non_camel_case_types)]
+                    struct prepareSvc<T: Replicator>(pub Arc<T>);
+                    impl<T: Replicator> tonic::server::UnaryService<super::LockDataRefId> for prepareSvc<T> {
+                        type Response = super::Empty;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::LockDataRefId>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).prepare(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = prepareSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/grpc_defs.Replicator/rollback_to" => {
+                    #[allow(non_camel_case_types)]
+                    struct rollback_toSvc<T: Replicator>(pub Arc<T>);
+                    impl<T: Replicator> tonic::server::UnaryService<super::RollbackRequest>
+                        for rollback_toSvc<T>
+                    {
+                        type Response = super::Empty;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RollbackRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).rollback_to(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = rollback_toSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 "/grpc_defs.Replicator/commit" => {
                     #[allow(non_camel_case_types)]
                     struct commitSvc<T: Replicator>(pub Arc<T>);
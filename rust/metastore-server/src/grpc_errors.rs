@@ -0,0 +1,29 @@
+use metastore::{NetworkError, NetworkResult, TxnError};
+use tonic::{Code, Response, Status};
+
+// Transaction errors travel as the JSON encoded `TxnError` in the status message, so the receiving side gets back
+// exactly the error that happened on the other node.
+pub fn to_status(err: TxnError) -> Status {
+    let code = match &err {
+        TxnError::NotFound => Code::NotFound,
+        TxnError::Network(_) => Code::Unavailable,
+        err if err.is_retryable() => Code::Aborted,
+        _ => Code::Internal,
+    };
+    Status::new(code, serde_json::to_string(&err).unwrap())
+}
+
+pub fn network_to_status(err: NetworkError) -> Status {
+    Status::new(Code::Unavailable, String::from(err))
+}
+
+pub fn into_network_result<T>(res: Result<Response<T>, Status>) -> NetworkResult<(), TxnError> {
+    match res {
+        Ok(_) => NetworkResult::default(),
+        Err(status) => match serde_json::from_str::<TxnError>(status.message()) {
+            Ok(err) => NetworkResult::from(Err(err)),
+            // Not sent by `to_status`, e.g. the connection broke.
+            Err(_) => NetworkResult(Err(NetworkError::new(status.to_string()))),
+        },
+    }
+}
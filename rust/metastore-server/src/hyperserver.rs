@@ -6,6 +6,7 @@ use serde_json::Value as JSONValue;
 mod async_replication_handler;
 mod follower_grpc_server;
 mod grpc_defs;
+mod grpc_errors;
 mod hyper_error_converter;
 mod json_request_writers;
 mod replicator_entrypoint;
@@ -55,7 +56,7 @@ async fn write_request(
     let mut lock = ctx.lock().unwrap();
     let mut txn = ReplicatedTxn::new(&mut lock);
     json_request_writers::write_json(value, &mut txn)?;
-    txn.commit();
+    txn.commit()?;
 
     Ok(Response::builder()
         .body(Body::from("Written successful"))
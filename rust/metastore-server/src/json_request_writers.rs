@@ -5,12 +5,16 @@ use serde_json::{Value as JSONValue, Value};
 
 use metastore::object_path::ObjectPath;
 use metastore::rwtransaction_wrapper::ReplicatedTxn;
-use metastore::{DatabaseInterface, DbContext, LockDataRef, SelfContainedDb, TypedValue};
+use metastore::{DatabaseInterface, DbContext, LockDataRef, SelfContainedDb, TxnError, TypedValue};
 
-pub fn read_json_request_txn(uri: &str, ctx: &SelfContainedDb, txn: LockDataRef) -> JSONValue {
+pub fn read_json_request_txn(
+    uri: &str,
+    ctx: &SelfContainedDb,
+    txn: LockDataRef,
+) -> Result<JSONValue, TxnError> {
     let objpath = prettify_json_path(uri);
 
-    let ret = ctx.serve_range_read(txn, &objpath).0.unwrap().unwrap();
+    let ret = ctx.serve_range_read(txn, &objpath)??;
     log::debug!("Reading {} using txn {}", objpath.as_str(), txn.id,);
 
     let mut json = JSONValue::Null;
@@ -23,10 +27,10 @@ pub fn read_json_request_txn(uri: &str, ctx: &SelfContainedDb, txn: LockDataRef)
         Some(x) => x,
         None => objpath.as_str(),
     };
-    match json.pointer_mut(stripped) {
+    Ok(match json.pointer_mut(stripped) {
         Some(a) => a.take(),
         None => JSONValue::Null,
-    }
+    })
 }
 // todo: modify function to handle transactions
 pub fn read_json_request(uri: &str, ctx: &DbContext) -> JSONValue {
@@ -49,7 +53,7 @@ fn prettify_json_path(uri: &str) -> ObjectPath {
     ObjectPath::from(uri)
 }
 
-pub fn write_json(value: Value, txn: &mut ReplicatedTxn) -> Result<(), String> {
+pub fn write_json(value: Value, txn: &mut ReplicatedTxn) -> Result<(), TxnError> {
     // todo: have to delete all existing values before inserting the new one.
     // or else, we might get data corruption
     /*
@@ -72,7 +76,7 @@ pub fn write_json_txnid(
     txn: LockDataRef,
     db: &SelfContainedDb,
     path: &str,
-) -> Result<(), String> {
+) -> Result<(), TxnError> {
     // todo: have to delete all existing values before inserting the new one.
     // or else, we might get data corruption
     /*
@@ -87,8 +91,8 @@ pub fn write_json_txnid(
     for (key, value) in map {
         let key_absolute = ObjectPath::from(path.to_owned() + key.as_str());
         let value: TypedValue = value.to_string().into();
-        log::debug!("Wrote {} {}", key_absolute.as_str(), value.as_str());
-        db.serve_write(txn, &key_absolute, value).unwrap_all();
+        log::debug!("Wrote {} {}", key_absolute.as_str(), value);
+        db.serve_write(txn, &key_absolute, value)??;
     }
     Ok(())
 }
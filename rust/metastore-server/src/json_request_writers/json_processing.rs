@@ -223,7 +223,7 @@ mod tests {
 
         let mut txn2 = ReplicatedTxn::new(ctx);
         test_json().into_iter().for_each(|(path, value)| {
-            assert_eq!(txn2.read(&path).unwrap().as_str(), value.to_string());
+            assert_eq!(txn2.read(&path), Ok(value.to_string().into()));
         });
         txn2.commit();
     }
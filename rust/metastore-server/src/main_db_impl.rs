@@ -1,5 +1,6 @@
 use crate::grpc_defs::main_replicator_server::MainReplicator;
 use crate::grpc_defs::{Empty, Json, JsonWriteRequest, LockDataRefId, ReadRequest};
+use crate::grpc_errors::{network_to_status, to_status};
 use crate::json_request_writers::{read_json_request_txn, write_json_txnid};
 use metastore::{DatabaseInterface, LockDataRef, SelfContainedDb};
 use std::cell::Cell;
@@ -30,7 +31,7 @@ impl MainReplicator for MainReplicatorServer {
     async fn read(&self, request: Request<ReadRequest>) -> Result<Response<Json>, Status> {
         let request = request.into_inner();
         let txn: LockDataRef = request.txn.unwrap().into();
-        let res = read_json_request_txn(&request.key, &self.0, txn);
+        let res = read_json_request_txn(&request.key, &self.0, txn).map_err(to_status)?;
         let res = serde_json::to_string(&res).unwrap();
 
         Ok(Response::new(Json { inner: res }))
@@ -42,7 +43,7 @@ impl MainReplicator for MainReplicatorServer {
         let txn: LockDataRef = txn.unwrap().into();
 
         let value: serde_json::Value = serde_json::from_str(&value.inner).unwrap();
-        write_json_txnid(value, txn, &self.0, &path).unwrap();
+        write_json_txnid(value, txn, &self.0, &path).map_err(to_status)?;
 
         Ok(Response::new(Json {
             inner: "success".into(),
@@ -57,7 +58,11 @@ impl MainReplicator for MainReplicatorServer {
 
     async fn commit(&self, request: Request<LockDataRefId>) -> Result<Response<Empty>, Status> {
         let txn: LockDataRef = request.into_inner().into();
-        self.0.commit(txn);
+        self.0
+            .commit(txn)
+            .0
+            .map_err(network_to_status)?
+            .map_err(to_status)?;
         Ok(Response::new(Empty {}))
     }
 }
@@ -8,15 +8,17 @@ use tokio::runtime::Runtime;
 use tonic::{transport::Server, Code, Request, Response, Status};
 
 use metastore::{
-    DatabaseInterface, LockDataRef, NetworkResult, ObjectPath, SelfContainedDb, TypedValue,
-    ValueWithMVCC,
+    DatabaseInterface, LockDataRef, NetworkResult, ObjectPath, Savepoint, SelfContainedDb,
+    TxnError, TypedValue, ValueWithMVCC,
 };
 
 use crate::follower_grpc_server::FollowerGRPCServer;
 use crate::grpc_defs;
+use crate::grpc_errors::into_network_result;
 use crate::grpc_defs::replicator_server::ReplicatorServer;
 use crate::grpc_defs::{
-    Empty, Kv, LockDataRefId, ReadRequest, Value, ValueRanged, WriteError, WriteRequest,
+    Empty, Kv, LockDataRefId, ReadRequest, RollbackRequest, Value, ValueRanged, WriteError,
+    WriteRequest,
 };
 use std::convert::TryFrom;
 use std::str::FromStr;
@@ -36,20 +38,40 @@ pub fn setup_logging() {
 
 pub type Client = grpc_defs::replicator_client::ReplicatorClient<tonic::transport::Channel>;
 
+// A replica reached over gRPC. Its address names it when it diverges, see `TxnError::ReplicaDiverged`.
+pub struct RemoteReplica {
+    client: Client,
+    addr: String,
+}
+
+impl RemoteReplica {
+    // The replica rejected a request the primary accepted.
+    fn tag(&self, res: NetworkResult<(), TxnError>) -> NetworkResult<(), TxnError> {
+        match res.0 {
+            Ok(Err(err)) => {
+                log::error!("Replica {} rejected a request: {}", self.addr, err);
+                NetworkResult::from(Err(TxnError::ReplicaDiverged(self.addr.clone())))
+            }
+            res => NetworkResult(res),
+        }
+    }
+}
+
 // todo: implement async_database_interface specifically for this client and make a wrapper around
 // `n` (replication factor) number of clients to reduce latency.
-impl DatabaseInterface for Client {
-    fn new_transaction(&self, txn: &LockDataRef) -> NetworkResult<(), String> {
+impl DatabaseInterface for RemoteReplica {
+    fn new_transaction(&self, txn: &LockDataRef) -> NetworkResult<(), TxnError> {
         log::debug!("(Localside) Creating new transaction {}", txn.id);
-        block_on(self.clone().new_with_time(LockDataRefId { id: txn.id }));
-        NetworkResult::default()
+        into_network_result(block_on(
+            self.client.clone().new_with_time(LockDataRefId { id: txn.id }),
+        ))
     }
 
     fn serve_read(
         &self,
         txn: LockDataRef,
         key: &ObjectPath,
-    ) -> NetworkResult<ValueWithMVCC, String> {
+    ) -> NetworkResult<ValueWithMVCC, TxnError> {
         unimplemented!()
     }
 
@@ -57,7 +79,7 @@ impl DatabaseInterface for Client {
         &self,
         txn: LockDataRef,
         key: &ObjectPath,
-    ) -> NetworkResult<Vec<(ObjectPath, ValueWithMVCC)>, String> {
+    ) -> NetworkResult<Vec<(ObjectPath, ValueWithMVCC)>, TxnError> {
         unimplemented!()
     }
 
@@ -66,7 +88,7 @@ impl DatabaseInterface for Client {
         txn: LockDataRef,
         key: &ObjectPath,
         value: TypedValue,
-    ) -> NetworkResult<(), String> {
+    ) -> NetworkResult<(), TxnError> {
         log::debug!("(Localside) Doing serve_write {}", txn.id);
         let kv = Kv {
             key: key.to_string(),
@@ -76,25 +98,45 @@ impl DatabaseInterface for Client {
             txn: Option::from(LockDataRefId { id: txn.id }),
             kv: Some(kv),
         };
-        block_on(Client::serve_write(&mut self.clone(), write));
-        NetworkResult::default()
+        self.tag(into_network_result(block_on(Client::serve_write(
+            &mut self.client.clone(),
+            write,
+        ))))
+    }
+
+    fn prepare(&self, txn: LockDataRef) -> NetworkResult<(), TxnError> {
+        log::debug!("(Localside) Doing prepare {}", txn.id);
+        into_network_result(block_on(Client::prepare(
+            &mut self.client.clone(),
+            LockDataRefId { id: txn.id },
+        )))
+    }
+
+    fn rollback_to(&self, txn: LockDataRef, savepoint: Savepoint) -> NetworkResult<(), TxnError> {
+        log::debug!("(Localside) Doing rollback_to {}", txn.id);
+        let request = RollbackRequest {
+            txn: Some(LockDataRefId { id: txn.id }),
+            savepoint: savepoint.0 as u64,
+        };
+        self.tag(into_network_result(block_on(Client::rollback_to(
+            &mut self.client.clone(),
+            request,
+        ))))
     }
 
-    fn commit(&self, txn: LockDataRef) -> NetworkResult<(), String> {
+    fn commit(&self, txn: LockDataRef) -> NetworkResult<(), TxnError> {
         log::debug!("(Localside) Doing commit {}", txn.id);
-        block_on(Client::commit(
-            &mut self.clone(),
+        into_network_result(block_on(Client::commit(
+            &mut self.client.clone(),
             LockDataRefId { id: txn.id },
-        ));
-        NetworkResult::default()
+        )))
     }
 
-    fn abort(&self, p0: LockDataRef) -> NetworkResult<(), String> {
-        block_on(Client::abort(
-            &mut self.clone(),
+    fn abort(&self, p0: LockDataRef) -> NetworkResult<(), TxnError> {
+        into_network_result(block_on(Client::abort(
+            &mut self.client.clone(),
             LockDataRefId { id: p0.id },
-        ));
-        NetworkResult::default()
+        )))
     }
 }
 
@@ -117,8 +159,8 @@ pub async fn generate_threaded_follower(addr: &str) -> Box<dyn DatabaseInterface
     wait_for_socket_open(addr).await;
 
     let addr = format!("http://{}", addr);
-    let client = Client::connect(Endpoint::try_from(addr).unwrap())
+    let client = Client::connect(Endpoint::try_from(addr.clone()).unwrap())
         .await
         .unwrap();
-    Box::new(client)
+    Box::new(RemoteReplica { client, addr })
 }
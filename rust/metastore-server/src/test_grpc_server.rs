@@ -18,6 +18,7 @@ use std::time::Duration;
 
 mod follower_grpc_server;
 mod grpc_defs;
+mod grpc_errors;
 mod json_request_writers;
 mod main_db_impl;
 mod replicator_entrypoint;
@@ -0,0 +1,113 @@
+// Measures how inserts of new keys and point reads scale with the number of threads, with and without range scans
+// running at the same time.
+// Run with `cargo bench --bench insert_scaling`.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use metastore::db_context::create_empty_context;
+use metastore::rwtransaction_wrapper::Transaction;
+use metastore::timestamp::Timestamp;
+use metastore::DbContext;
+
+const OPS_PER_THREAD: usize = 20_000;
+const READERS_PER_WRITER: usize = 1;
+const SCANS_PER_THREAD: usize = 200;
+
+fn insert_keys(ctx: &DbContext, thread: usize) -> usize {
+    let mut done = 0;
+    for i in 0..OPS_PER_THREAD {
+        let key = format!("/bench/{}/{}", thread, i);
+        let mut txn = Transaction::new_with_time(ctx, Timestamp::now());
+        if txn
+            .write(ctx, &key.as_str().into(), i.to_string().into())
+            .is_ok()
+            && txn.commit(ctx).is_ok()
+        {
+            done += 1;
+        }
+    }
+    done
+}
+
+fn read_keys(ctx: &DbContext) -> usize {
+    let mut done = 0;
+    for i in 0..OPS_PER_THREAD {
+        let key = format!("/preloaded/{}", i % 1000);
+        let mut txn = Transaction::new_with_time(ctx, Timestamp::now());
+        if txn.read_mvcc(ctx, &key.as_str().into()).is_ok() && txn.commit(ctx).is_ok() {
+            done += 1;
+        }
+    }
+    done
+}
+
+// Every scan reads all of `/preloaded/`, so it has to lock all shards for several pages.
+fn scan_keys(ctx: &DbContext) -> usize {
+    let mut done = 0;
+    for _ in 0..SCANS_PER_THREAD {
+        let mut txn = Transaction::new_with_time(ctx, Timestamp::now());
+        if txn.read_range_owned(ctx, &"/preloaded/".into()).is_ok() && txn.commit(ctx).is_ok() {
+            done += 1;
+        }
+    }
+    done
+}
+
+fn run(writers: usize, scanners: usize) -> (f64, f64, f64) {
+    let ctx = Arc::new(create_empty_context());
+    let mut txn = Transaction::new_with_time(&ctx, Timestamp::now());
+    for i in 0..1000 {
+        txn.write(
+            &ctx,
+            &format!("/preloaded/{}", i).as_str().into(),
+            "0".into(),
+        )
+        .unwrap();
+    }
+    txn.commit(&ctx).unwrap();
+
+    let start = Instant::now();
+    let mut handles = Vec::new();
+    for thread in 0..writers {
+        let writer_ctx = ctx.clone();
+        handles.push(std::thread::spawn(move || {
+            (insert_keys(&writer_ctx, thread), 0, 0)
+        }));
+        for _ in 0..READERS_PER_WRITER {
+            let reader_ctx = ctx.clone();
+            handles.push(std::thread::spawn(move || (0, read_keys(&reader_ctx), 0)));
+        }
+    }
+    for _ in 0..scanners {
+        let scanner_ctx = ctx.clone();
+        handles.push(std::thread::spawn(move || (0, 0, scan_keys(&scanner_ctx))));
+    }
+    let (inserts, reads, scans) = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .fold((0, 0, 0), |a, b| (a.0 + b.0, a.1 + b.1, a.2 + b.2));
+    let elapsed = start.elapsed().as_secs_f64();
+
+    (
+        inserts as f64 / elapsed,
+        reads as f64 / elapsed,
+        scans as f64 / elapsed,
+    )
+}
+
+fn main() {
+    println!(
+        "{:>8} {:>8} {:>16} {:>16} {:>16}",
+        "writers", "scanners", "inserts/sec", "reads/sec", "scans/sec"
+    );
+    for writers in [1, 2, 4, 8].iter() {
+        for scanners in [0, 2].iter() {
+            let (inserts, reads, scans) = run(*writers, *scanners);
+            println!(
+                "{:>8} {:>8} {:>16.0} {:>16.0} {:>16.0}",
+                writers, scanners, inserts, reads, scans
+            );
+        }
+    }
+}
@@ -2,7 +2,7 @@
 use crate::replicated_slave::SelfContainedDb;
 use crate::rpc_handler::DatabaseInterface;
 use crate::rwtransaction_wrapper::LockDataRef;
-use crate::{ObjectPath, TypedValue};
+use crate::{ObjectPath, TxnError, TypedValue};
 use parking_lot::Mutex;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashSet;
@@ -47,7 +47,7 @@ impl BtreeIndexInternal {
         key: &ObjectPath,
         prevvalue: &str,
         newvalue: String,
-    ) -> Result<(), String> {
+    ) -> Result<(), TxnError> {
         assert!(self
             .contained_keys
             .lock()
@@ -59,7 +59,7 @@ impl BtreeIndexInternal {
 
         retry(|| match self.db.serve_read(txn, &value_as_obj)? {
             Ok(val) => {
-                assert_eq!(val.get_val().as_str(), key.as_str());
+                assert_eq!(val.get_val().as_str(), Some(key.as_str()));
                 // Delete this node
                 self.db
                     .serve_write(txn, &value_as_obj, TypedValue::Deleted)?;
@@ -78,9 +78,8 @@ impl BtreeIndexInternal {
 
         let value_as_obj = ObjectPath::from(value);
 
-        retry::<_, String, _>(|| match self.db.serve_read(txn, &value_as_obj)? {
-            // todo: string comparisons bad, should've used enum errors
-            Err(err) if err == r#"Other("Read value doesn't exist")"# => {
+        retry::<_, TxnError, _>(|| match self.db.serve_read(txn, &value_as_obj)? {
+            Err(TxnError::NotFound) => {
                 self.db
                     .serve_write(txn, &value_as_obj, key.as_str().into())?;
                 Ok(())
@@ -90,12 +89,15 @@ impl BtreeIndexInternal {
         })
         .unwrap();
     }
-    pub fn query_key(&self, txn: LockDataRef, value: String) -> Result<ObjectPath, String> {
+    pub fn query_key(&self, txn: LockDataRef, value: String) -> Result<ObjectPath, TxnError> {
         let objpath = ObjectPath::from(value);
-        let res = retry::<TypedValue, String, _>(|| {
+        let res = retry::<TypedValue, TxnError, _>(|| {
             Ok(self.db.serve_read(txn, &objpath)??.into_inner().1)
         });
-        res.map(String::from).map(ObjectPath::from)
+        match res? {
+            TypedValue::String(key) => Ok(ObjectPath::from(key)),
+            other => Err(format!("Index entry {} is not a key", other).into()),
+        }
     }
 
     pub fn commit(&self, txn: LockDataRef) {
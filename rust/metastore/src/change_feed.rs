@@ -0,0 +1,464 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::object_path::ObjectPath;
+use crate::timestamp::Timestamp;
+use crate::wal_watcher::{ByteBufferWAL, WalLoader, WalPosition, WalRecordKind, WalTxn};
+use crate::{DbContext, TypedValue};
+
+// A committed write. Deleting a key shows up as a change to `TypedValue::Deleted`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub key: ObjectPath,
+    pub value: TypedValue,
+    // Timestamp of the transaction that made the change.
+    pub timestamp: Timestamp,
+    // Where the transaction's commit record is in the WAL. That's the order transactions committed in, which isn't
+    // the order of their timestamps. All changes of a transaction have the same position.
+    pub position: WalPosition,
+}
+
+// How many changes a subscriber may fall behind by. One that falls further behind is dropped, so slow subscribers
+// don't hold up commits or fill up memory. It can subscribe again after the last change it got, see `subscribe`.
+const CAPACITY: usize = 1024;
+
+struct Subscriber {
+    prefix: ObjectPath,
+    sender: Sender<Change>,
+}
+
+impl Subscriber {
+    fn changes<'a>(
+        &'a self,
+        txn: &'a WalTxn,
+        position: WalPosition,
+    ) -> impl Iterator<Item = Change> + 'a {
+        txn.writes()
+            .filter(move |(key, _)| key.as_str().starts_with(self.prefix.as_str()))
+            .map(move |(key, value)| Change {
+                key: key.clone(),
+                value: value.clone(),
+                timestamp: txn.timestamp(),
+                position,
+            })
+    }
+
+    // Sends all changes of the transaction or, if they don't fit, none. Returns false once the subscription was
+    // dropped or fell too far behind.
+    fn send(&self, txn: &WalTxn, position: WalPosition) -> bool {
+        let count = self.changes(txn, position).count();
+        if self.sender.len() + count > self.sender.capacity().unwrap_or(usize::MAX) {
+            log::warn!(
+                "Dropping subscriber to {}, it fell behind by {} changes",
+                self.prefix.as_str(),
+                self.sender.len()
+            );
+            return false;
+        }
+        self.changes(txn, position)
+            .all(|change| self.sender.try_send(change).is_ok())
+    }
+}
+
+// Subscribers get the changes in the order their commit records are in the WAL. Committers log concurrently, so
+// they don't finish logging in that order: each one takes a ticket before it logs (see `publisher`), and its changes
+// are held back until no committer that may have logged before it still has one.
+#[derive(Default)]
+pub(crate) struct Subscribers(Mutex<State>);
+
+#[derive(Default)]
+struct State {
+    subscribers: Vec<Subscriber>,
+    next_ticket: u64,
+    // The tickets of committers that are logging, with where the WAL ended when they took it. Their commit records
+    // come after that.
+    logging: HashMap<u64, WalPosition>,
+    // Logged commits, by position, waiting for the ones that may come before them.
+    logged: BTreeMap<WalPosition, WalTxn>,
+}
+
+impl State {
+    // Sends the logged commits that no committer can come before anymore.
+    fn send_ready(&mut self) {
+        let logging = self.logging.values().min().copied();
+        while let Some(entry) = self.logged.first_entry() {
+            if logging.is_some_and(|end| *entry.key() > end) {
+                break;
+            }
+            let (position, txn) = entry.remove_entry();
+            self.subscribers
+                .retain(|subscriber| subscriber.send(&txn, position));
+        }
+    }
+}
+
+impl Subscribers {
+    // Called by a committing transaction while it holds `commit_lock`, right before logging its commit. Returns None
+    // if nobody is subscribed. Subscribing waits for `commit_lock`, so that doesn't change until the commit is logged.
+    pub(crate) fn publisher(&self, wal: &ByteBufferWAL) -> Option<Publisher<'_>> {
+        let mut state = self.0.lock();
+        if state.subscribers.is_empty() {
+            return None;
+        }
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.logging.insert(ticket, wal.end());
+        Some(Publisher {
+            subscribers: self,
+            ticket,
+        })
+    }
+}
+
+// A committer's ticket. Dropping it without publishing, e.g. because the commit failed, lets the later commits
+// through.
+pub(crate) struct Publisher<'a> {
+    subscribers: &'a Subscribers,
+    ticket: u64,
+}
+
+impl Publisher<'_> {
+    // Sends the writes of a transaction whose commit record was logged at `position`, once all commits before it
+    // were sent.
+    pub(crate) fn publish(self, txn: WalTxn, position: WalPosition) {
+        self.subscribers.0.lock().logged.insert(position, txn);
+    }
+}
+
+impl Drop for Publisher<'_> {
+    fn drop(&mut self) {
+        let mut state = self.subscribers.0.lock();
+        state.logging.remove(&self.ticket);
+        state.send_ready();
+    }
+}
+
+// Changes under a prefix, in the order their transactions committed. Dropping it unsubscribes. It ends if it falls
+// more than `CAPACITY` changes behind, then subscribe again after the position of the last change received.
+pub struct Subscription {
+    receiver: Receiver<Change>,
+}
+
+impl Subscription {
+    pub fn try_recv(&self) -> Option<Change> {
+        self.receiver.try_recv().ok()
+    }
+
+    // Returns None if nothing changed within `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Change> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+}
+
+// Blocks until the next change.
+impl Iterator for Subscription {
+    type Item = Change;
+
+    fn next(&mut self) -> Option<Change> {
+        self.receiver.recv().ok()
+    }
+}
+
+// Subscribes to the changes to keys starting with `prefix`. With `after`, the changes made by transactions that
+// committed after the one at that position are read from the WAL first, in the order they committed. That only
+// works as long as the WAL still has all of them, i.e. `after` isn't from before the last checkpoint.
+pub fn subscribe(
+    ctx: &DbContext,
+    prefix: &ObjectPath,
+    after: Option<WalPosition>,
+) -> Result<Subscription, String> {
+    // No transaction can commit while we hold the lock, so each one is either in the WAL already or gets
+    // published to us once we're registered, but not both.
+    let _commits = ctx.commit_lock.write();
+    let missed = match after {
+        Some(after) => committed_after(ctx, after)?,
+        None => Vec::new(),
+    };
+
+    // Room for everything we missed, on top of what we may fall behind by from now on.
+    let missed_changes: usize = missed.iter().map(|(_, txn)| txn.writes().count()).sum();
+    let (sender, receiver) = channel::bounded(CAPACITY + missed_changes);
+    let subscriber = Subscriber {
+        prefix: prefix.clone(),
+        sender,
+    };
+    for (position, txn) in &missed {
+        subscriber.send(txn, *position);
+    }
+    ctx.subscribers.0.lock().subscribers.push(subscriber);
+    Ok(Subscription { receiver })
+}
+
+// The transactions in the WAL that committed after the one at `after`, with their positions, in the order they
+// committed.
+fn committed_after(
+    ctx: &DbContext,
+    after: WalPosition,
+) -> Result<Vec<(WalPosition, WalTxn)>, String> {
+    let first_segment = ctx.wallog.first_segment()?;
+    if after.segment < first_segment {
+        return Err(format!(
+            "Changes before WAL segment {} are only in a checkpoint, not in the WAL",
+            first_segment
+        ));
+    }
+
+    let mut committed = Vec::new();
+    let mut prepared = HashMap::new();
+    for (position, txn) in ctx.wallog.load_positions_from(first_segment)? {
+        let txn = match txn.kind() {
+            WalRecordKind::Commit => txn,
+            WalRecordKind::Prepare => {
+                prepared.insert(txn.txn(), txn);
+                continue;
+            }
+            WalRecordKind::AbortPrepared => {
+                prepared.remove(&txn.txn());
+                continue;
+            }
+            // The writes are in the prepare record.
+            WalRecordKind::CommitPrepared => match prepared.remove(&txn.txn()) {
+                Some(txn) => txn,
+                None if position <= after => continue,
+                None => {
+                    return Err(format!(
+                        "Transaction {} committed at {:?} was prepared before WAL segment {}, its changes are only \
+                         in a checkpoint",
+                        txn.txn().id,
+                        position,
+                        first_segment
+                    ))
+                }
+            },
+            // Only about telling the replicas, the transaction's writes are in its own record.
+            WalRecordKind::Coordinating | WalRecordKind::Acknowledged => continue,
+        };
+        if position > after {
+            committed.push((position, txn));
+        }
+    }
+    Ok(committed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::change_feed::{Change, Subscription, CAPACITY};
+    use crate::db_context::create_empty_context;
+    use crate::rwtransaction_wrapper::Transaction;
+    use crate::timestamp::Timestamp;
+    use crate::wal_watcher::WalDurability;
+    use crate::{DbContext, ObjectPath, ReplicatedTxn, TypedValue};
+
+    fn write(db: &DbContext, writes: &[(&str, TypedValue)]) -> Timestamp {
+        let mut txn = ReplicatedTxn::new(db);
+        for (key, value) in writes {
+            txn.write(&(*key).into(), value.clone()).unwrap();
+        }
+        let time = txn.get_txn().timestamp;
+        txn.commit().unwrap();
+        time
+    }
+
+    #[test]
+    fn streams_committed_changes_under_prefix() {
+        let db = db!("/test/a" = "1");
+        let subscription = db.subscribe(&"/test/".into());
+
+        let mut aborted = ReplicatedTxn::new(&db);
+        aborted.write(&"/test/a".into(), "2".into()).unwrap();
+        aborted.abort();
+        let time = write(
+            &db,
+            &[
+                ("/test/a", "3".into()),
+                ("/other/a", "4".into()),
+                ("/test/b", TypedValue::Deleted),
+            ],
+        );
+
+        let change = subscription.try_recv().unwrap();
+        assert_eq!(
+            (change.key, change.value, change.timestamp),
+            ("/test/a".into(), "3".into(), time)
+        );
+        let second = subscription.try_recv().unwrap();
+        assert_eq!(
+            (second.key, second.value, second.position),
+            ("/test/b".into(), TypedValue::Deleted, change.position)
+        );
+        assert_eq!(subscription.recv_timeout(Duration::from_millis(10)), None);
+
+        // Dropped subscriptions are removed on the next commit.
+        std::mem::drop(subscription);
+        write(&db, &[("/test/a", "5".into())]);
+        assert!(db.subscribers.publisher(&db.wallog).is_none());
+    }
+
+    fn received(subscription: &Subscription) -> Vec<Change> {
+        std::iter::from_fn(|| subscription.try_recv()).collect()
+    }
+
+    fn keys(changes: &[Change]) -> Vec<ObjectPath> {
+        changes.iter().map(|c| c.key.clone()).collect()
+    }
+
+    #[test]
+    fn resumes_from_wal() {
+        let ctx = create_empty_context();
+        let live = ctx.subscribe(&"/test/".into());
+        let commit = |key: &str, prepare: bool| {
+            let mut txn = Transaction::new_with_time(&ctx, Timestamp::now());
+            txn.write(&ctx, &key.into(), "1".into()).unwrap();
+            if prepare {
+                txn.prepare(&ctx).unwrap();
+            }
+            txn.commit(&ctx).unwrap();
+        };
+        commit("/test/a", false);
+        commit("/test/b", false);
+        commit("/test/c", true);
+        let mut aborted = Transaction::new_with_time(&ctx, Timestamp::now());
+        aborted.write(&ctx, &"/test/d".into(), "1".into()).unwrap();
+        aborted.prepare(&ctx).unwrap();
+        aborted.abort_decided(&ctx).unwrap();
+        let seen = received(&live);
+        assert_eq!(
+            keys(&seen),
+            vec!["/test/a".into(), "/test/b".into(), "/test/c".into()]
+        );
+
+        let subscription = ctx
+            .subscribe_after(&"/test/".into(), seen[0].position)
+            .unwrap();
+        commit("/test/e", false);
+
+        let changes = received(&subscription);
+        assert_eq!(
+            keys(&changes),
+            vec!["/test/b".into(), "/test/c".into(), "/test/e".into()]
+        );
+        assert_eq!(changes[..2], seen[1..]);
+        assert!(changes.windows(2).all(|c| c[0].position < c[1].position));
+    }
+
+    #[test]
+    fn resumes_in_commit_order() {
+        let ctx = create_empty_context();
+        let live = ctx.subscribe(&"/test/".into());
+        let mut older = Transaction::new_with_time(&ctx, Timestamp::now());
+        let mut newer = Transaction::new_with_time(&ctx, Timestamp::now());
+        older
+            .write(&ctx, &"/test/older".into(), "1".into())
+            .unwrap();
+        newer
+            .write(&ctx, &"/test/newer".into(), "1".into())
+            .unwrap();
+        newer.commit(&ctx).unwrap();
+        older.commit(&ctx).unwrap();
+        assert!(older.txn.timestamp < newer.txn.timestamp);
+
+        let seen = received(&live);
+        assert_eq!(
+            keys(&seen),
+            vec!["/test/newer".into(), "/test/older".into()]
+        );
+
+        // Resuming after the newer transaction still gets the older one, which committed later.
+        let subscription = ctx
+            .subscribe_after(&"/test/".into(), seen[0].position)
+            .unwrap();
+        assert_eq!(received(&subscription), seen[1..]);
+    }
+
+    #[test]
+    fn cant_resume_from_before_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("change-feed-{}", rand::random::<u64>()));
+        let ctx = DbContext::open(&dir).unwrap();
+        let live = ctx.subscribe(&"/test/".into());
+        write(&ctx, &[("/test/a", "1".into())]);
+        let position = received(&live)[0].position;
+        assert!(ctx.subscribe_after(&"/test/".into(), position).is_ok());
+
+        ctx.checkpoint().unwrap();
+        assert!(ctx.subscribe_after(&"/test/".into(), position).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn group_commit_batches_while_subscribed() {
+        let dir = std::env::temp_dir().join(format!("change-feed-{}", rand::random::<u64>()));
+        let durability = WalDurability::GroupCommit {
+            max_delay: Duration::from_millis(5),
+            max_batch: 8,
+        };
+        let ctx = DbContext::open_with_durability(&dir, durability).unwrap();
+        let live = ctx.subscribe(&"/test/".into());
+        crossbeam::scope(|s| {
+            for thread in 0..8 {
+                let ctx = &ctx;
+                s.spawn(move |_| {
+                    for i in 0..25 {
+                        write(ctx, &[(&format!("/test/{}/{}", thread, i), "1".into())]);
+                    }
+                });
+            }
+        })
+        .unwrap();
+
+        let syncs = ctx.wallog.sync_count();
+        assert!(syncs < 200, "{} fsyncs for 200 commits", syncs);
+        let seen = received(&live);
+        assert_eq!(seen.len(), 200);
+        assert!(seen.windows(2).all(|c| c[0].position < c[1].position));
+
+        std::mem::drop(ctx);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn slow_subscriber_is_dropped() {
+        let ctx = create_empty_context();
+        let mut slow = ctx.subscribe(&"/test/".into());
+        for i in 0..=CAPACITY {
+            write(&ctx, &[(&format!("/test/{}", i), "1".into())]);
+        }
+        let seen: Vec<Change> = slow.by_ref().collect();
+        assert_eq!(seen.len(), CAPACITY);
+
+        // It catches up on the rest from the WAL.
+        let subscription = ctx
+            .subscribe_after(&"/test/".into(), seen.last().unwrap().position)
+            .unwrap();
+        assert_eq!(
+            keys(&received(&subscription)),
+            vec![format!("/test/{}", CAPACITY).as_str().into()]
+        );
+    }
+
+    #[test]
+    fn cant_resume_over_commit_prepared_before_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("change-feed-{}", rand::random::<u64>()));
+        let ctx = DbContext::open(&dir).unwrap();
+        let live = ctx.subscribe(&"/test/".into());
+        let mut txn = Transaction::new_with_time(&ctx, Timestamp::now());
+        txn.write(&ctx, &"/test/a".into(), "1".into()).unwrap();
+        txn.prepare(&ctx).unwrap();
+        ctx.checkpoint().unwrap();
+
+        write(&ctx, &[("/test/b", "1".into())]);
+        let position = received(&live)[0].position;
+        assert!(ctx.subscribe_after(&"/test/".into(), position).is_ok());
+        txn.commit(&ctx).unwrap();
+        // The changes of `txn` are only in the checkpoint.
+        assert!(ctx.subscribe_after(&"/test/".into(), position).is_err());
+
+        std::mem::drop(ctx);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
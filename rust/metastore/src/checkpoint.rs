@@ -0,0 +1,369 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::object_path::ObjectPath;
+use crate::rwtransaction_wrapper::{
+    LockDataRef, MVCCMetadata, Transaction, ValueWithMVCC, WriteIntent, WriteIntentStatus,
+};
+use crate::timestamp::Timestamp;
+use crate::{DbContext, TypedValue};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const FORMAT_VERSION: u32 = 1;
+
+// The manifest names the latest checkpoint: its data file and the first WAL segment it doesn't cover.
+// A checkpoint only takes effect once the manifest pointing to it has been renamed into place,
+// so a crash while checkpointing leaves the previous checkpoint (and the segments it needs) intact.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    data_file: String,
+    first_wal_segment: u64,
+}
+
+// On-disk layout of a data file (newline separated JSON):
+//   line 1:   StorageHeader (format version and the status of every known transaction)
+//   line 2..: one StoredKey per key, in key order, with its whole version chain (newest version first)
+#[derive(Serialize, Deserialize)]
+struct StorageHeader {
+    version: u32,
+    transactions: Vec<(u64, Timestamp, WriteIntentStatus)>,
+    // Replicated transactions we coordinated whose outcome (whether they committed) the replicas haven't
+    // acknowledged yet, see `coordinator::Decisions`.
+    #[serde(default)]
+    decisions: Vec<(u64, Timestamp, bool)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredIntent {
+    txn_id: u64,
+    txn_timestamp: Timestamp,
+    was_committed: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredVersion {
+    begin_ts: Timestamp,
+    end_ts: Timestamp,
+    last_read: Timestamp,
+    intent: Option<StoredIntent>,
+    value: TypedValue,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    key: ObjectPath,
+    versions: Vec<StoredVersion>,
+}
+
+impl StoredVersion {
+    fn new(meta: &MVCCMetadata, value: TypedValue) -> Self {
+        Self {
+            begin_ts: meta.get_beg_time(),
+            end_ts: meta.get_end_time(),
+            last_read: meta.get_last_read_time(),
+            intent: meta.get_write_intents().map(|wi| StoredIntent {
+                txn_id: wi.associated_transaction.id,
+                txn_timestamp: wi.associated_transaction.timestamp,
+                was_committed: wi.was_commited,
+            }),
+            value,
+        }
+    }
+
+    fn into_value(self, previous_mvcc_value: Option<usize>) -> ValueWithMVCC {
+        let intent = self.intent.map(|wi| WriteIntent {
+            associated_transaction: LockDataRef {
+                id: wi.txn_id,
+                timestamp: wi.txn_timestamp,
+            },
+            was_commited: wi.was_committed,
+        });
+        let meta = MVCCMetadata::from_parts(
+            self.begin_ts,
+            self.end_ts,
+            self.last_read,
+            intent,
+            previous_mvcc_value,
+        );
+        ValueWithMVCC::from_tuple(meta, self.value)
+    }
+}
+
+// Collects the value and every archived version reachable from it in `old_values_store`.
+fn version_chain(ctx: &DbContext, value: &ValueWithMVCC) -> Vec<StoredVersion> {
+    let (mut meta, val) = value.as_inner();
+    let mut versions = vec![StoredVersion::new(&meta, val.clone())];
+
+    while let Ok(prev) = meta.get_prev_mvcc(ctx) {
+        let (prevmeta, prevval) = prev.as_inner();
+        versions.push(StoredVersion::new(&prevmeta, prevval.clone()));
+        meta = prevmeta;
+    }
+    versions
+}
+
+// Checkpoints of a database under its data directory. A checkpoint is a full dump of the database, so it's only
+// written when asked to (`DbContext::checkpoint`). The WAL is what makes commits durable: every transaction committed
+// after the latest checkpoint is replayed from it, and checkpoints just let recovery skip the segments before them.
+// This is not a storage engine: the data still lives in `MutBTreeMap`, so the whole database (and the old versions
+// vacuum hasn't removed) has to fit in memory, and opening it reads the whole checkpoint back in.
+pub struct CheckpointStore {
+    dir: PathBuf,
+}
+
+impl CheckpointStore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, String> {
+        std::fs::create_dir_all(dir.as_ref()).map_err(|err| err.to_string())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Writes all keys (including MVCC metadata, write intents and old versions) and all transaction statuses
+    // to a new data file, then points the manifest at it.
+    // Holds the database write lock for the whole duration so the stored state is consistent.
+    // Every transaction logged in a WAL segment before `first_wal_segment` must be reflected in `ctx`.
+    pub fn store(&self, ctx: &DbContext, first_wal_segment: u64) -> Result<(), String> {
+        let previous = self.read_manifest()?;
+        let data_file = format!("data-{:06}.db", first_wal_segment);
+        let tmp_path = self.dir.join(format!("{}.tmp", data_file));
+        let mut writer = BufWriter::new(File::create(&tmp_path).map_err(|err| err.to_string())?);
+
+        ctx.db.with_exclusive(|map| -> Result<(), String> {
+            let header = StorageHeader {
+                version: FORMAT_VERSION,
+                transactions: ctx
+                    .transaction_map
+                    .all_statuses()
+                    .into_iter()
+                    .map(|(txn, status)| (txn.id, txn.timestamp, status))
+                    .collect(),
+                decisions: ctx
+                    .decisions
+                    .all()
+                    .into_iter()
+                    .map(|(txn, commit)| (txn.id, txn.timestamp, commit))
+                    .collect(),
+            };
+            write_line(&mut writer, &header)?;
+
+            for (key, value) in map.iter() {
+                let stored = StoredKey {
+                    key: key.clone(),
+                    versions: version_chain(ctx, value),
+                };
+                write_line(&mut writer, &stored)?;
+            }
+            Ok(())
+        })?;
+
+        let file = writer.into_inner().map_err(|err| err.to_string())?;
+        file.sync_all().map_err(|err| err.to_string())?;
+        std::fs::rename(&tmp_path, self.dir.join(&data_file)).map_err(|err| err.to_string())?;
+
+        let manifest = Manifest {
+            version: FORMAT_VERSION,
+            data_file,
+            first_wal_segment,
+        };
+        let tmp_path = self.dir.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut writer = File::create(&tmp_path).map_err(|err| err.to_string())?;
+        write_line(&mut writer, &manifest)?;
+        writer.sync_all().map_err(|err| err.to_string())?;
+        std::fs::rename(&tmp_path, self.dir.join(MANIFEST_FILE)).map_err(|err| err.to_string())?;
+        self.sync_dir()?;
+
+        match previous {
+            Some(previous) if previous.data_file != manifest.data_file => {
+                std::fs::remove_file(self.dir.join(previous.data_file)).map_err(|err| err.to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn read_manifest(&self) -> Result<Option<Manifest>, String> {
+        match std::fs::read_to_string(self.dir.join(MANIFEST_FILE)) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(|err| err.to_string()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn sync_dir(&self) -> Result<(), String> {
+        File::open(&self.dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|err| err.to_string())
+    }
+
+    // Loads the latest checkpoint into `ctx`, which should be empty.
+    // Returns the first WAL segment to resume replaying from, or None if no checkpoint has been stored in this directory yet.
+    pub fn load(&self, ctx: &DbContext) -> Result<Option<u64>, String> {
+        let manifest = match self.read_manifest()? {
+            Some(manifest) => manifest,
+            None => return Ok(None),
+        };
+        if manifest.version != FORMAT_VERSION {
+            return Err(format!("Unsupported manifest version {}", manifest.version));
+        }
+        let file = File::open(self.dir.join(&manifest.data_file)).map_err(|err| err.to_string())?;
+        let mut lines = BufReader::new(file).lines();
+
+        let header = lines
+            .next()
+            .ok_or_else(|| "Data file is missing its header".to_string())?
+            .map_err(|err| err.to_string())?;
+        let header: StorageHeader = serde_json::from_str(&header).map_err(|err| err.to_string())?;
+        if header.version != FORMAT_VERSION {
+            return Err(format!("Unsupported data file version {}", header.version));
+        }
+
+        let mut max_time = Timestamp::mintime();
+        for (id, timestamp, status) in header.transactions {
+            // The process that owned pending transactions is gone, so they can never commit.
+            let status = match status {
+                WriteIntentStatus::Pending => WriteIntentStatus::Aborted,
+                status => status,
+            };
+            let txn = LockDataRef { id, timestamp };
+            ctx.transaction_map.insert_status(txn, status);
+            // Prepared transactions wait for their coordinator's decision, which the WAL may already have.
+            if status == WriteIntentStatus::Prepared {
+                ctx.in_doubt.lock().push(Transaction::from_prepared(txn));
+            }
+            max_time = max_time.max(timestamp);
+        }
+        for (id, timestamp, commit) in header.decisions {
+            ctx.decisions.restore(LockDataRef { id, timestamp }, commit);
+        }
+
+        for line in lines {
+            let line = line.map_err(|err| err.to_string())?;
+            let stored: StoredKey = serde_json::from_str(&line).map_err(|err| err.to_string())?;
+
+            // Rebuild the version chain from oldest to newest, so each version can link to the one before it.
+            let mut versions = stored.versions.into_iter().rev().peekable();
+            let mut prev = None;
+            while let Some(version) = versions.next() {
+                max_time = max_time.max(version.begin_ts).max(version.last_read);
+                let value = version.into_value(prev);
+                if versions.peek().is_some() {
+                    prev = Some(ctx.old_values_store.insert(value));
+                } else {
+                    ctx.db.insert_raw(stored.key.clone(), value);
+                }
+            }
+        }
+
+        Timestamp::advance_past(max_time);
+        Ok(Some(manifest.first_wal_segment))
+    }
+}
+
+pub(crate) fn write_line<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<(), String> {
+    serde_json::to_writer(&mut *writer, value).map_err(|err| err.to_string())?;
+    writer.write_all(b"\n").map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::rwtransaction_wrapper::Transaction;
+    use crate::timestamp::Timestamp;
+    use crate::DbContext;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("metastore-storage-{}", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn reopen_keeps_values_and_history() {
+        let dir = temp_dir();
+        let (first, second);
+        {
+            let ctx = DbContext::open(&dir).unwrap();
+            first = Timestamp::now();
+            let mut txn = Transaction::new_with_time(&ctx, first);
+            txn.write(&ctx, &"/test/a".into(), "1".into()).unwrap();
+            txn.commit(&ctx).unwrap();
+
+            second = Timestamp::now();
+            let mut txn = Transaction::new_with_time(&ctx, second);
+            txn.write(&ctx, &"/test/a".into(), "2".into()).unwrap();
+            txn.write(&ctx, &"/test/b".into(), "3".into()).unwrap();
+            txn.commit(&ctx).unwrap();
+
+            // Never committed, so it must not be visible after reopening.
+            let mut pending = Transaction::new_with_time(&ctx, Timestamp::now());
+            pending.write(&ctx, &"/test/c".into(), "4".into()).unwrap();
+
+            ctx.checkpoint().unwrap();
+        }
+
+        let ctx = DbContext::open(&dir).unwrap();
+        let mut txn = Transaction::new_with_time(&ctx, Timestamp::now());
+        assert!(txn.txn.timestamp > second);
+        assert_eq!(
+            txn.read_mvcc(&ctx, &"/test/a".into()).unwrap().get_val(),
+            &"2".into()
+        );
+        assert_eq!(
+            txn.read_mvcc(&ctx, &"/test/b".into()).unwrap().get_val(),
+            &"3".into()
+        );
+        assert_matches!(txn.read_mvcc(&ctx, &"/test/c".into()), Err(..));
+
+        let mut old = Transaction::new_with_time(&ctx, first);
+        assert_eq!(
+            old.read_mvcc(&ctx, &"/test/a".into()).unwrap().get_val(),
+            &"1".into()
+        );
+
+        std::mem::drop(ctx);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn closing_doesnt_checkpoint() {
+        let dir = temp_dir();
+        {
+            let ctx = DbContext::open(&dir).unwrap();
+            let mut txn = Transaction::new_with_time(&ctx, Timestamp::now());
+            txn.write(&ctx, &"/test/a".into(), "1".into()).unwrap();
+            txn.commit(&ctx).unwrap();
+        }
+        assert!(!dir.join(super::MANIFEST_FILE).exists());
+
+        // Recovered from the WAL alone.
+        let ctx = DbContext::open(&dir).unwrap();
+        let mut txn = Transaction::new_with_time(&ctx, Timestamp::now());
+        assert_eq!(
+            txn.read_mvcc(&ctx, &"/test/a".into()).unwrap().get_val(),
+            &"1".into()
+        );
+
+        std::mem::drop(ctx);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn open_empty_directory() {
+        let dir = temp_dir();
+        let ctx = DbContext::open(&dir).unwrap();
+        let mut txn = Transaction::new_with_time(&ctx, Timestamp::now());
+        assert_matches!(txn.read_mvcc(&ctx, &"/test/a".into()), Err(..));
+
+        std::mem::drop(ctx);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
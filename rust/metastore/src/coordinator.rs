@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use parking_lot::Mutex;
+
+use crate::rwtransaction_wrapper::LockDataRef;
+use crate::wal_watcher::{WalStorer, WalTxn};
+use crate::{DbContext, TxnError};
+
+// The transactions we coordinate a two-phase commit for (see `ReplicatedTxn::commit`) whose outcome the replicas
+// haven't acknowledged yet. The participants are the replicas behind `DbContext::replicators`: they're all reached
+// through it, so a decision is sent to all of them at once.
+//
+// A transaction is added (and a `Coordinating` record logged) before any replica is prepared, and removed (and an
+// `Acknowledged` record logged) once they all have its outcome. Until then it's kept in checkpoints, and recovery
+// finds it in the WAL, so a coordinator that crashes in between still tells the replicas. The outcome is only
+// recorded as committed in the same `commit_lock` section as the transaction's commit record, so anything without
+// one aborted.
+#[derive(Default)]
+pub(crate) struct Decisions(Mutex<HashMap<LockDataRef, Option<bool>>>);
+
+impl Decisions {
+    pub(crate) fn start(&self, txn: LockDataRef) {
+        self.0.lock().insert(txn, None);
+    }
+
+    // Returns false, and does nothing, for transactions we don't coordinate.
+    pub(crate) fn decide(&self, txn: LockDataRef, commit: bool) -> bool {
+        match self.0.lock().get_mut(&txn) {
+            Some(decision) => {
+                *decision = Some(commit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn acknowledged(&self, txn: LockDataRef) {
+        self.0.lock().remove(&txn);
+    }
+
+    // Decided transactions that the replicas may not know the outcome of.
+    pub(crate) fn undelivered(&self) -> Vec<(LockDataRef, bool)> {
+        self.0
+            .lock()
+            .iter()
+            .filter_map(|(txn, decision)| decision.map(|commit| (*txn, commit)))
+            .collect()
+    }
+
+    // Every transaction with its outcome, for a checkpoint. Must hold `commit_lock` exclusively, so the ones that
+    // haven't been decided yet can't have a commit record: if we crash, they abort.
+    pub(crate) fn all(&self) -> Vec<(LockDataRef, bool)> {
+        self.0
+            .lock()
+            .iter()
+            .map(|(txn, decision)| (*txn, decision.unwrap_or(false)))
+            .collect()
+    }
+
+    // After recovery, for the transactions that were in a checkpoint or the WAL.
+    pub(crate) fn restore(&self, txn: LockDataRef, commit: bool) {
+        self.0.lock().insert(txn, Some(commit));
+    }
+}
+
+// Logs that we're about to prepare `txn` on the replicas, so they get its outcome even if we crash.
+pub(crate) fn start(ctx: &DbContext, txn: LockDataRef) -> Result<(), TxnError> {
+    let _persist_guard = ctx.commit_lock.read();
+    ctx.wallog
+        .store(WalTxn::coordinating(txn))
+        .map_err(TxnError::Wal)?;
+    ctx.decisions.start(txn);
+    Ok(())
+}
+
+// Tells the replicas the outcome of `txn`. If they don't all acknowledge it, it's kept for `resend`. Transactions
+// that were aborted before `start` weren't prepared anywhere, so they aren't kept.
+pub(crate) fn deliver(ctx: &DbContext, txn: LockDataRef, commit: bool) -> Result<(), TxnError> {
+    let started = ctx.decisions.decide(txn, commit);
+    let res = if commit {
+        ctx.replicator().commit(txn)
+    } else {
+        ctx.replicator().abort(txn)
+    };
+    if let Err(err) = res.into_result() {
+        log::error!(
+            "Replicas didn't acknowledge that transaction {} {}: {}",
+            txn.id,
+            if commit { "committed" } else { "aborted" },
+            err
+        );
+        return Err(err);
+    }
+
+    if started {
+        let _persist_guard = ctx.commit_lock.read();
+        ctx.wallog
+            .store(WalTxn::acknowledged(txn))
+            .map_err(TxnError::Wal)?;
+        ctx.decisions.acknowledged(txn);
+    }
+    Ok(())
+}
+
+// Sends every outcome the replicas haven't acknowledged again. Returns the first error, if any; the transactions
+// that failed are kept for the next try.
+pub(crate) fn resend(ctx: &DbContext) -> Result<(), TxnError> {
+    let mut res = Ok(());
+    for (txn, commit) in ctx.decisions.undelivered() {
+        if let Err(err) = deliver(ctx, txn, commit) {
+            res = res.and(Err(err));
+        }
+    }
+    res
+}
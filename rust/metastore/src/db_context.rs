@@ -1,18 +1,36 @@
+use crate::checkpoint::CheckpointStore;
 use crate::replicated_slave::SelfContainedDb;
-use crate::wal_watcher::{ByteBufferWAL, WalLoader};
+use crate::wal_watcher::{ByteBufferWAL, WalDurability, WalLoader, WalPosition};
 
 use crate::history_storage::MutSlab;
-use crate::rwtransaction_wrapper::{IntentMap, MutBTreeMap};
+use crate::rwtransaction_wrapper::{IntentMap, LockDataRef, MutBTreeMap, Transaction};
 
+use crate::change_feed::{self, Subscribers, Subscription};
+use crate::coordinator::{self, Decisions};
 use crate::file_debugger::print_to_file;
 use crate::local_replication_handler::LocalReplicationHandler;
+use crate::read_only_txn::SnapshotRegistry;
+use crate::retry::{self, RetryOptions};
 use crate::rpc_handler::DatabaseInterface;
+use crate::object_path::ObjectPath;
+use crate::TypedValue;
+use crate::snapshot;
+use crate::time_travel::{self, Version};
+use crate::vacuum::{self, VacuumStats};
+use crate::wait_queue::{WaitPolicy, WaitQueues};
 use crate::timestamp::Timestamp;
 use crate::wal_watcher::wal_check_consistency::check_func1;
+use crate::{ReplicatedTxn, TxnError};
+use parking_lot::{Mutex, RwLock};
 use std::ops::Deref;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 pub mod self_contained_wrapper;
 
+const WAL_DIR: &str = "wal";
+
 pub fn create_empty_context() -> DbContext {
     DbContext {
         db: MutBTreeMap::new(),
@@ -20,6 +38,17 @@ pub fn create_empty_context() -> DbContext {
         old_values_store: MutSlab::new(),
         wallog: ByteBufferWAL::new(),
         replicators: None,
+        checkpoints: None,
+        commit_lock: RwLock::new(()),
+        vacuum_watermark: AtomicU64::new(Timestamp::mintime().0),
+        finished_transactions: AtomicU64::new(0),
+        in_doubt: Mutex::new(Vec::new()),
+        decisions: Decisions::default(),
+        diverged_replicas: Mutex::new(Vec::new()),
+        wait_policy: WaitPolicy::default(),
+        wait_queues: WaitQueues::default(),
+        snapshots: Arc::default(),
+        subscribers: Subscribers::default(),
     }
 }
 
@@ -33,6 +62,17 @@ pub fn create_replicated_context() -> DbContext {
             3,
             SelfContainedDb::default,
         ))),
+        checkpoints: None,
+        commit_lock: RwLock::new(()),
+        vacuum_watermark: AtomicU64::new(Timestamp::mintime().0),
+        finished_transactions: AtomicU64::new(0),
+        in_doubt: Mutex::new(Vec::new()),
+        decisions: Decisions::default(),
+        diverged_replicas: Mutex::new(Vec::new()),
+        wait_policy: WaitPolicy::default(),
+        wait_queues: WaitQueues::default(),
+        snapshots: Arc::default(),
+        subscribers: Subscribers::default(),
     }
 }
 
@@ -42,6 +82,25 @@ pub struct DbContext {
     pub old_values_store: MutSlab,
     pub wallog: ByteBufferWAL,
     pub replicators: Option<Box<dyn DatabaseInterface>>,
+    pub checkpoints: Option<CheckpointStore>,
+    // Held (shared) by commits while they log to the WAL and publish their status,
+    // and (exclusively) by `checkpoint` so it never sees a commit halfway done.
+    pub(crate) commit_lock: RwLock<()>,
+    // Versions that only transactions older than this could read may have been removed by `vacuum`.
+    pub(crate) vacuum_watermark: AtomicU64,
+    finished_transactions: AtomicU64,
+    // Transactions that were prepared (see `Transaction::prepare`) but whose outcome wasn't logged before the
+    // database was closed. They keep their write intents until `resolve_in_doubt` is called.
+    pub(crate) in_doubt: Mutex<Vec<Transaction>>,
+    // Outcomes of the replicated transactions we coordinated that the replicas haven't acknowledged yet.
+    pub(crate) decisions: Decisions,
+    // The replicas that disagreed with us about whether a write succeeded, see `TxnError::ReplicaDiverged`.
+    diverged_replicas: Mutex<Vec<String>>,
+    wait_policy: WaitPolicy,
+    pub(crate) wait_queues: WaitQueues,
+    // Shared with the snapshots of `Snapshot` transactions, which unregister themselves when they're dropped.
+    pub(crate) snapshots: Arc<SnapshotRegistry>,
+    pub(crate) subscribers: Subscribers,
 }
 
 impl Drop for DbContext {
@@ -57,7 +116,197 @@ impl Drop for DbContext {
     }
 }
 
+// Number of finished transactions between two automatic `reclaim_transactions` sweeps, see `transaction_finished`.
+const RECLAIM_INTERVAL: u64 = 10_000;
+
 impl DbContext {
+    // Opens the database stored in the data directory `path`, creating an empty one if it doesn't exist yet.
+    // Transactions that were committed to the WAL after the last `checkpoint` are replayed, and new transactions
+    // are appended to that same WAL. The data is still kept in memory, see `CheckpointStore`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        Self::open_with_durability(path, WalDurability::default())
+    }
+
+    pub fn open_with_durability<P: AsRef<Path>>(
+        path: P,
+        durability: WalDurability,
+    ) -> Result<Self, String> {
+        let checkpoints = CheckpointStore::open(path)?;
+        let mut ctx = create_empty_context();
+        let first_segment = checkpoints.load(&ctx)?.unwrap_or(0);
+
+        let mut wallog = ByteBufferWAL::open(checkpoints.dir().join(WAL_DIR), first_segment)?;
+        wallog.set_durability(durability);
+        let max_time = wallog.apply_from(&ctx, first_segment)?;
+        Timestamp::advance_past(max_time);
+
+        ctx.wallog = wallog;
+        ctx.checkpoints = Some(checkpoints);
+        Ok(ctx)
+    }
+
+    // Checkpoints the current state of the database to its data directory, so reopening it doesn't have to replay
+    // the whole WAL. WAL segments that are fully covered by the checkpoint are deleted afterwards.
+    // Only runs when called: committed transactions are already durable in the WAL, and a checkpoint dumps the
+    // whole database, so when to pay for one is up to the caller.
+    pub fn checkpoint(&self) -> Result<(), String> {
+        let checkpoints = self
+            .checkpoints
+            .as_ref()
+            .ok_or_else(|| "Database wasn't opened from a data directory".to_string())?;
+        let _commits = self.commit_lock.write();
+        let first_segment = self.wallog.rotate()?;
+        checkpoints.store(self, first_segment)?;
+        self.wallog.remove_segments_before(first_segment)
+    }
+
+    // Streams the changes committed from now on to keys starting with `prefix`. See `change_feed::subscribe`.
+    pub fn subscribe(&self, prefix: &ObjectPath) -> Subscription {
+        change_feed::subscribe(self, prefix, None).unwrap()
+    }
+
+    // Like `subscribe`, but first catches up on the changes of transactions that committed after the one at
+    // `position`, e.g. the `Change::position` of the last change a previous subscription got.
+    pub fn subscribe_after(
+        &self,
+        prefix: &ObjectPath,
+        position: WalPosition,
+    ) -> Result<Subscription, String> {
+        change_feed::subscribe(self, prefix, Some(position))
+    }
+
+    // Writes the keys visible at `time` to a snapshot file. See `snapshot::write`.
+    pub fn snapshot_at<P: AsRef<Path>>(&self, time: Timestamp, path: P) -> Result<usize, String> {
+        snapshot::write(self, time, path)
+    }
+
+    pub fn from_snapshot<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        snapshot::load(path)
+    }
+
+    // Runs `f` in a transaction and commits it, retrying on conflicts. See `retry::run_transaction`.
+    pub fn run_transaction<T, F>(&self, f: F) -> Result<T, TxnError>
+    where
+        F: FnMut(&mut ReplicatedTxn) -> Result<T, TxnError>,
+    {
+        retry::run_transaction(self, RetryOptions::default(), f)
+    }
+
+    pub fn run_transaction_with<T, F>(&self, options: RetryOptions, f: F) -> Result<T, TxnError>
+    where
+        F: FnMut(&mut ReplicatedTxn) -> Result<T, TxnError>,
+    {
+        retry::run_transaction(self, options, f)
+    }
+
+    // Reads `key` as it was at `time`. See `ReadOnlyTxn::at` for which timestamps can be read.
+    pub fn read_as_of(&self, key: &ObjectPath, time: Timestamp) -> Result<TypedValue, TxnError> {
+        time_travel::read_as_of(self, key, time)
+    }
+
+    pub fn range_as_of(
+        &self,
+        prefix: &ObjectPath,
+        time: Timestamp,
+    ) -> Result<Vec<(ObjectPath, TypedValue)>, TxnError> {
+        time_travel::range_as_of(self, prefix, time)
+    }
+
+    // Committed versions of `key`, oldest first. Only goes back as far as vacuum has kept them.
+    pub fn history(&self, key: &ObjectPath) -> Vec<Version> {
+        time_travel::history(self, key)
+    }
+
+    pub fn vacuum(&self) -> VacuumStats {
+        vacuum::vacuum(self)
+    }
+
+    pub fn vacuum_watermark(&self) -> Timestamp {
+        Timestamp(self.vacuum_watermark.load(Ordering::SeqCst))
+    }
+
+    pub fn reclaim_transactions(&self) -> usize {
+        vacuum::reclaim_transactions(self)
+    }
+
+    // Called whenever a transaction commits or aborts, to reclaim finished transaction records every so often.
+    pub(crate) fn transaction_finished(&self) {
+        let finished = self.finished_transactions.fetch_add(1, Ordering::SeqCst) + 1;
+        if finished % RECLAIM_INTERVAL == 0 {
+            self.reclaim_transactions();
+        }
+    }
+
+    // Prepared transactions still waiting for the coordinator to decide whether they commit.
+    pub fn in_doubt_transactions(&self) -> Vec<LockDataRef> {
+        self.in_doubt.lock().iter().map(|txn| txn.txn).collect()
+    }
+
+    pub fn resolve_in_doubt(&self, txn: LockDataRef, commit: bool) -> Result<(), TxnError> {
+        let mut in_doubt = self.in_doubt.lock();
+        let pos = in_doubt
+            .iter()
+            .position(|t| t.txn == txn)
+            .ok_or_else(|| format!("Transaction {} isn't in doubt", txn.id))?;
+        let mut txn = in_doubt.remove(pos);
+        drop(in_doubt);
+        let res = if commit {
+            txn.commit(self)
+        } else {
+            txn.abort_decided(self)
+        };
+        // Only fails if the decision couldn't be logged, then it's still in doubt.
+        if res.is_err() {
+            self.in_doubt.lock().push(txn);
+        }
+        res
+    }
+
+    pub(crate) fn take_in_doubt(&self) -> Vec<Transaction> {
+        std::mem::take(&mut *self.in_doubt.lock())
+    }
+
+    // Replicated transactions whose outcome we couldn't tell the replicas yet, e.g. because one of them was down
+    // or we restarted before getting to it.
+    pub fn undelivered_decisions(&self) -> Vec<LockDataRef> {
+        self.decisions
+            .undelivered()
+            .into_iter()
+            .map(|(txn, _)| txn)
+            .collect()
+    }
+
+    // Tells the replicas the outcome of every transaction in `undelivered_decisions`, e.g. after reopening the
+    // database and setting its `replicators` again. See `coordinator::resend`.
+    pub fn resend_decisions(&self) -> Result<(), TxnError> {
+        coordinator::resend(self)
+    }
+
+    pub fn wait_policy(&self) -> WaitPolicy {
+        self.wait_policy
+    }
+
+    pub fn set_wait_policy(&mut self, policy: WaitPolicy) {
+        self.wait_policy = policy;
+    }
+
+    // Whether the replicas may no longer match us. See `TxnError::ReplicaDiverged`.
+    pub fn replica_diverged(&self) -> bool {
+        !self.diverged_replicas.lock().is_empty()
+    }
+
+    // The replicas that may no longer match us.
+    pub fn diverged_replicas(&self) -> Vec<String> {
+        self.diverged_replicas.lock().clone()
+    }
+
+    pub(crate) fn flag_replica_diverged(&self, replica: &str) {
+        let mut diverged = self.diverged_replicas.lock();
+        if !diverged.iter().any(|r| r == replica) {
+            diverged.push(replica.to_string());
+        }
+    }
+
     pub fn replicator(&self) -> &Box<dyn DatabaseInterface> {
         self.replicators.as_ref().unwrap()
     }
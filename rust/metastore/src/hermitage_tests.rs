@@ -17,3 +17,282 @@ pub fn g1ctest() {
     txn1.commit().unwrap();
     txn2.commit().unwrap();
 }
+
+// The same tests at every isolation level. Conflicts show up as errors rather than blocking, so "prevented" means
+// that one of the transactions fails, or reads a value that keeps the history serializable.
+mod isolation_levels {
+    use std::time::Duration;
+
+    use crate::rwtransaction_wrapper::ReplicatedTxn;
+    use crate::isolation::IsolationLevel;
+    use crate::{DbContext, TxnError};
+
+    use IsolationLevel::*;
+
+    const LEVELS: [IsolationLevel; 3] = [Serializable, Snapshot, ReadCommitted];
+
+    fn begin(db: &DbContext, level: IsolationLevel) -> ReplicatedTxn<'_> {
+        ReplicatedTxn::new_with_isolation(db, level)
+    }
+
+    // Write cycles: prevented at every level, there's only ever one pending writer per key.
+    #[test]
+    fn g0() {
+        for level in LEVELS {
+            let db = db!("1" = "10", "2" = "20");
+            let mut txn1 = begin(&db, level);
+            let mut txn2 = begin(&db, level);
+            txn1.write(&"1".into(), "11".into()).unwrap();
+            assert_matches!(txn2.write(&"1".into(), "12".into()), Err(TxnError::WriteConflict(..)));
+            txn1.write(&"2".into(), "21".into()).unwrap();
+            txn1.commit().unwrap();
+            txn2.abort();
+        }
+    }
+
+    // Aborted reads: prevented at every level. Serializable fails on the pending write, the others read around it.
+    #[test]
+    fn g1a() {
+        for level in LEVELS {
+            let db = db!("1" = "10", "2" = "20");
+            let mut txn1 = begin(&db, level);
+            let mut txn2 = begin(&db, level);
+            txn1.write(&"1".into(), "101".into()).unwrap();
+            assert_eq!(txn1.read(&"1".into()), Ok("101".into()));
+            match level {
+                Serializable => assert_matches!(txn2.read(&"1".into()), Err(TxnError::WriteConflict(..))),
+                _ => assert_eq!(txn2.read(&"1".into()), Ok("10".into())),
+            }
+            txn1.abort();
+            assert_eq!(txn2.read(&"1".into()), Ok("10".into()));
+            txn2.commit().unwrap();
+        }
+    }
+
+    // Intermediate reads: prevented at every level, only the last write of a transaction is ever seen.
+    #[test]
+    fn g1b() {
+        for level in LEVELS {
+            let db = db!("1" = "10", "2" = "20");
+            let mut txn1 = begin(&db, level);
+            let mut txn2 = begin(&db, level);
+            txn1.write(&"1".into(), "101".into()).unwrap();
+            assert_ne!(txn2.read(&"1".into()), Ok("101".into()));
+            txn1.write(&"1".into(), "11".into()).unwrap();
+            txn1.commit().unwrap();
+            // The snapshot of txn2 was taken while txn1 was still running.
+            let expected = if level == Snapshot { "10" } else { "11" };
+            assert_eq!(txn2.read(&"1".into()), Ok(expected.into()));
+            txn2.commit().unwrap();
+        }
+    }
+
+    // Circular information flow: prevented at every level.
+    #[test]
+    fn g1c() {
+        for level in LEVELS {
+            let db = db!("1" = "10", "2" = "20");
+            let mut txn1 = begin(&db, level);
+            let mut txn2 = begin(&db, level);
+            txn1.write(&"1".into(), "11".into()).unwrap();
+            txn2.write(&"2".into(), "22".into()).unwrap();
+            // txn1 is older, so it reads the version before txn2's write.
+            assert_eq!(txn1.read(&"2".into()), Ok("20".into()));
+            match level {
+                Serializable => assert_matches!(txn2.read(&"1".into()), Err(TxnError::WriteConflict(..))),
+                _ => assert_eq!(txn2.read(&"1".into()), Ok("10".into())),
+            }
+            txn1.commit().unwrap();
+            txn2.commit().unwrap();
+        }
+    }
+
+    // Lost updates: allowed under read committed only.
+    #[test]
+    fn p4() {
+        for level in LEVELS {
+            let db = db!("1" = "10");
+            let mut txn1 = begin(&db, level);
+            let mut txn2 = begin(&db, level);
+            assert_eq!(txn1.read(&"1".into()), Ok("10".into()));
+            assert_eq!(txn2.read(&"1".into()), Ok("10".into()));
+            match level {
+                Serializable => {
+                    // txn2 already read the value txn1 would overwrite.
+                    assert_eq!(txn1.write(&"1".into(), "11".into()), Err(TxnError::ReadTimestampConflict));
+                    txn1.abort();
+                    txn2.write(&"1".into(), "11".into()).unwrap();
+                    txn2.commit().unwrap();
+                }
+                Snapshot => {
+                    txn1.write(&"1".into(), "11".into()).unwrap();
+                    txn1.commit().unwrap();
+                    assert_eq!(txn2.write(&"1".into(), "11".into()), Err(TxnError::SnapshotConflict));
+                    txn2.abort();
+                }
+                ReadCommitted => {
+                    txn1.write(&"1".into(), "11".into()).unwrap();
+                    txn1.commit().unwrap();
+                    // Both incremented 10, one of the increments is lost.
+                    txn2.write(&"1".into(), "11".into()).unwrap();
+                    txn2.commit().unwrap();
+                }
+            }
+        }
+    }
+
+    // Read skew: allowed under read committed only.
+    #[test]
+    fn g_single() {
+        for level in LEVELS {
+            let db = db!("1" = "10", "2" = "20");
+            let mut txn1 = begin(&db, level);
+            let mut txn2 = begin(&db, level);
+            assert_eq!(txn1.read(&"1".into()), Ok("10".into()));
+            assert_eq!(txn2.read(&"1".into()), Ok("10".into()));
+            assert_eq!(txn2.read(&"2".into()), Ok("20".into()));
+            txn2.write(&"1".into(), "12".into()).unwrap();
+            txn2.write(&"2".into(), "18".into()).unwrap();
+            txn2.commit().unwrap();
+            // Only 18 together with the 10 read before is inconsistent.
+            let expected = if level == ReadCommitted { "18" } else { "20" };
+            assert_eq!(txn1.read(&"2".into()), Ok(expected.into()));
+            txn1.commit().unwrap();
+        }
+    }
+
+    // Write skew: allowed under snapshot isolation and read committed.
+    #[test]
+    fn g2_item() {
+        for level in LEVELS {
+            let db = db!("1" = "10", "2" = "20");
+            let mut txn1 = begin(&db, level);
+            let mut txn2 = begin(&db, level);
+            for txn in [&mut txn1, &mut txn2] {
+                assert_eq!(txn.read(&"1".into()), Ok("10".into()));
+                assert_eq!(txn.read(&"2".into()), Ok("20".into()));
+            }
+            if level == Serializable {
+                assert_eq!(txn1.write(&"1".into(), "11".into()), Err(TxnError::ReadTimestampConflict));
+                txn1.abort();
+            } else {
+                txn1.write(&"1".into(), "11".into()).unwrap();
+                txn1.commit().unwrap();
+            }
+            txn2.write(&"2".into(), "21".into()).unwrap();
+            txn2.commit().unwrap();
+        }
+    }
+
+    // Predicate-many-preceders: a repeated range read sees a new key only under read committed.
+    #[test]
+    fn pmp() {
+        for level in LEVELS {
+            let db = db!("/test/1" = "10", "/test/2" = "20");
+            let mut txn1 = begin(&db, level);
+            let mut txn2 = begin(&db, level);
+            assert_eq!(txn1.read_range_owned(&"/test/".into()).unwrap().len(), 2);
+            txn2.write(&"/test/3".into(), "30".into()).unwrap();
+            txn2.commit().unwrap();
+            let expected = if level == ReadCommitted { 3 } else { 2 };
+            assert_eq!(txn1.read_range_owned(&"/test/".into()).unwrap().len(), expected);
+            txn1.commit().unwrap();
+        }
+    }
+
+    // Write skew on predicates: allowed under snapshot isolation and read committed.
+    #[test]
+    fn g2() {
+        for level in LEVELS {
+            let db = db!("/test/1" = "10", "/test/2" = "20");
+            let mut txn1 = begin(&db, level);
+            let mut txn2 = begin(&db, level);
+            assert_eq!(txn1.read_range_owned(&"/test/".into()).unwrap().len(), 2);
+            assert_eq!(txn2.read_range_owned(&"/test/".into()).unwrap().len(), 2);
+            if level == Serializable {
+                assert_eq!(txn1.write(&"/test/3".into(), "30".into()), Err(TxnError::PhantomDetected));
+                txn1.abort();
+            } else {
+                txn1.write(&"/test/3".into(), "30".into()).unwrap();
+                txn1.commit().unwrap();
+            }
+            txn2.write(&"/test/4".into(), "42".into()).unwrap();
+            txn2.commit().unwrap();
+        }
+    }
+
+    // A snapshot has what committed before it was taken, also while an older transaction is still running, and
+    // nothing that transaction writes, also once it commits.
+    #[test]
+    fn snapshots_read_around_pending_writers() {
+        let db = db!("1" = "10", "2" = "20");
+        let mut long = begin(&db, Serializable);
+        long.write(&"1".into(), "11".into()).unwrap();
+        let mut txn1 = begin(&db, Serializable);
+        txn1.write(&"2".into(), "21".into()).unwrap();
+        txn1.commit().unwrap();
+
+        let mut txn2 = begin(&db, Snapshot);
+        assert_eq!(txn2.read(&"2".into()), Ok("21".into()));
+        txn2.write(&"2".into(), "22".into()).unwrap();
+        assert_eq!(txn2.read(&"1".into()), Ok("10".into()));
+        long.commit().unwrap();
+        assert_eq!(txn2.read(&"1".into()), Ok("10".into()));
+        assert_eq!(txn2.write(&"1".into(), "12".into()), Err(TxnError::SnapshotConflict));
+        txn2.abort();
+    }
+
+    #[test]
+    fn snapshots_are_released() {
+        let db = db!("1" = "10");
+        let mut txn = begin(&db, Snapshot);
+        assert!(db.vacuum().watermark < txn.get_txn().timestamp);
+        txn.write(&"1".into(), "11".into()).unwrap();
+        assert_eq!(txn.read(&"1".into()), Ok("11".into()));
+        txn.commit().unwrap();
+        begin(&db, Snapshot).abort();
+        assert!(db.snapshots.with_oldest(|oldest| oldest.is_none()));
+        // Also when the transaction is dropped without finishing.
+        std::mem::drop(begin(&db, Snapshot));
+        assert!(db.snapshots.with_oldest(|oldest| oldest.is_none()));
+    }
+
+    // Vacuum keeps what a read committed statement reads while it reads it, also the version from before a pending
+    // write that it reads instead.
+    #[test]
+    fn read_committed_reads_while_vacuuming() {
+        let db = db!("1" = "0", "2" = "20");
+        let mut pending = begin(&db, Serializable);
+        pending.write(&"2".into(), "21".into()).unwrap();
+
+        crossbeam::scope(|s| {
+            s.spawn(|_| {
+                for i in 1..100 {
+                    let mut txn = begin(&db, Serializable);
+                    txn.write(&"1".into(), i.to_string().into()).unwrap();
+                    txn.commit().unwrap();
+                }
+            });
+            s.spawn(|_| {
+                for _ in 0..100 {
+                    db.vacuum();
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            });
+
+            let mut reader = begin(&db, ReadCommitted);
+            let mut last = 0;
+            for _ in 0..1000 {
+                let value: i64 = reader.read(&"1".into()).unwrap().as_str().unwrap().parse().unwrap();
+                assert!(value >= last);
+                last = value;
+                assert_eq!(reader.read(&"2".into()), Ok("20".into()));
+            }
+            reader.commit().unwrap();
+        })
+        .unwrap();
+
+        assert!(db.snapshots.with_oldest(|oldest| oldest.is_none()));
+        pending.commit().unwrap();
+    }
+}
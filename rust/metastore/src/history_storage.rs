@@ -23,6 +23,19 @@ impl MutSlab {
     pub fn new() -> Self {
         Default::default()
     }
+    // Removes every entry whose index `keep` returns false for. Returns the number of removed entries.
+    pub(crate) fn retain(&self, mut keep: impl FnMut(usize) -> bool) -> usize {
+        let slab = unsafe { &mut *self.0.lock().unwrap().get() };
+        let before = slab.len();
+        slab.retain(|index, _| keep(index));
+        before - slab.len()
+    }
+    pub fn len(&self) -> usize {
+        unsafe { &*self.0.lock().unwrap().get() }.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     pub fn insert(&self, v: ValueWithMVCC) -> usize {
         unsafe { &mut *self.0.lock().unwrap().get() }.insert(v)
     }
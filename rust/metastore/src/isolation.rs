@@ -0,0 +1,188 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::object_path::ObjectPath;
+use crate::read_only_txn::SnapshotRegistry;
+use crate::rwtransaction_wrapper::{
+    read_reference, read_snapshot, read_snapshot_hiding, IntentMap, LockDataRef, ValueWithMVCC,
+};
+use crate::timestamp::Timestamp;
+use crate::{DbContext, TxnError};
+
+// How much a transaction is isolated from the ones running at the same time. Writes take the same intents at every
+// level and can't go under a newer read or write, what differs is which versions reads see and whether they're
+// recorded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IsolationLevel {
+    // Reads are recorded at the transaction's timestamp, so older transactions can't change what was read.
+    #[default]
+    Serializable,
+    // Reads see the snapshot taken when the transaction started and aren't recorded. Writing a key someone else
+    // wrote after that snapshot fails, so updates can't be lost, but write skew is possible.
+    Snapshot,
+    // Every read sees the latest committed value and isn't recorded. Never waits for writers.
+    ReadCommitted,
+}
+
+// Where a transaction reads from, according to its isolation level. Serializable by default.
+#[derive(Debug, Default)]
+pub(crate) struct ReadView {
+    level: IsolationLevel,
+    snapshot: Option<Snapshot>,
+}
+
+// What a `Snapshot` transaction sees: the transactions that committed before it started. Registered in
+// `DbContext::snapshots` until it's dropped, so vacuum keeps those versions.
+#[derive(Debug)]
+struct Snapshot {
+    // The transaction's own timestamp. Newer transactions started after it.
+    time: Timestamp,
+    // The older transactions that were still running when it started. They stay hidden once they commit too.
+    pending: HashSet<Timestamp>,
+    registry: Arc<SnapshotRegistry>,
+    // Before `time` and all of `pending`, for the registry.
+    oldest: Timestamp,
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.registry.unregister(self.oldest);
+    }
+}
+
+// The timestamp a `ReadCommitted` statement reads at, registered in `DbContext::snapshots` until the statement is
+// done, so vacuum keeps the versions it reads.
+struct StatementSnapshot<'a> {
+    registry: &'a SnapshotRegistry,
+    txn: LockDataRef,
+}
+
+impl<'a> StatementSnapshot<'a> {
+    fn new(ctx: &'a DbContext, time: Timestamp) -> Result<Self, TxnError> {
+        // Registered before checking, like `ReadOnlyTxn::at`.
+        ctx.snapshots.register(time);
+        let snapshot = Self {
+            registry: &ctx.snapshots,
+            txn: IntentMap::generate_read_txn_with_time(time),
+        };
+        if time < ctx.vacuum_watermark() {
+            return Err(TxnError::SnapshotTooOld);
+        }
+        Ok(snapshot)
+    }
+}
+
+impl Drop for StatementSnapshot<'_> {
+    fn drop(&mut self) {
+        self.registry.unregister(self.txn.timestamp);
+    }
+}
+
+impl ReadView {
+    // Must be called right after `txn` is registered as pending, so every transaction older than it is registered
+    // too (see `closed_timestamp`).
+    pub(crate) fn new(ctx: &DbContext, level: IsolationLevel, txn: LockDataRef) -> Self {
+        let snapshot = (level == IsolationLevel::Snapshot).then(|| {
+            let pending: HashSet<Timestamp> = ctx
+                .transaction_map
+                .pending_before(txn.timestamp)
+                .into_iter()
+                .collect();
+            // Vacuum has to keep the versions from before us and before each pending transaction.
+            let oldest = pending.iter().fold(txn.timestamp, |oldest, time| oldest.min(*time));
+            let oldest = Timestamp(oldest.0 - 1);
+            ctx.snapshots.register(oldest);
+            Snapshot {
+                time: txn.timestamp,
+                pending,
+                registry: ctx.snapshots.clone(),
+                oldest,
+            }
+        });
+        Self { level, snapshot }
+    }
+
+    pub(crate) fn level(&self) -> IsolationLevel {
+        self.level
+    }
+
+    // Lets vacuum remove what the snapshot sees once the transaction finished. Dropping the view does too.
+    pub(crate) fn release(&mut self) {
+        self.snapshot = None;
+    }
+
+    // Whether reads are recorded on the values (and ranges) they read, so older writers fail instead.
+    pub(crate) fn records_reads(&self) -> bool {
+        self.level == IsolationLevel::Serializable
+    }
+
+    // Whether reads see the version written at `time`, unless it's ours. Writes fail if the latest version of the
+    // key isn't one of those. See `MVCCMetadata::check_write`.
+    pub(crate) fn sees(&self, time: Timestamp) -> bool {
+        self.snapshot.as_ref().is_none_or(|snapshot| {
+            time < snapshot.time && !snapshot.pending.contains(&time)
+        })
+    }
+
+    pub(crate) fn read(
+        &self,
+        ctx: &DbContext,
+        value: &ValueWithMVCC,
+        txn: LockDataRef,
+    ) -> Result<ValueWithMVCC, TxnError> {
+        if self.level == IsolationLevel::Serializable {
+            return read_reference(ctx, value, txn);
+        }
+        // Our own writes are visible at every level.
+        let own = value
+            .as_inner()
+            .0
+            .get_write_intents()
+            .is_some_and(|wi| wi.associated_transaction == txn);
+        if own {
+            return read_snapshot(ctx, value, txn);
+        }
+        match &self.snapshot {
+            // Pending writers are in `pending` (or newer than us), so they're read around.
+            Some(snapshot) => read_snapshot_hiding(
+                ctx,
+                value,
+                IntentMap::generate_read_txn_with_time(snapshot.time),
+                &|time| snapshot.pending.contains(&time),
+            ),
+            None => {
+                let now = StatementSnapshot::new(ctx, Timestamp::now())?;
+                match read_snapshot(ctx, value, now.txn) {
+                    // Read what was there before the pending write instead of waiting for it. If vacuum already
+                    // removed that, this fails with `TxnError::SnapshotTooOld` and the statement can be retried.
+                    Err(TxnError::WriteConflict(owner)) => {
+                        let before = StatementSnapshot::new(ctx, Timestamp(owner.timestamp.0 - 1))?;
+                        read_snapshot(ctx, value, before.txn)
+                    }
+                    res => res,
+                }
+            }
+        }
+    }
+}
+
+// Reads `key` at the isolation level of `view`. Only serializable reads of missing keys are recorded.
+pub(crate) fn read(
+    ctx: &DbContext,
+    view: &ReadView,
+    key: &ObjectPath,
+    txn: LockDataRef,
+) -> Result<ValueWithMVCC, TxnError> {
+    let mut lock = ctx.db.get_raw_with_lock(key);
+    match lock.value() {
+        Some(value) => view.read(ctx, value, txn),
+        None => {
+            if view.records_reads() {
+                ctx.db.record_missing_read(key, txn.timestamp);
+            }
+            Err(TxnError::NotFound)
+        }
+    }
+}
@@ -22,6 +22,7 @@ extern crate quickcheck_macros;
 pub use crate::db_context::DbContext;
 pub use crate::object_path::ObjectPath;
 pub use crate::rwtransaction_wrapper::ReplicatedTxn;
+pub use crate::read_only_txn::ReadOnlyTxn;
 
 // mod hyperserver;
 pub mod btree_index;
@@ -33,8 +34,13 @@ pub mod rwtransaction_wrapper;
 pub use replicated_slave::SelfContainedDb;
 pub use rwtransaction_wrapper::LockDataRef;
 pub use rpc_handler::DatabaseInterface;
-pub use rpc_handler::NetworkResult;
-pub use rwtransaction_wrapper::{ValueWithMVCC, TypedValue};
+pub use rpc_handler::{NetworkError, NetworkResult};
+pub use range_scan::{RangeScan, ScanOptions};
+pub use isolation::IsolationLevel;
+pub use retry::RetryOptions;
+pub use txn_error::TxnError;
+pub use wait_queue::WaitPolicy;
+pub use rwtransaction_wrapper::{Savepoint, ValueWithMVCC, TypedValue};
 
 #[macro_use]
 pub mod test_transaction_generate;
@@ -46,12 +52,23 @@ pub mod secondary_indexing;
 
 #[macro_use]
 pub mod error_macro;
+pub mod change_feed;
+pub mod checkpoint;
+mod coordinator;
 pub mod db_context;
 pub mod file_debugger;
 pub mod hermitage_tests;
 pub mod history_storage;
+pub mod isolation;
 mod local_replication_handler;
 pub mod replicated_slave;
-mod retry;
+pub mod range_scan;
+pub mod read_only_txn;
+pub mod retry;
 mod rpc_handler;
+pub mod snapshot;
+pub mod time_travel;
+pub mod txn_error;
+pub mod vacuum;
+pub mod wait_queue;
 mod tuple_maker;
@@ -1,6 +1,6 @@
 use crate::rpc_handler::{DatabaseInterface, NetworkResult};
-use crate::rwtransaction_wrapper::{LockDataRef, ValueWithMVCC};
-use crate::{ObjectPath, TypedValue};
+use crate::rwtransaction_wrapper::{LockDataRef, Savepoint, ValueWithMVCC};
+use crate::{ObjectPath, TxnError, TypedValue};
 use std::iter::FromIterator;
 
 // Automatically fans out replication requests to `replication_factor` of nodes.
@@ -9,7 +9,7 @@ pub struct LocalReplicationHandler<A> {
     replication_factor: u8,
 }
 impl<A: DatabaseInterface> DatabaseInterface for LocalReplicationHandler<A> {
-    fn new_transaction(&self, txn: &LockDataRef) -> NetworkResult<(), String> {
+    fn new_transaction(&self, txn: &LockDataRef) -> NetworkResult<(), TxnError> {
         self.iter(|a| a.new_transaction(txn));
         NetworkResult::default()
     }
@@ -18,7 +18,7 @@ impl<A: DatabaseInterface> DatabaseInterface for LocalReplicationHandler<A> {
         &self,
         txn: LockDataRef,
         key: &ObjectPath,
-    ) -> NetworkResult<ValueWithMVCC, String> {
+    ) -> NetworkResult<ValueWithMVCC, TxnError> {
         self.nodes.get(0).unwrap().serve_read(txn, key)
     }
 
@@ -26,7 +26,7 @@ impl<A: DatabaseInterface> DatabaseInterface for LocalReplicationHandler<A> {
         &self,
         txn: LockDataRef,
         key: &ObjectPath,
-    ) -> NetworkResult<Vec<(ObjectPath, ValueWithMVCC)>, String> {
+    ) -> NetworkResult<Vec<(ObjectPath, ValueWithMVCC)>, TxnError> {
         self.nodes.get(0).unwrap().serve_range_read(txn, key)
     }
 
@@ -35,20 +35,25 @@ impl<A: DatabaseInterface> DatabaseInterface for LocalReplicationHandler<A> {
         txn: LockDataRef,
         key: &ObjectPath,
         value: TypedValue,
-    ) -> NetworkResult<(), String> {
-        self.iter_result(|a| a.serve_write(txn, key, value.clone()))
+    ) -> NetworkResult<(), TxnError> {
+        self.iter_tagged(|a| a.serve_write(txn, key, value.clone()))
     }
 
-    fn commit(&self, txn: LockDataRef) -> NetworkResult<(), String> {
-        self.iter(|a| {
-            a.commit(txn);
-        });
-        Default::default()
+    fn prepare(&self, txn: LockDataRef) -> NetworkResult<(), TxnError> {
+        self.iter_result(|a| a.prepare(txn))
     }
 
-    fn abort(&self, p0: LockDataRef) -> NetworkResult<(), String> {
-        self.iter(|a| a.abort(p0));
-        Default::default()
+    fn rollback_to(&self, txn: LockDataRef, savepoint: Savepoint) -> NetworkResult<(), TxnError> {
+        self.iter_tagged(|a| a.rollback_to(txn, savepoint))
+    }
+
+    // Every replica is told, even after one fails, so only the ones that didn't acknowledge it get it again.
+    fn commit(&self, txn: LockDataRef) -> NetworkResult<(), TxnError> {
+        self.iter_result(|a| a.commit(txn))
+    }
+
+    fn abort(&self, p0: LockDataRef) -> NetworkResult<(), TxnError> {
+        self.iter_result(|a| a.abort(p0))
     }
 }
 
@@ -71,6 +76,26 @@ impl<A> LocalReplicationHandler<A> {
             .reduce(|a, b| a.and(b))
             .unwrap()
     }
+    // Like `iter_result`, but a replica that rejects the request is named in a `TxnError::ReplicaDiverged`, so
+    // the primary knows which one no longer matches it.
+    fn iter_tagged<Func: FnMut(&Box<A>) -> NetworkResult<(), TxnError>>(
+        &self,
+        mut function: Func,
+    ) -> NetworkResult<(), TxnError> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| match function(node).0 {
+                Ok(Err(err @ TxnError::ReplicaDiverged(_))) => NetworkResult::from(Err(err)),
+                Ok(Err(err)) => {
+                    log::error!("Replica {} rejected a request: {}", i, err);
+                    NetworkResult::from(Err(TxnError::ReplicaDiverged(i.to_string())))
+                }
+                res => NetworkResult(res),
+            })
+            .reduce(|a, b| a.and(b))
+            .unwrap()
+    }
     fn iter<Ret, Func: FnMut(&Box<A>) -> Ret>(&self, function: Func) -> Ret {
         self.nodes.iter().map(function).last().unwrap()
     }
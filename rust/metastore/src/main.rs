@@ -43,11 +43,10 @@ mod object_path;
 mod parsing;
 mod rwtransaction_wrapper;
 
-#[macro_use]
-mod retry;
-
 #[macro_use]
 mod test_transaction_generate;
+#[macro_use]
+mod retry;
 mod thread_tests;
 mod timestamp;
 mod wal_watcher;
@@ -57,14 +56,26 @@ mod tuple_maker;
 
 pub use crate::rwtransaction_wrapper::ReplicatedTxn;
 pub use rwtransaction_wrapper::TypedValue;
+pub use txn_error::TxnError;
 #[macro_use]
 mod error_macro;
 mod btree_index;
+mod change_feed;
+mod checkpoint;
+mod coordinator;
 mod db_context;
 mod file_debugger;
 mod hermitage_tests;
 mod history_storage;
+mod isolation;
+mod range_scan;
+mod read_only_txn;
 mod replicated_slave;
+mod snapshot;
+mod time_travel;
+mod txn_error;
+mod vacuum;
+mod wait_queue;
 
 fn main() {
     for _ in 0..20 {
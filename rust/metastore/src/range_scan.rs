@@ -0,0 +1,266 @@
+use std::collections::VecDeque;
+use std::ops::Bound;
+
+use crate::isolation::ReadView;
+use crate::object_path::ObjectPath;
+use crate::rwtransaction_wrapper::{LockDataRef, ValueWithMVCC};
+use crate::{DbContext, TxnError};
+
+// Number of keys `RangeScan` reads at once.
+const PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    // Return at most this many keys.
+    pub limit: Option<usize>,
+    // Only return keys after this one (before it, if `reverse`), e.g. the last key of the previous page.
+    pub start_after: Option<ObjectPath>,
+    // Return the keys from the largest to the smallest.
+    pub reverse: bool,
+}
+
+// Reads the keys under `prefix` like `Transaction::read_range_owned`, but only as far as `options` ask.
+// Only the part of the range that was actually scanned is recorded as read, so older transactions can still
+// insert keys past the last one returned. Below serializable, nothing is recorded.
+pub(crate) fn scan(
+    ctx: &DbContext,
+    txn: LockDataRef,
+    view: &ReadView,
+    prefix: &ObjectPath,
+    options: &ScanOptions,
+) -> Result<Vec<(ObjectPath, ValueWithMVCC)>, TxnError> {
+    let (mut lower, mut upper) = prefix.get_prefix_ranges();
+    if let (Some(cursor), Bound::Included(min), Bound::Included(max)) =
+        (&options.start_after, &lower, &upper)
+    {
+        // A cursor outside of the prefix skips either all of it or nothing.
+        if options.reverse {
+            if cursor <= min {
+                return Ok(Vec::new());
+            }
+            if cursor <= max {
+                upper = Bound::Excluded(cursor.clone());
+            }
+        } else {
+            if cursor >= max {
+                return Ok(Vec::new());
+            }
+            if cursor >= min {
+                lower = Bound::Excluded(cursor.clone());
+            }
+        }
+    }
+    let limit = options.limit.unwrap_or(usize::MAX);
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+
+    // Every page is recorded while it's still locked, so no key can be inserted into a part we scanned without
+    // seeing the read. Keys can still be inserted into the part that's left, but those are read by a later page.
+    let mut values = Vec::new();
+    ctx.db.scan_pages(
+        (lower, upper),
+        options.reverse,
+        |key, value| {
+            match view.read(ctx, value, txn) {
+                Ok(value) => values.push((key.clone(), value)),
+                // Keys that don't exist at our timestamp just aren't part of the range.
+                Err(TxnError::NotFound) => {}
+                Err(err) => return Err(err),
+            }
+            Ok(values.len() < limit)
+        },
+        |page| {
+            if view.records_reads() {
+                ctx.db.record_range_read(page, txn.timestamp);
+            }
+        },
+    )?;
+    Ok(values)
+}
+
+// Scans a range page by page, so it never has to be in memory all at once. Other transactions can run between
+// two pages, but every page is read at the transaction's timestamp, so together they're one consistent scan.
+pub struct RangeScan<'a> {
+    ctx: &'a DbContext,
+    txn: LockDataRef,
+    view: &'a ReadView,
+    prefix: ObjectPath,
+    // `start_after` and `limit` are moved forward after every page.
+    options: ScanOptions,
+    page: VecDeque<(ObjectPath, ValueWithMVCC)>,
+    done: bool,
+    // Returned instead of the first page, see `failing`.
+    error: Option<TxnError>,
+}
+
+impl<'a> RangeScan<'a> {
+    pub(crate) fn new(
+        ctx: &'a DbContext,
+        txn: LockDataRef,
+        view: &'a ReadView,
+        prefix: &ObjectPath,
+        options: ScanOptions,
+    ) -> Self {
+        Self {
+            ctx,
+            txn,
+            view,
+            prefix: prefix.clone(),
+            options,
+            page: VecDeque::new(),
+            done: false,
+            error: None,
+        }
+    }
+
+    // A scan that only returns `err`, for a transaction that can't read anymore.
+    pub(crate) fn failing(self, err: TxnError) -> Self {
+        Self {
+            error: Some(err),
+            ..self
+        }
+    }
+
+    fn next_page(&mut self) -> Result<(), TxnError> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        let page_size = self
+            .options
+            .limit
+            .map_or(PAGE_SIZE, |limit| limit.min(PAGE_SIZE));
+        let options = ScanOptions {
+            limit: Some(page_size),
+            ..self.options.clone()
+        };
+        let page = scan(self.ctx, self.txn, self.view, &self.prefix, &options)?;
+
+        self.done = page.len() < page_size;
+        if let Some(limit) = &mut self.options.limit {
+            *limit -= page.len();
+            self.done |= *limit == 0;
+        }
+        if let Some((last, _)) = page.last() {
+            self.options.start_after = Some(last.clone());
+        }
+        self.page = page.into();
+        Ok(())
+    }
+}
+
+// Stops after the first error.
+impl Iterator for RangeScan<'_> {
+    type Item = Result<(ObjectPath, ValueWithMVCC), TxnError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            if let Err(err) = self.next_page() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        self.page.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::range_scan::ScanOptions;
+    use crate::{ObjectPath, ReplicatedTxn, TxnError};
+
+    fn keys<V>(values: Vec<(ObjectPath, V)>) -> Vec<String> {
+        values
+            .into_iter()
+            .map(|(key, _)| key.as_str().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn pages_forward_and_backward() {
+        let db = db!(
+            "/test/a" = "1",
+            "/test/b" = "2",
+            "/test/c" = "3",
+            "/test/d" = "4",
+            "/user/a" = "5"
+        );
+        let mut txn = ReplicatedTxn::new(&db);
+        let prefix = "/test/".into();
+        let mut options = ScanOptions {
+            limit: Some(3),
+            ..ScanOptions::default()
+        };
+        let page = txn.scan(&prefix, &options).unwrap();
+        assert_eq!(keys(page), vec!["/test/a", "/test/b", "/test/c"]);
+        options.start_after = Some("/test/c".into());
+        assert_eq!(keys(txn.scan(&prefix, &options).unwrap()), vec!["/test/d"]);
+        options.start_after = Some("/test/d".into());
+        assert!(txn.scan(&prefix, &options).unwrap().is_empty());
+
+        let reverse = ScanOptions {
+            limit: Some(2),
+            start_after: Some("/test/d".into()),
+            reverse: true,
+        };
+        assert_eq!(
+            keys(txn.scan(&prefix, &reverse).unwrap()),
+            vec!["/test/c", "/test/b"]
+        );
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn only_the_scanned_part_is_read() {
+        let db = db!("/test/a" = "1", "/test/c" = "2", "/test/e" = "3");
+        let mut old = ReplicatedTxn::new(&db);
+        let mut txn = ReplicatedTxn::new(&db);
+        let options = ScanOptions {
+            limit: Some(2),
+            ..ScanOptions::default()
+        };
+        assert_eq!(keys(txn.scan(&"/test/".into(), &options).unwrap()).len(), 2);
+
+        assert_eq!(
+            old.write(&"/test/b".into(), "4".into()),
+            Err(TxnError::PhantomDetected)
+        );
+        assert_eq!(old.write(&"/test/d".into(), "4".into()), Ok(()));
+        old.commit().unwrap();
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn streams_in_pages() {
+        let db = db!("/other/a" = "0");
+        let mut writer = ReplicatedTxn::new(&db);
+        for i in 0..250 {
+            writer
+                .write(&format!("/test/{:03}", i).into(), i.to_string().into())
+                .unwrap();
+        }
+        writer.commit().unwrap();
+
+        let mut txn = ReplicatedTxn::new(&db);
+        let all: Result<Vec<_>, _> = txn
+            .scan_iter(&"/test/".into(), ScanOptions::default())
+            .collect();
+        let all = all.unwrap();
+        assert_eq!(all.len(), 250);
+        assert_eq!(all[249].0.as_str(), "/test/249");
+
+        let options = ScanOptions {
+            limit: Some(120),
+            start_after: Some("/test/200".into()),
+            reverse: true,
+        };
+        let last: Vec<_> = txn
+            .scan_iter(&"/test/".into(), options)
+            .map(|res| res.unwrap().0)
+            .collect();
+        assert_eq!(last.len(), 120);
+        assert_eq!(last[0].as_str(), "/test/199");
+        assert_eq!(last[119].as_str(), "/test/080");
+        txn.commit().unwrap();
+    }
+}
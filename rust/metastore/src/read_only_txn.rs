@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+
+use parking_lot::Mutex;
+
+use crate::object_path::ObjectPath;
+use crate::rwtransaction_wrapper::{read_snapshot, IntentMap, LockDataRef, ValueWithMVCC};
+use crate::timestamp::Timestamp;
+use crate::{DbContext, TxnError, TypedValue};
+
+// Timestamps of the read-only transactions that are still open, with how many are open at each.
+// `vacuum` keeps the versions they can see.
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotRegistry(Mutex<BTreeMap<Timestamp, usize>>);
+
+impl SnapshotRegistry {
+    // Holds the registry locked while `f` runs, so no snapshot can register in the meantime.
+    pub(crate) fn with_oldest<R>(&self, f: impl FnOnce(Option<Timestamp>) -> R) -> R {
+        let snapshots = self.0.lock();
+        f(snapshots.keys().next().copied())
+    }
+
+    pub(crate) fn register(&self, time: Timestamp) {
+        *self.0.lock().entry(time).or_insert(0) += 1;
+    }
+
+    pub(crate) fn unregister(&self, time: Timestamp) {
+        let mut snapshots = self.0.lock();
+        let count = snapshots.get_mut(&time).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            snapshots.remove(&time);
+        }
+    }
+}
+
+// The latest timestamp that no pending transaction can change anymore.
+pub(crate) fn closed_timestamp(ctx: &DbContext) -> Timestamp {
+    // Take the time first, so a transaction that registers itself after we looked at the pending ones
+    // is newer than us.
+    let now = Timestamp::now();
+    match ctx.transaction_map.oldest_pending() {
+        Some(oldest) if oldest <= now => Timestamp(oldest.0 - 1),
+        _ => now,
+    }
+}
+
+// A transaction that only reads, from a consistent snapshot of the database.
+// Unlike `ReplicatedTxn`, it doesn't record its reads on the values and isn't registered in the `IntentMap`,
+// so it never makes a writer fail. In exchange, it can only read at timestamps no writer can still change,
+// i.e. older than every transaction that hasn't finished yet.
+pub struct ReadOnlyTxn<'a> {
+    ctx: &'a DbContext,
+    txn: LockDataRef,
+}
+
+impl<'a> ReadOnlyTxn<'a> {
+    // Reads at the latest timestamp that no pending transaction can change anymore.
+    pub fn new(ctx: &'a DbContext) -> Self {
+        Self::at(ctx, closed_timestamp(ctx)).unwrap()
+    }
+
+    // Reads at `time`, which must not be newer than what `new` would choose, nor older than the vacuum watermark.
+    pub fn at(ctx: &'a DbContext, time: Timestamp) -> Result<Self, TxnError> {
+        if time > closed_timestamp(ctx) {
+            return Err("Transactions older than the read timestamp may still write".into());
+        }
+        // Register before checking, so a vacuum either already published its watermark or sees this snapshot.
+        ctx.snapshots.register(time);
+        if time < ctx.vacuum_watermark() {
+            ctx.snapshots.unregister(time);
+            return Err(TxnError::SnapshotTooOld);
+        }
+        Ok(Self {
+            ctx,
+            txn: IntentMap::generate_read_txn_with_time(time),
+        })
+    }
+
+    pub fn timestamp(&self) -> Timestamp {
+        self.txn.timestamp
+    }
+
+    pub fn read_mvcc(&self, key: &ObjectPath) -> Result<ValueWithMVCC, TxnError> {
+        let mut lock = self.ctx.db.get_raw_with_lock(key);
+        read_snapshot(self.ctx, lock.value().ok_or(TxnError::NotFound)?, self.txn)
+    }
+
+    pub fn read(&self, key: &ObjectPath) -> Result<TypedValue, TxnError> {
+        self.read_mvcc(key).map(|a| a.into_inner().1)
+    }
+
+    pub fn read_range_owned(
+        &self,
+        key: &ObjectPath,
+    ) -> Result<Vec<(ObjectPath, ValueWithMVCC)>, TxnError> {
+        let mut values = Vec::new();
+        self.ctx.db.scan_pages(
+            key.get_prefix_ranges(),
+            false,
+            |key, value| {
+                match read_snapshot(self.ctx, value, self.txn) {
+                    Ok(value) => values.push((key.clone(), value)),
+                    Err(TxnError::NotFound) => {}
+                    Err(err) => return Err(err),
+                }
+                Ok(true)
+            },
+            |_| {},
+        )?;
+        Ok(values)
+    }
+}
+
+impl Drop for ReadOnlyTxn<'_> {
+    fn drop(&mut self) {
+        self.ctx.snapshots.unregister(self.txn.timestamp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::read_only_txn::ReadOnlyTxn;
+    use crate::{ReplicatedTxn, TxnError};
+
+    #[test]
+    fn doesnt_make_writers_fail() {
+        let db = db!("/test/a" = "1", "/test/b" = "2");
+        let mut writer = ReplicatedTxn::new(&db);
+
+        let snapshot = ReadOnlyTxn::new(&db);
+        assert!(snapshot.timestamp() < writer.get_txn().timestamp);
+        assert_eq!(snapshot.read(&"/test/a".into()), Ok("1".into()));
+        assert_eq!(snapshot.read_range_owned(&"/test/".into()).unwrap().len(), 2);
+
+        // A regular transaction reading the key now would make this write fail.
+        writer.write(&"/test/a".into(), "3".into()).unwrap();
+        writer.write(&"/test/c".into(), "4".into()).unwrap();
+        writer.commit().unwrap();
+
+        assert_eq!(snapshot.read(&"/test/a".into()), Ok("1".into()));
+        assert_eq!(snapshot.read(&"/test/c".into()), Err(TxnError::NotFound));
+        assert_eq!(snapshot.read_range_owned(&"/test/".into()).unwrap().len(), 2);
+        assert_eq!(ReadOnlyTxn::new(&db).read(&"/test/a".into()), Ok("3".into()));
+    }
+
+    #[test]
+    fn cant_read_what_pending_transactions_may_change() {
+        let db = db!("/test/a" = "1");
+        let writer = ReplicatedTxn::new(&db);
+        assert!(ReadOnlyTxn::at(&db, writer.get_txn().timestamp).is_err());
+        assert!(ReadOnlyTxn::at(&db, ReadOnlyTxn::new(&db).timestamp()).is_ok());
+        writer.commit().unwrap();
+    }
+
+    #[test]
+    fn vacuum_keeps_versions_of_open_snapshots() {
+        let db = db!("/test/a" = "1");
+        let snapshot = ReadOnlyTxn::new(&db);
+        for i in 2..5 {
+            let mut txn = ReplicatedTxn::new(&db);
+            txn.write(&"/test/a".into(), i.to_string().into()).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let stats = db.vacuum();
+        assert!(stats.watermark <= snapshot.timestamp());
+        assert_eq!(snapshot.read(&"/test/a".into()), Ok("1".into()));
+        let old = snapshot.timestamp();
+        std::mem::drop(snapshot);
+
+        db.vacuum();
+        assert!(db.vacuum_watermark() > old);
+        assert_eq!(ReadOnlyTxn::at(&db, old).err(), Some(TxnError::SnapshotTooOld));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::object_path::ObjectPath;
-use crate::rwtransaction_wrapper::{LockDataRef, Transaction, ValueWithMVCC};
+use crate::rwtransaction_wrapper::{LockDataRef, Transaction, ValueWithMVCC, WriteIntentStatus};
+use crate::wait_queue::WaitPolicy;
 use crate::DbContext;
 
 use std::cell::UnsafeCell;
@@ -10,6 +11,7 @@ use parking_lot::{FairMutex, FairMutexGuard, Mutex, RwLock};
 use std::ops::{Deref, DerefMut};
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
 
 struct ConcurrentHashmap {
     lock: FairMutex<()>,
@@ -114,11 +116,19 @@ unsafe impl Sync for SelfContainedDb {}
 
 unsafe impl Send for SelfContainedDb {}
 
+// How long a replica waits for a conflicting intent to be resolved. The primary has already accepted the write,
+// so the intent can only belong to a transaction whose outcome hasn't reached us yet.
+const REPLICA_WAIT_TIMEOUT: Duration = Duration::from_secs(1);
+
 // Utility implementation
 impl Default for SelfContainedDb {
     fn default() -> Self {
+        let mut db = create_empty_context();
+        db.set_wait_policy(WaitPolicy::WaitForOlder {
+            timeout: REPLICA_WAIT_TIMEOUT,
+        });
         Self {
-            db: create_empty_context(),
+            db,
             transactions: ConcurrentHashmap::new(),
         }
     }
@@ -129,10 +139,12 @@ impl SelfContainedDb {
         Self::new(create_replicated_context())
     }
     pub fn new(db: DbContext) -> Self {
-        Self {
-            db,
-            transactions: ConcurrentHashmap::new(),
+        let transactions = ConcurrentHashmap::new();
+        // Transactions that were prepared before a restart get their decision from the coordinator like any other.
+        for txn in db.take_in_doubt() {
+            transactions.insert(txn.txn, txn);
         }
+        Self { db, transactions }
     }
     pub fn get_inner(&self) -> &DbContext {
         &self.db
@@ -163,17 +175,35 @@ impl SelfContainedDb {
         }
     }
 
+    // The coordinator resends its decision until we acknowledge it, so we can hear it again after finishing `txn`.
+    // Returns the answer in that case. A transaction we don't know anymore was finished and reclaimed.
+    fn already_finished(&self, txn: &LockDataRef, commit: bool) -> Option<NetworkResult<(), TxnError>> {
+        let status = match self.db.transaction_map.get_by_ref(txn) {
+            Some(data) if data.is_finished() => data.get_write_intent(),
+            Some(_) => return None,
+            None => return Some(NetworkResult::default()),
+        };
+        Some(NetworkResult::from(
+            match (commit, status == WriteIntentStatus::Committed) {
+                (true, false) => Err(TxnError::Aborted),
+                (false, true) => Err(format!("Transaction {} already committed", txn.id).into()),
+                _ => Ok(()),
+            },
+        ))
+    }
+
     fn remove_txn(&self, a: HashmapGuard<'_>) {
         self.transactions.remove(a)
     }
 }
 
-use super::rwtransaction_wrapper::TypedValue;
+use super::rwtransaction_wrapper::{Savepoint, TypedValue};
 use crate::rpc_handler::{DatabaseInterface, NetworkResult};
+use crate::TxnError;
 
 /// Main transaction-related implementations
 impl DatabaseInterface for SelfContainedDb {
-    fn new_transaction(&self, txn: &LockDataRef) -> NetworkResult<(), String> {
+    fn new_transaction(&self, txn: &LockDataRef) -> NetworkResult<(), TxnError> {
         let _txn = self.create_txn(txn);
         NetworkResult::default()
     }
@@ -183,7 +213,7 @@ impl DatabaseInterface for SelfContainedDb {
         &self,
         txn: LockDataRef,
         key: &ObjectPath,
-    ) -> NetworkResult<ValueWithMVCC, String> {
+    ) -> NetworkResult<ValueWithMVCC, TxnError> {
         let mut rwtxn = self.get_txn(&txn);
         NetworkResult::from(rwtxn.read_mvcc(&self.db, key))
     }
@@ -191,7 +221,7 @@ impl DatabaseInterface for SelfContainedDb {
         &self,
         txn: LockDataRef,
         key: &ObjectPath,
-    ) -> NetworkResult<Vec<(ObjectPath, ValueWithMVCC)>, String> {
+    ) -> NetworkResult<Vec<(ObjectPath, ValueWithMVCC)>, TxnError> {
         let mut rwtxn = self.get_txn(&txn);
         NetworkResult::from(rwtxn.read_range_owned(&self.db, key))
     }
@@ -201,20 +231,40 @@ impl DatabaseInterface for SelfContainedDb {
         txn: LockDataRef,
         key: &ObjectPath,
         value: TypedValue,
-    ) -> NetworkResult<(), String> {
+    ) -> NetworkResult<(), TxnError> {
         let mut rwtxn = self.get_txn(&txn);
         NetworkResult::from(rwtxn.write(&self.db, key, value))
     }
-    fn commit(&self, txn: LockDataRef) -> NetworkResult<(), String> {
+    fn prepare(&self, txn: LockDataRef) -> NetworkResult<(), TxnError> {
         let mut rwtxn = self.get_txn(&txn);
-        rwtxn.commit(&self.db).unwrap();
-        self.remove_txn(rwtxn);
-        NetworkResult::default()
+        NetworkResult::from(rwtxn.prepare(&self.db))
     }
-    fn abort(&self, p0: LockDataRef) -> NetworkResult<(), String> {
+    fn rollback_to(&self, txn: LockDataRef, savepoint: Savepoint) -> NetworkResult<(), TxnError> {
+        let mut rwtxn = self.get_txn(&txn);
+        NetworkResult::from(rwtxn.rollback_to(&self.db, savepoint))
+    }
+    fn commit(&self, txn: LockDataRef) -> NetworkResult<(), TxnError> {
+        if let Some(res) = self.already_finished(&txn, true) {
+            return res;
+        }
+        let mut rwtxn = self.get_txn(&txn);
+        let res = rwtxn.commit(&self.db);
+        // Kept if it's still prepared, for when the coordinator sends its decision again.
+        if res.is_ok() || !rwtxn.is_prepared() {
+            self.remove_txn(rwtxn);
+        }
+        NetworkResult::from(res)
+    }
+    fn abort(&self, p0: LockDataRef) -> NetworkResult<(), TxnError> {
+        if let Some(res) = self.already_finished(&p0, false) {
+            return res;
+        }
         let mut rwtxn = self.get_txn(&p0);
-        rwtxn.abort(&self.db);
-        self.remove_txn(rwtxn);
-        NetworkResult::default()
+        let res = rwtxn.abort_decided(&self.db);
+        // Kept if it's still prepared, for when the coordinator sends its decision again.
+        if res.is_ok() {
+            self.remove_txn(rwtxn);
+        }
+        NetworkResult::from(res)
     }
 }
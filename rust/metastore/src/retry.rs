@@ -1,19 +1,190 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::{DbContext, ReplicatedTxn, TxnError};
+
 #[macro_export]
 macro_rules! retry5 {
     ($tree:tt) => {
         let mut counter = 0;
         loop {
-            let res: Result<(), String> = try {
-                $tree
-                Ok(())
-            };
+            let res: Result<(), String> = try { $tree };
             if res.is_ok() {
                 break;
-            } else if counter < 5 {
+            } else if counter >= 5 {
                 return Err("Too many retries".to_string());
             } else {
-                counter+=1;
+                counter += 1;
             }
         };
     };
 }
+
+// How `DbContext::run_transaction` retries transactions that failed with a retryable error.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryOptions {
+    // Number of times the transaction is run at most, including the first one.
+    pub max_attempts: u32,
+    // Backoff after the first failed attempt. It doubles with each further attempt, up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryOptions {
+    // Somewhere between half and all of the exponential backoff, so conflicting transactions don't retry in lockstep.
+    fn backoff(&self, failed_attempts: u32) -> Duration {
+        let exp = self
+            .initial_backoff
+            .saturating_mul(1 << failed_attempts.saturating_sub(1).min(31))
+            .min(self.max_backoff);
+        rand::thread_rng().gen_range(exp / 2..=exp)
+    }
+}
+
+// Runs `f` in a new transaction and commits it. If `f` or the commit fails with a retryable error, the transaction
+// is aborted and `f` runs again in a new transaction (with a newer timestamp), after backing off.
+pub fn run_transaction<T, F>(ctx: &DbContext, options: RetryOptions, mut f: F) -> Result<T, TxnError>
+where
+    F: FnMut(&mut ReplicatedTxn) -> Result<T, TxnError>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let mut txn = ReplicatedTxn::new(ctx);
+        let res = match f(&mut txn) {
+            Ok(val) => txn.commit().map(|_| val),
+            Err(err) => {
+                txn.abort();
+                Err(err)
+            }
+        };
+        match res {
+            Err(err) if err.is_retryable() => {
+                if attempts >= options.max_attempts {
+                    return Err(TxnError::RetriesExhausted(Box::new(err)));
+                }
+                std::thread::sleep(options.backoff(attempts));
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::db_context::create_empty_context;
+    use crate::retry::RetryOptions;
+    use crate::{ReplicatedTxn, TxnError};
+
+    fn retry5_until(succeeds_at: u32) -> Result<u32, String> {
+        let mut calls = 0;
+        retry5!({
+            calls += 1;
+            if calls < succeeds_at {
+                Err("conflict".to_string())?;
+            }
+        });
+        Ok(calls)
+    }
+
+    #[test]
+    fn retry5_retries() {
+        assert_eq!(retry5_until(1), Ok(1));
+        assert_eq!(retry5_until(3), Ok(3));
+        assert_eq!(retry5_until(100), Err("Too many retries".to_string()));
+    }
+
+    #[test]
+    fn retries_conflicts() {
+        let db = db!("/test/a" = "1");
+        let mut blocker = ReplicatedTxn::new(&db);
+        blocker.write(&"/test/a".into(), "2".into()).unwrap();
+
+        let mut attempts = 0;
+        let res = db.run_transaction(|txn| {
+            attempts += 1;
+            if attempts == 3 {
+                std::mem::replace(&mut blocker, ReplicatedTxn::new(&db)).commit()?;
+            }
+            let val = txn.read(&"/test/a".into())?;
+            txn.write(&"/test/b".into(), val.clone())?;
+            Ok(val)
+        });
+        assert_eq!(res, Ok("2".into()));
+        assert_eq!(attempts, 3);
+        blocker.abort();
+    }
+
+    #[test]
+    fn gives_up_with_the_last_error() {
+        let db = db!("/test/a" = "1");
+        let mut blocker = ReplicatedTxn::new(&db);
+        blocker.write(&"/test/a".into(), "2".into()).unwrap();
+        let options = RetryOptions {
+            max_attempts: 3,
+            initial_backoff: Duration::from_micros(10),
+            max_backoff: Duration::from_micros(100),
+        };
+
+        let mut attempts = 0;
+        let res = db.run_transaction_with(options, |txn| {
+            attempts += 1;
+            txn.read(&"/test/a".into())
+        });
+        assert_eq!(
+            res,
+            Err(TxnError::RetriesExhausted(Box::new(
+                TxnError::WriteConflict(*blocker.get_txn())
+            )))
+        );
+        assert_eq!(attempts, 3);
+
+        // Errors that retrying can't fix are returned right away.
+        attempts = 0;
+        let res = db.run_transaction_with(options, |txn| {
+            attempts += 1;
+            txn.read(&"/test/c".into())
+        });
+        assert_eq!(res, Err(TxnError::NotFound));
+        assert_eq!(attempts, 1);
+        blocker.abort();
+    }
+
+    #[test]
+    fn runs_without_replicas() {
+        let db = create_empty_context();
+        let mut blocker = ReplicatedTxn::new(&db);
+        blocker.write(&"/test/a".into(), "1".into()).unwrap();
+
+        let mut attempts = 0;
+        let res = db.run_transaction(|txn| {
+            attempts += 1;
+            if attempts == 2 {
+                std::mem::replace(&mut blocker, ReplicatedTxn::new(&db)).commit()?;
+            }
+            let val = txn.read(&"/test/a".into())?;
+            txn.write(&"/test/b".into(), val.clone())?;
+            Ok(val)
+        });
+        assert_eq!(res, Ok("1".into()));
+        assert_eq!(attempts, 2);
+        assert_eq!(
+            db.run_transaction(|txn| txn.read(&"/test/b".into())),
+            Ok("1".into())
+        );
+        blocker.abort();
+    }
+}
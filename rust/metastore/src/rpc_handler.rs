@@ -3,13 +3,19 @@ use std::iter::FromIterator;
 use std::ops::{ControlFlow, FromResidual, Try};
 
 use crate::replicated_slave::SelfContainedDb;
-use crate::rwtransaction_wrapper::{LockDataRef, TypedValue, ValueWithMVCC};
-use crate::ObjectPath;
+use crate::rwtransaction_wrapper::{LockDataRef, Savepoint, TypedValue, ValueWithMVCC};
+use crate::{ObjectPath, TxnError};
 use std::fmt::Debug;
 
 #[derive(Debug)]
 pub struct NetworkError(std::io::Error);
 
+impl NetworkError {
+    pub fn new<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> Self {
+        Self(std::io::Error::new(std::io::ErrorKind::Other, err))
+    }
+}
+
 impl From<NetworkError> for String {
     fn from(a: NetworkError) -> Self {
         a.0.to_string()
@@ -17,11 +23,19 @@ impl From<NetworkError> for String {
 }
 pub struct NetworkResult<R, E>(pub Result<Result<R, E>, NetworkError>);
 
+impl<R, E: From<NetworkError>> NetworkResult<R, E> {
+    // Folds network errors into the error type.
+    pub fn into_result(self) -> Result<R, E> {
+        self.0.map_err(E::from).and_then(|res| res)
+    }
+}
+
 impl<R, E> NetworkResult<R, E> {
+    // The first error of the two, if any.
     pub fn and(self, res: NetworkResult<R, E>) -> NetworkResult<R, E> {
         match self.0 {
-            Ok(_) => res,
-            Err(_) => self,
+            Ok(Ok(_)) => res,
+            _ => self,
         }
     }
 }
@@ -40,7 +54,7 @@ impl<R, E> From<Result<R, E>> for NetworkResult<R, E> {
     }
 }
 
-impl Default for NetworkResult<(), String> {
+impl Default for NetworkResult<(), TxnError> {
     fn default() -> Self {
         Self::from(Result::Ok(()))
     }
@@ -50,25 +64,29 @@ impl Default for NetworkResult<(), String> {
 // todo: make an interface for GRPC only, specifically for async stuff.
 // todo: terminate instruction.
 pub trait DatabaseInterface {
-    fn new_transaction(&self, txn: &LockDataRef) -> NetworkResult<(), String>;
+    fn new_transaction(&self, txn: &LockDataRef) -> NetworkResult<(), TxnError>;
     fn serve_read(
         &self,
         txn: LockDataRef,
         key: &ObjectPath,
-    ) -> NetworkResult<ValueWithMVCC, String>;
+    ) -> NetworkResult<ValueWithMVCC, TxnError>;
     fn serve_range_read(
         &self,
         txn: LockDataRef,
         key: &ObjectPath,
-    ) -> NetworkResult<Vec<(ObjectPath, ValueWithMVCC)>, String>;
+    ) -> NetworkResult<Vec<(ObjectPath, ValueWithMVCC)>, TxnError>;
     fn serve_write(
         &self,
         txn: LockDataRef,
         key: &ObjectPath,
         value: TypedValue,
-    ) -> NetworkResult<(), String>;
-    fn commit(&self, txn: LockDataRef) -> NetworkResult<(), String>;
-    fn abort(&self, p0: LockDataRef) -> NetworkResult<(), String>;
+    ) -> NetworkResult<(), TxnError>;
+    // First phase of two-phase commit: make the transaction's writes durable and promise to commit them.
+    fn prepare(&self, txn: LockDataRef) -> NetworkResult<(), TxnError>;
+    // Undoes the transaction's writes made after `savepoint`, see `Transaction::rollback_to`.
+    fn rollback_to(&self, txn: LockDataRef, savepoint: Savepoint) -> NetworkResult<(), TxnError>;
+    fn commit(&self, txn: LockDataRef) -> NetworkResult<(), TxnError>;
+    fn abort(&self, p0: LockDataRef) -> NetworkResult<(), TxnError>;
 }
 
 impl<R, E> FromResidual<Result<std::convert::Infallible, NetworkError>> for NetworkResult<R, E> {
@@ -1,35 +1,60 @@
 mod mvcc_manager;
 
+use crate::coordinator;
+use crate::isolation::{self, IsolationLevel, ReadView};
 use crate::object_path::ObjectPath;
+use crate::range_scan::{self, RangeScan, ScanOptions};
+use crate::wait_queue;
 use crate::DbContext;
 pub use mvcc_manager::btreemap_kv_backend::MutBTreeMap;
 pub use mvcc_manager::IntentMap;
 pub use mvcc_manager::MVCCMetadata;
-use mvcc_manager::WriteIntentStatus;
+pub use mvcc_manager::{WriteIntent, WriteIntentStatus};
 pub use mvcc_manager::{LockDataRef, UnlockedWritableMVCC, ValueWithMVCC};
+pub(crate) use mvcc_manager::{read_reference, read_snapshot, read_snapshot_hiding};
 use std::assert_matches::debug_assert_matches;
-use log::debug;
+use std::collections::BTreeSet;
 
+// A transaction that's run on the replicas as well. On a database without replicators (e.g. one from
+// `DbContext::open` or `create_empty_context`), it's just its local `Transaction`.
 pub struct ReplicatedTxn<'a> {
     ctx: &'a DbContext,
     main: Transaction,
     done: bool,
 }
 
+// A point in a transaction that its later writes can be rolled back to, see `Transaction::rollback_to`.
+// It's the number of writes the transaction had made, which is the same on the replicas as on the primary.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Savepoint(pub usize);
+
 // equivalent to RWTransactionWrapper but without the borrowing reference.
 #[derive(Debug)]
 pub struct Transaction {
     pub(crate) txn: LockDataRef,
-    written_kv: Vec<(ObjectPath, ValueWithMVCC)>,
+    // Keys we've written, so our intents on them can be resolved as soon as we commit or abort.
+    written_keys: BTreeSet<ObjectPath>,
     log: WalTxn,
+    // Whether our writes are already in the WAL as a prepared transaction.
+    prepared: bool,
+    view: ReadView,
 }
 
 impl Transaction {
     fn new(txn: LockDataRef) -> Self {
         Self {
             txn,
-            log: WalTxn::new(txn.timestamp),
-            written_kv: Vec::new(),
+            log: WalTxn::for_txn(txn),
+            written_keys: BTreeSet::new(),
+            prepared: false,
+            view: ReadView::default(),
+        }
+    }
+    // A transaction that was prepared before the database restarted. Its writes are already in the database.
+    pub(crate) fn from_prepared(txn: LockDataRef) -> Self {
+        Self {
+            prepared: true,
+            ..Self::new(txn)
         }
     }
     pub fn new_with_time_id(ctx: &DbContext, time: Timestamp, id: u64) -> Self {
@@ -42,45 +67,73 @@ impl Transaction {
             .make_write_txn_with_time(time, Timestamp::now().0);
         Self::new(txn)
     }
-    pub fn abort(&mut self, ctx: &DbContext) {
-        ctx.transaction_map
-            .set_txn_status(self.txn, WriteIntentStatus::Aborted)
-            .unwrap();
+    pub fn new_with_isolation(ctx: &DbContext, time: Timestamp, level: IsolationLevel) -> Self {
+        let mut ret = Self::new_with_time(ctx, time);
+        ret.view = ReadView::new(ctx, level, ret.txn);
+        ret
+    }
+    pub fn isolation(&self) -> IsolationLevel {
+        self.view.level()
+    }
+    // Fails if we already committed, or prepared: then only the coordinator can abort us, see `abort_decided`.
+    pub fn abort(&mut self, ctx: &DbContext) -> Result<(), TxnError> {
+        if self.prepared {
+            return Err(TxnError::Prepared);
+        }
+        self.abort_decided(ctx)
+    }
+    // Aborts because the coordinator of a two-phase commit decided so, even if we're prepared.
+    pub(crate) fn abort_decided(&mut self, ctx: &DbContext) -> Result<(), TxnError> {
+        {
+            let _persist_guard = ctx.commit_lock.read();
+            if self.prepared {
+                // We stay prepared if the decision isn't logged, the coordinator sends it again.
+                ctx.wallog
+                    .store(WalTxn::decision(self.txn, false))
+                    .map_err(TxnError::Wal)?;
+            }
+            ctx.transaction_map
+                .abort_prepared(self.txn)
+                .map_err(|_| TxnError::Committed)?;
+        }
+        self.resolve_intents(ctx, WriteIntentStatus::Aborted);
+        self.view.release();
+        ctx.transaction_finished();
+        Ok(())
+    }
+    // Clears our intents after committing, or rolls back our writes after aborting, so later readers
+    // don't have to look up our status and fix up the values themselves.
+    fn resolve_intents(&mut self, ctx: &DbContext, status: WriteIntentStatus) {
+        for key in std::mem::take(&mut self.written_keys) {
+            ctx.db
+                .with_value_mut(&key, |value| value.resolve_intent_of(ctx, self.txn, status));
+            ctx.wait_queues.notify(&key);
+        }
     }
     pub fn read_range_owned(
         &mut self,
         ctx: &DbContext,
         key: &ObjectPath,
-    ) -> Result<Vec<(ObjectPath, ValueWithMVCC)>, String> {
-        let (_lock, range) = ctx
-            .db
-            .range_with_lock(key.get_prefix_ranges(), self.txn.timestamp);
-
-        let mut keys1 = Vec::new();
-        for (key, value_ptr) in range {
-            match mvcc_manager::read_reference(ctx, value_ptr, self.txn) {
-                Ok(kv2) => {
-                    keys1.push((key.clone(), kv2));
-                }
-                // These are the acceptable errors
-                Err(ReadError::ValueNotFound) => {}
-                Err(ReadError::Other(a)) if &a == "Other(\"Read value doesn't exist\")" => {}
-                Err(err) => {
-                    return Err(format!("{:?}", err));
-                }
-            }
-        }
-
-        Ok(keys1)
+    ) -> Result<Vec<(ObjectPath, ValueWithMVCC)>, TxnError> {
+        self.scan(ctx, key, &ScanOptions::default())
+    }
+    pub fn scan(
+        &mut self,
+        ctx: &DbContext,
+        prefix: &ObjectPath,
+        options: &ScanOptions,
+    ) -> Result<Vec<(ObjectPath, ValueWithMVCC)>, TxnError> {
+        range_scan::scan(ctx, self.txn, &self.view, prefix, options)
     }
     pub fn read_mvcc(
         &mut self,
         ctx: &DbContext,
         key: &ObjectPath,
-    ) -> Result<ValueWithMVCC, String> {
-        let ret =
-            mvcc_manager::read(ctx, key, self.txn).map_err(<ReadError as Into<String>>::into)?;
-        Ok(ret)
+    ) -> Result<ValueWithMVCC, TxnError> {
+        // Only serializable reads can run into a pending write, the others read around it.
+        wait_queue::with_wait(ctx, self.txn, key, || {
+            isolation::read(ctx, &self.view, key, self.txn)
+        })
     }
 
     pub fn write(
@@ -88,27 +141,229 @@ impl Transaction {
         ctx: &DbContext,
         key: &ObjectPath,
         value: TypedValue,
-    ) -> Result<(), String> {
-        use crate::rpc_handler::DatabaseInterface;
-        mvcc_manager::update(ctx, key, value.clone(), self.txn)?;
+    ) -> Result<(), TxnError> {
+        wait_queue::with_wait(ctx, self.txn, key, || {
+            mvcc_manager::update(ctx, key, value.clone(), self.txn, &self.view)
+        })?;
+        self.written_keys.insert(key.clone());
 
-        (&mut self.log).serve_write(self.txn, key, value);
+        self.log.log_write(key.clone(), value);
         Ok(())
     }
 
-    pub fn commit(&mut self, ctx: &DbContext) -> Result<(), String> {
-        let kv = &mut self.written_kv;
+    // Writes `new` only if the key currently holds exactly `expected` (or doesn't exist, if `expected` is None), see
+    // `TypedValue::is_identical`. Otherwise fails with `TxnError::ConditionFailed` and the current value.
+    pub fn compare_and_set(
+        &mut self,
+        ctx: &DbContext,
+        key: &ObjectPath,
+        expected: Option<&TypedValue>,
+        new: TypedValue,
+    ) -> Result<(), TxnError> {
+        self.read_modify_write(ctx, key, |current| {
+            let matches = match (current, expected) {
+                (Some(current), Some(expected)) => current.is_identical(expected),
+                (current, expected) => current.is_none() && expected.is_none(),
+            };
+            if matches {
+                Ok(new.clone())
+            } else {
+                Err(TxnError::ConditionFailed(current.cloned()))
+            }
+        })?;
+        Ok(())
+    }
+
+    pub fn put_if_absent(
+        &mut self,
+        ctx: &DbContext,
+        key: &ObjectPath,
+        value: TypedValue,
+    ) -> Result<(), TxnError> {
+        self.compare_and_set(ctx, key, None, value)
+    }
+
+    // Adds `delta` (an `Int` or a `Number`) to a number and returns the result. A key that doesn't exist counts as 0.
+    // Two `Int`s add up to an `Int`, failing if that overflows, anything else to a `Number`.
+    pub fn increment(
+        &mut self,
+        ctx: &DbContext,
+        key: &ObjectPath,
+        delta: TypedValue,
+    ) -> Result<TypedValue, TxnError> {
+        if delta.as_f64().is_none() {
+            return Err(format!("Can't increment by {}, it's not a number", delta).into());
+        }
+        self.read_modify_write(ctx, key, |current| match (current, &delta) {
+            (None, delta) => Ok(delta.clone()),
+            (Some(TypedValue::Int(i)), TypedValue::Int(d)) => i
+                .checked_add(*d)
+                .map(TypedValue::Int)
+                .ok_or_else(|| format!("Incrementing {} by {} overflows", i, d).into()),
+            (Some(value), delta) => match value.as_f64() {
+                Some(n) => Ok(TypedValue::Number(n + delta.as_f64().unwrap())),
+                None => Err(format!("Can't increment {}, it's not a number", value).into()),
+            },
+        })
+    }
+
+    // Reads and writes the key in one step, see `mvcc_manager::read_modify_write`, and logs the result.
+    fn read_modify_write(
+        &mut self,
+        ctx: &DbContext,
+        key: &ObjectPath,
+        mut f: impl FnMut(Option<&TypedValue>) -> Result<TypedValue, TxnError>,
+    ) -> Result<TypedValue, TxnError> {
         let txn = self.txn;
-        kv.drain(..).for_each(|a| {
-            // As optimization, clear the committed intents
-            ctx.db.get_mut(&a.0).map(|a| a.clear_committed_intent(txn));
-        });
-        let mut placeholder = WalTxn::new(self.txn.timestamp);
-        std::mem::swap(&mut placeholder, &mut self.log);
-        ctx.wallog.store(placeholder).unwrap();
+        let view = &self.view;
+        let value = wait_queue::with_wait(ctx, txn, key, || {
+            mvcc_manager::read_modify_write(ctx, key, txn, view, &mut f)
+        })?;
+        self.written_keys.insert(key.clone());
+
+        self.log.log_write(key.clone(), value.clone());
+        Ok(value)
+    }
+
+    // Deletes every key starting with `prefix` that exists at our timestamp, and returns them. The range is read
+    // first, so transactions older than us can't insert new keys under the prefix afterwards either.
+    pub fn delete_range(
+        &mut self,
+        ctx: &DbContext,
+        prefix: &ObjectPath,
+    ) -> Result<Vec<ObjectPath>, TxnError> {
+        let keys: Vec<ObjectPath> = self
+            .read_range_owned(ctx, prefix)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        for key in &keys {
+            self.write(ctx, key, TypedValue::Deleted)?;
+        }
+        Ok(keys)
+    }
+
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint(self.log.op_count())
+    }
 
+    // Undoes our writes since `savepoint`: each key gets back the value we had written to it before, or, if we
+    // hadn't, is released as if we aborted. Reads are kept, and so are the savepoints taken before this one.
+    // Those taken after it can't be used anymore.
+    pub fn rollback_to(&mut self, ctx: &DbContext, savepoint: Savepoint) -> Result<(), TxnError> {
+        if self.prepared {
+            return Err("Can't roll back a prepared transaction".into());
+        }
+        if savepoint.0 > self.log.op_count() {
+            return Err("Savepoint was already rolled back".into());
+        }
+        let undone: BTreeSet<ObjectPath> = self.log.truncate(savepoint.0).into_iter().collect();
+        for key in undone {
+            let earlier = self
+                .log
+                .writes()
+                .filter(|(written, _)| **written == key)
+                .last()
+                .map(|(_, value)| value.clone());
+            let txn = self.txn;
+            let released = earlier.is_none();
+            ctx.db
+                .with_value_mut(&key, |value| match earlier {
+                    Some(earlier) => value.restore_own_write(txn, earlier),
+                    None => value.resolve_intent_of(ctx, txn, WriteIntentStatus::Aborted),
+                })
+                .expect("Keys we wrote are only removed once we finished");
+            if released {
+                self.written_keys.remove(&key);
+                ctx.wait_queues.notify(&key);
+            }
+        }
+        Ok(())
+    }
+
+    // First phase of a two-phase commit. Logs our writes durably and promises to commit them, so they survive
+    // a restart and stay locked until `commit` or `abort` is called with the coordinator's decision.
+    pub fn prepare(&mut self, ctx: &DbContext) -> Result<(), TxnError> {
+        if self.prepared {
+            return Ok(());
+        }
+        let _persist_guard = ctx.commit_lock.read();
+        // Check before logging, so an aborted transaction never shows up as prepared in the WAL.
+        match ctx.transaction_map.get_by_ref(&self.txn) {
+            Some(data) if data.get_write_intent() == WriteIntentStatus::Pending => {}
+            _ => return Err(TxnError::Aborted),
+        }
+        // Keep our writes, to publish them to the change feed once we commit.
+        ctx.wallog
+            .store(self.log.clone().into_prepared())
+            .map_err(TxnError::Wal)?;
         ctx.transaction_map
-            .set_txn_status(self.txn, WriteIntentStatus::Committed)
+            .set_txn_status(self.txn, WriteIntentStatus::Prepared)
+            .map_err(|_| TxnError::Aborted)?;
+        self.prepared = true;
+        Ok(())
+    }
+
+    // Fails if the commit record can't be logged, and then we're aborted. Unless we're prepared: we promised the
+    // coordinator to commit, so we stay prepared until it sends its decision again.
+    pub fn commit(&mut self, ctx: &DbContext) -> Result<(), TxnError> {
+        let res = {
+            let _persist_guard = ctx.commit_lock.read();
+            // Check before logging, so recovery never replays an aborted transaction as committed.
+            let finished = ctx
+                .transaction_map
+                .get_by_ref(&self.txn)
+                .map_or(true, |data| data.is_finished());
+            if finished {
+                Err(TxnError::Aborted)
+            } else {
+                let publisher = ctx.subscribers.publisher(&ctx.wallog);
+                let changes = publisher.as_ref().map(|_| self.log.clone());
+                let record = if self.prepared {
+                    WalTxn::decision(self.txn, true)
+                } else {
+                    std::mem::replace(&mut self.log, WalTxn::for_txn(self.txn))
+                };
+                match ctx.wallog.store(record) {
+                    Err(err) if self.prepared => return Err(TxnError::Wal(err)),
+                    // Only we finish our transaction, and it wasn't finished yet, so neither status change fails.
+                    Err(err) => {
+                        let _ = ctx
+                            .transaction_map
+                            .set_txn_status(self.txn, WriteIntentStatus::Aborted);
+                        Err(TxnError::Wal(err))
+                    }
+                    Ok(position) => {
+                        let res = ctx
+                            .transaction_map
+                            .set_txn_status(self.txn, WriteIntentStatus::Committed)
+                            .map_err(|_| TxnError::Aborted);
+                        if let (Ok(()), Some(publisher), Some(changes)) = (&res, publisher, changes)
+                        {
+                            publisher.publish(changes, position);
+                        }
+                        // Together with the commit record, so a checkpoint has both or neither.
+                        if res.is_ok() && ctx.replicators.is_some() {
+                            ctx.decisions.decide(self.txn, true);
+                        }
+                        res
+                    }
+                }
+            }
+        };
+        let status = if res.is_ok() {
+            WriteIntentStatus::Committed
+        } else {
+            WriteIntentStatus::Aborted
+        };
+        self.resolve_intents(ctx, status);
+        self.view.release();
+        ctx.transaction_finished();
+        res
+    }
+
+    pub(crate) fn is_prepared(&self) -> bool {
+        self.prepared
     }
 }
 
@@ -116,7 +371,8 @@ use crate::timestamp::Timestamp;
 use crate::wal_watcher::{WalStorer, WalTxn};
 
 use crate::rpc_handler::DatabaseInterface;
-pub use crate::rwtransaction_wrapper::mvcc_manager::{ReadError, TypedValue};
+pub use crate::rwtransaction_wrapper::mvcc_manager::TypedValue;
+use crate::TxnError;
 use rand::Rng;
 
 impl<'a> ReplicatedTxn<'a> {
@@ -129,72 +385,226 @@ impl<'a> ReplicatedTxn<'a> {
             ctx,
             done: false,
         };
-        ctx.replicator().new_transaction(ret.get_txn());
+        if let Some(replicator) = &ctx.replicators {
+            replicator.new_transaction(ret.get_txn());
+        }
         ret
     }
+    // The replicas only get our accepted writes, so they don't need to know the isolation level.
+    pub fn new_with_isolation(ctx: &'a DbContext, level: IsolationLevel) -> Self {
+        let ret = Self {
+            main: Transaction::new_with_isolation(ctx, Timestamp::now(), level),
+            ctx,
+            done: false,
+        };
+        if let Some(replicator) = &ctx.replicators {
+            replicator.new_transaction(ret.get_txn());
+        }
+        ret
+    }
+    pub fn isolation(&self) -> IsolationLevel {
+        self.main.isolation()
+    }
+    // Once the transaction is aborted, e.g. because a replica rejected one of its writes, everything else fails.
+    fn check_active(&self) -> Result<(), TxnError> {
+        if self.done {
+            Err(TxnError::Aborted)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl<'a> ReplicatedTxn<'a> {
     pub fn read_range_owned(
         &mut self,
         key: &ObjectPath,
-    ) -> Result<Vec<(ObjectPath, ValueWithMVCC)>, String> {
+    ) -> Result<Vec<(ObjectPath, ValueWithMVCC)>, TxnError> {
+        self.check_active()?;
         let res1 = self.main.read_range_owned(self.ctx, key)?;
 
         // We don't need to send reads to the replicators for performance reasons.
         // let res = self.ctx.replicator().serve_range_read(*self.get_txn(), key)??;
         Ok(res1)
     }
-    pub fn read_mvcc(&mut self, key: &ObjectPath) -> Result<ValueWithMVCC, String> {
+    // One page of the keys under `prefix`. See `ScanOptions`.
+    pub fn scan(
+        &mut self,
+        prefix: &ObjectPath,
+        options: &ScanOptions,
+    ) -> Result<Vec<(ObjectPath, ValueWithMVCC)>, TxnError> {
+        self.check_active()?;
+        self.main.scan(self.ctx, prefix, options)
+    }
+    // All keys under `prefix` that `options` ask for, read a page at a time as the iterator advances.
+    pub fn scan_iter(&mut self, prefix: &ObjectPath, options: ScanOptions) -> RangeScan<'_> {
+        let scan = RangeScan::new(self.ctx, self.main.txn, &self.main.view, prefix, options);
+        match self.check_active() {
+            Ok(()) => scan,
+            Err(err) => scan.failing(err),
+        }
+    }
+    pub fn read_mvcc(&mut self, key: &ObjectPath) -> Result<ValueWithMVCC, TxnError> {
+        self.check_active()?;
         let myres = self.main.read_mvcc(self.ctx, key)?;
 
         // Every X times, do a replicator read, just to make sure everything matches.
-        if probabilistic_should_quorum_read() {
+        // Replicas read serializably, so they'd see different values at the other levels.
+        if self.ctx.replicators.is_some()
+            && self.isolation() == IsolationLevel::Serializable
+            && probabilistic_should_quorum_read()
+        {
             let res = self.ctx.replicator().serve_read(*self.get_txn(), key)??;
             if res.as_inner().1 != myres.as_inner().1 {
-                return Err("Replicator reads don't match".to_string());
+                return Err("Replicator reads don't match".into());
             }
         }
 
         Ok(myres)
     }
-    pub fn read(&mut self, key: &ObjectPath) -> Result<TypedValue, String> {
+    pub fn read(&mut self, key: &ObjectPath) -> Result<TypedValue, TxnError> {
         self.read_mvcc(key).map(|a| a.into_inner().1)
     }
-    pub fn write(&mut self, key: &ObjectPath, value: TypedValue) -> Result<(), String> {
-        let res1 = self
-            .main
-            .write(self.ctx, key, value.clone())
-            .map_err(|a| format!("main error {}", a));
-        let res = self
-            .ctx
-            .replicator()
-            .serve_write(*self.get_txn(), key, value)?
-            .map_err(|a| format!("replicator error {}", a));
-
-        if res.is_ok() != res1.is_ok() {
-            debug!(
-                "nonmatching write results: {:?} {:?} {}",
-                res,
-                res1,
-                self.get_txn().id
+    pub fn write(&mut self, key: &ObjectPath, value: TypedValue) -> Result<(), TxnError> {
+        self.check_active()?;
+        self.main.write(self.ctx, key, value.clone())?;
+        self.replicate_write(key, value)
+    }
+    // See `Transaction::compare_and_set`. Only the value we ended up writing is sent to the replicas.
+    pub fn compare_and_set(
+        &mut self,
+        key: &ObjectPath,
+        expected: Option<&TypedValue>,
+        new: TypedValue,
+    ) -> Result<(), TxnError> {
+        self.check_active()?;
+        self.main
+            .compare_and_set(self.ctx, key, expected, new.clone())?;
+        self.replicate_write(key, new)
+    }
+    pub fn put_if_absent(&mut self, key: &ObjectPath, value: TypedValue) -> Result<(), TxnError> {
+        self.compare_and_set(key, None, value)
+    }
+    pub fn increment(&mut self, key: &ObjectPath, delta: TypedValue) -> Result<TypedValue, TxnError> {
+        self.check_active()?;
+        let value = self.main.increment(self.ctx, key, delta)?;
+        self.replicate_write(key, value.clone())?;
+        Ok(value)
+    }
+    fn replicate_write(&mut self, key: &ObjectPath, value: TypedValue) -> Result<(), TxnError> {
+        let replicator = match &self.ctx.replicators {
+            Some(replicator) => replicator,
+            None => return Ok(()),
+        };
+        // Replicas only get the writes we accepted. Reads aren't sent to them, so they couldn't tell
+        // whether a write conflicts with one.
+        let res = replicator.serve_write(*self.get_txn(), key, value)?;
+
+        if let Err(err) = res {
+            // The replica refused a write we accepted, so it doesn't hold the same data as we do.
+            log::error!(
+                "Replica rejected write of {} in transaction {}: {}",
+                key.as_str(),
+                self.get_txn().id,
+                err
             );
-            // Must abort writes, todo!
+            return Err(self.diverged(err));
+        }
+        Ok(())
+    }
+    // Flags the replica that rejected what we did and aborts. When we fan out to several replicas, the error names
+    // the one that did already (see `LocalReplicationHandler`). Otherwise the replicator is the only replica, 0.
+    fn diverged(&mut self, err: TxnError) -> TxnError {
+        let replica = match err {
+            TxnError::ReplicaDiverged(replica) => replica,
+            _ => "0".to_string(),
+        };
+        self.ctx.flag_replica_diverged(&replica);
+        self.abort();
+        TxnError::ReplicaDiverged(replica)
+    }
+    // See `Transaction::delete_range`. The replicas get the deletes as regular writes.
+    pub fn delete_range(&mut self, prefix: &ObjectPath) -> Result<Vec<ObjectPath>, TxnError> {
+        let keys: Vec<ObjectPath> = self
+            .read_range_owned(prefix)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        for key in &keys {
+            self.write(key, TypedValue::Deleted)?;
         }
-        res.and(res1)
+        Ok(keys)
     }
-    pub fn commit(mut self) -> Result<(), String> {
-        // todo fix atomic commit section with actual two-phase commit.
+    pub fn savepoint(&self) -> Savepoint {
+        self.main.savepoint()
+    }
+    // See `Transaction::rollback_to`. If a replica can't roll back, the whole transaction is aborted.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), TxnError> {
+        self.check_active()?;
+        self.main.rollback_to(self.ctx, savepoint)?;
+        let replicator = match &self.ctx.replicators {
+            Some(replicator) => replicator,
+            None => return Ok(()),
+        };
+        match replicator.rollback_to(*self.get_txn(), savepoint).0 {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => {
+                log::error!(
+                    "Replica couldn't roll back transaction {}: {}",
+                    self.get_txn().id,
+                    err
+                );
+                Err(self.diverged(err))
+            }
+            // The replica may still have the writes we undid.
+            Err(err) => {
+                self.abort();
+                Err(err.into())
+            }
+        }
+    }
+    // Commits on the replicas and locally with two-phase commit, so the transaction commits everywhere or nowhere.
+    pub fn commit(mut self) -> Result<(), TxnError> {
+        self.check_active()?;
         let t = *self.get_txn();
-        self.ctx.replicator().commit(t)?;
-        self.main.commit(self.ctx)?;
+        if self.ctx.replicators.is_none() {
+            self.done = true;
+            return self.main.commit(self.ctx);
+        }
+
+        // From here on, the replicas are told the outcome even if we crash. See `coordinator::Decisions`.
+        if let Err(err) = coordinator::start(self.ctx, t) {
+            self.abort();
+            return Err(err);
+        }
+
+        // Phase 1: every replica logs our writes durably and promises it can commit them.
+        if let Err(err) = self.ctx.replicator().prepare(t).into_result() {
+            self.abort();
+            return Err(err);
+        }
+
+        // Phase 2: the commit record in our own log is the decision. If we never get to write it, the transaction
+        // is aborted. Replicas that don't acknowledge it keep the transaction prepared until `resend_decisions`.
         self.done = true;
-        Ok(())
+        let res = self.main.commit(self.ctx);
+        // Someone else may have aborted us locally, then the replicas must abort too.
+        let _ = coordinator::deliver(self.ctx, t, res.is_ok());
+        res
     }
+    // Aborts here and on the replicas. Does nothing if the transaction was already aborted.
     pub fn abort(&mut self) {
-        self.ctx.replicator().abort(*self.get_txn());
-        self.main.abort(self.ctx);
+        if self.done {
+            return;
+        }
         self.done = true;
+        // Only fails for transactions that are committed or prepared, and ours are neither.
+        if let Err(err) = self.main.abort(self.ctx) {
+            log::error!("Couldn't abort transaction {}: {}", self.get_txn().id, err);
+        }
+        if self.ctx.replicators.is_some() {
+            let _ = coordinator::deliver(self.ctx, *self.get_txn(), false);
+        }
     }
 
     pub fn new(ctx: &'a DbContext) -> Self {
@@ -213,9 +623,9 @@ pub mod auto_commit {
     use crate::object_path::ObjectPath;
     use crate::rwtransaction_wrapper::mvcc_manager::TypedValue;
     use crate::rwtransaction_wrapper::ValueWithMVCC;
-    use crate::{DbContext, ReplicatedTxn};
+    use crate::{DbContext, ReplicatedTxn, TxnError};
 
-    pub fn read(db: &DbContext, key: &ObjectPath) -> Result<ValueWithMVCC, String> {
+    pub fn read(db: &DbContext, key: &ObjectPath) -> Result<ValueWithMVCC, TxnError> {
         let mut txn = ReplicatedTxn::new(db);
         let ret = txn.read_mvcc(key);
         txn.commit().unwrap();
@@ -234,11 +644,13 @@ impl Drop for ReplicatedTxn<'_> {
         if !self.done {
             self.abort()
         } else {
-            let status = self.ctx.transaction_map.get_by_ref(&self.main.txn);
-            debug_assert_matches!(
-                status.unwrap().0,
-                WriteIntentStatus::Committed | WriteIntentStatus::Aborted
-            );
+            // The record may already have been reclaimed, which only happens to finished transactions.
+            if let Some(status) = self.ctx.transaction_map.get_by_ref(&self.main.txn) {
+                debug_assert_matches!(
+                    status.0,
+                    WriteIntentStatus::Committed | WriteIntentStatus::Aborted
+                );
+            }
         }
     }
 }
@@ -252,38 +664,34 @@ mod tests {
     use crate::db_context::{create_empty_context, create_replicated_context};
 
     #[test]
-    #[should_panic]
     fn cant_commit_abort_twice() {
         let db = db!();
         let mut t = ReplicatedTxn::new(&db);
         t.abort();
-        t.commit();
+        assert_eq!(t.commit(), Err(TxnError::Aborted));
     }
 
     #[test]
-    #[ignore]
     fn regression_phantom() {
         /*
         Check for phantom bug. Previously when we insert a key, we check that the keys to the left/right of that new key has a lower read_timestamp.
         This is called next-key locking. This prevents phantoms from occuring. For example, doing a range read on /test/a at Timestamp `a` should lock
         that whole range "/test/a". Thus, a transaction with an older timestamp cannot go in and write to that range anymore.
 
-        However, our current implementation of next key locking is primitive. It does not take into account that next/previous keys could be pending instead of
-        committed value. Therefore, I can sneak a phantom in by doing the following (which should be illegal): */
+        However, next key locking didn't take into account that next/previous keys could be pending instead of
+        committed values, which let the following phantom in. Range reads are now recorded per key span instead. */
         let db = db!("/test/a" = "a", "/other/c" = "c");
         let mut t1 = ReplicatedTxn::new(&db);
         let mut t2 = ReplicatedTxn::new(&db);
         let mut t3 = ReplicatedTxn::new(&db);
 
         dbg!(t3.read_range_owned(&"/test/a/".into()).unwrap());
-        dbg!(t1.write(&"/test/a/1".into(), "a".into()));
+        assert_matches!(t1.write(&"/test/a/1".into(), "a".into()), Err(TxnError::PhantomDetected));
 
-        // Not fixed yet, there should be error here.
-        // When it locks the next key, it'd lock the uncommitted value /test/a/1 instead of the actual /test/a value.
-        // This is a bug.
-        assert_matches!(t2.write(&"/test/a/2".into(), "a".into()), Err(..));
+        // When it locked the next key, it'd lock the uncommitted value /test/a/1 instead of the actual /test/a value.
+        assert_matches!(t2.write(&"/test/a/2".into(), "a".into()), Err(TxnError::PhantomDetected));
         t1.abort();
-        t2.commit();
+        t2.commit().unwrap();
 
         let mut t4 = ReplicatedTxn::new(&db);
         println!("{:?}", t4.read_range_owned(&"/".into()));
@@ -319,6 +727,216 @@ mod tests {
         assert_matches!(auto_commit::read(&db, &"k".into()), Err(..));
     }
 
+    #[test]
+    fn delete_range_removes_subtree() {
+        let db = db!(
+            "/test/a" = "1",
+            "/test/a/b" = "2",
+            "/test/c" = "3",
+            "/other/a" = "4"
+        );
+        let mut old = ReplicatedTxn::new(&db);
+        let mut t = ReplicatedTxn::new(&db);
+        let before = t.get_txn().timestamp;
+        assert_eq!(
+            t.delete_range(&"/test/".into()),
+            Ok(vec!["/test/a".into(), "/test/a/b".into(), "/test/c".into()])
+        );
+        assert_eq!(t.read(&"/test/a".into()), Err(TxnError::NotFound));
+        t.commit().unwrap();
+
+        // An older transaction can neither insert under the prefix nor see the deletes.
+        assert_eq!(
+            old.write(&"/test/b".into(), "5".into()),
+            Err(TxnError::PhantomDetected)
+        );
+        old.abort();
+        let mut r = ReplicatedTxn::new(&db);
+        assert!(r.read_range_owned(&"/test/".into()).unwrap().is_empty());
+        assert_eq!(r.read(&"/other/a".into()), Ok("4".into()));
+        r.commit().unwrap();
+
+        // Writing a deleted key again adds a version after the tombstone instead of replacing the key.
+        let mut w = ReplicatedTxn::new(&db);
+        w.write(&"/test/a".into(), "6".into()).unwrap();
+        w.commit().unwrap();
+        let values: Vec<TypedValue> = db
+            .history(&"/test/a".into())
+            .into_iter()
+            .map(|v| v.value)
+            .collect();
+        assert_eq!(values, vec!["1".into(), TypedValue::Deleted, "6".into()]);
+        assert_eq!(
+            db.read_as_of(&"/test/a/b".into(), Timestamp(before.0 - 1)),
+            Ok("2".into())
+        );
+    }
+
+    #[test]
+    fn conditional_writes() {
+        let db = db!("/test/a" = "1");
+        let mut t = ReplicatedTxn::new(&db);
+        let a = "/test/a".into();
+        assert_eq!(t.compare_and_set(&a, Some(&"1".into()), "2".into()), Ok(()));
+        assert_eq!(
+            t.compare_and_set(&a, Some(&"1".into()), "3".into()),
+            Err(TxnError::ConditionFailed(Some("2".into())))
+        );
+        assert_eq!(
+            t.compare_and_set(&a, Some(&5.0.into()), "3".into()),
+            Err(TxnError::ConditionFailed(Some("2".into())))
+        );
+        assert_eq!(
+            t.put_if_absent(&a, "3".into()),
+            Err(TxnError::ConditionFailed(Some("2".into())))
+        );
+        assert_eq!(t.put_if_absent(&"/test/b".into(), "3".into()), Ok(()));
+        assert_eq!(t.increment(&"/test/count".into(), 2.0.into()), Ok(2.0.into()));
+        assert_eq!(t.increment(&"/test/count".into(), 1.5.into()), Ok(3.5.into()));
+        assert_matches!(t.increment(&a, 1.0.into()), Err(TxnError::Other(..)));
+        t.commit().unwrap();
+
+        let mut t = ReplicatedTxn::new(&db);
+        assert_eq!(t.read(&a), Ok("2".into()));
+        assert_eq!(t.read(&"/test/b".into()), Ok("3".into()));
+        assert_eq!(t.read(&"/test/count".into()), Ok(3.5.into()));
+        // A deleted key is absent again.
+        t.write(&"/test/b".into(), TypedValue::Deleted).unwrap();
+        assert_eq!(t.put_if_absent(&"/test/b".into(), "4".into()), Ok(()));
+        t.commit().unwrap();
+    }
+
+    #[test]
+    fn conditions_compare_types_exactly() {
+        let db = db!("/test/a" = "1");
+        let mut t = ReplicatedTxn::new(&db);
+        let a = "/test/a".into();
+        t.write(&a, TypedValue::Int(1)).unwrap();
+        assert_eq!(
+            t.compare_and_set(&a, Some(&1.0.into()), "2".into()),
+            Err(TxnError::ConditionFailed(Some(TypedValue::Int(1))))
+        );
+        assert_eq!(t.compare_and_set(&a, Some(&TypedValue::Int(1)), 0.0.into()), Ok(()));
+        assert_eq!(
+            t.compare_and_set(&a, Some(&(-0.0).into()), "2".into()),
+            Err(TxnError::ConditionFailed(Some(0.0.into())))
+        );
+        t.write(&a, f64::NAN.into()).unwrap();
+        assert_eq!(t.compare_and_set(&a, Some(&f64::NAN.into()), "2".into()), Ok(()));
+        t.commit().unwrap();
+    }
+
+    #[test]
+    fn increments_ints() {
+        let db = db!("/test/a" = "1");
+        let mut t = ReplicatedTxn::new(&db);
+        let count = "/test/count".into();
+        assert_eq!(t.increment(&count, 2i64.into()), Ok(TypedValue::Int(2)));
+        assert!(t.increment(&count, (-5i64).into()).unwrap().is_identical(&TypedValue::Int(-3)));
+        assert!(t.increment(&count, 0.5.into()).unwrap().is_identical(&(-2.5).into()));
+        t.write(&count, i64::MAX.into()).unwrap();
+        assert_matches!(t.increment(&count, 1i64.into()), Err(TxnError::Other(..)));
+        assert_matches!(t.increment(&count, "1".into()), Err(TxnError::Other(..)));
+        t.commit().unwrap();
+
+        let mut t = ReplicatedTxn::new(&db);
+        assert!(t.read(&count).unwrap().is_identical(&i64::MAX.into()));
+        t.commit().unwrap();
+    }
+
+    #[test]
+    fn failed_conditions_still_read() {
+        let db = db!("/test/a" = "1");
+        let mut old = ReplicatedTxn::new(&db);
+        let mut new = ReplicatedTxn::new(&db);
+        assert_matches!(new.put_if_absent(&"/test/a".into(), "2".into()), Err(..));
+        assert_matches!(
+            new.compare_and_set(&"/test/b".into(), Some(&"1".into()), "2".into()),
+            Err(..)
+        );
+
+        // What `new` saw can't change anymore, or it might have decided differently.
+        assert_eq!(
+            old.write(&"/test/a".into(), "3".into()),
+            Err(TxnError::ReadTimestampConflict)
+        );
+        assert_eq!(
+            old.write(&"/test/b".into(), "3".into()),
+            Err(TxnError::PhantomDetected)
+        );
+        new.commit().unwrap();
+        old.abort();
+    }
+
+    #[test]
+    fn rollback_to_savepoint() {
+        let db = db!("/test/a" = "1");
+        let mut t = ReplicatedTxn::new(&db);
+        t.write(&"/test/a".into(), "2".into()).unwrap();
+        let first = t.savepoint();
+        t.write(&"/test/a".into(), "3".into()).unwrap();
+        t.write(&"/test/b".into(), "1".into()).unwrap();
+        let second = t.savepoint();
+        t.write(&"/test/c".into(), "1".into()).unwrap();
+
+        t.rollback_to(second).unwrap();
+        assert_eq!(t.read(&"/test/c".into()), Err(TxnError::NotFound));
+        assert_eq!(t.read(&"/test/b".into()), Ok("1".into()));
+        t.rollback_to(first).unwrap();
+        assert_eq!(t.read(&"/test/a".into()), Ok("2".into()));
+        assert_eq!(t.read(&"/test/b".into()), Err(TxnError::NotFound));
+        assert_matches!(t.rollback_to(second), Err(TxnError::Other(..)));
+
+        // Keys we don't hold anymore are free for others.
+        let mut other = ReplicatedTxn::new(&db);
+        other.write(&"/test/b".into(), "2".into()).unwrap();
+        assert_matches!(
+            other.write(&"/test/a".into(), "2".into()),
+            Err(TxnError::WriteConflict(..))
+        );
+        other.commit().unwrap();
+        t.commit().unwrap();
+
+        let values: Vec<TypedValue> = db
+            .history(&"/test/a".into())
+            .into_iter()
+            .map(|v| v.value)
+            .collect();
+        assert_eq!(values, vec!["1".into(), "2".into()]);
+        assert_eq!(auto_commit::read(&db, &"/test/b".into()).unwrap().get_val(), &"2".into());
+    }
+
+    #[test]
+    fn rollback_to_savepoint_on_wal() {
+        let db = create_empty_context();
+        let mut t = Transaction::new_with_time(&db, Timestamp::now());
+        t.write(&db, &"/test/a".into(), "1".into()).unwrap();
+        let savepoint = t.savepoint();
+        t.write(&db, &"/test/a".into(), "2".into()).unwrap();
+        t.write(&db, &"/test/b".into(), "2".into()).unwrap();
+        t.rollback_to(&db, savepoint).unwrap();
+        t.commit(&db).unwrap();
+
+        let logged: Vec<(ObjectPath, TypedValue)> = db
+            .wallog
+            .load()
+            .unwrap()
+            .iter()
+            .flat_map(|txn| {
+                txn.writes()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(logged, vec![("/test/a".into(), "1".into())]);
+
+        t = Transaction::new_with_time(&db, Timestamp::now());
+        t.write(&db, &"/test/a".into(), "3".into()).unwrap();
+        t.prepare(&db).unwrap();
+        assert_matches!(t.rollback_to(&db, Savepoint(0)), Err(..));
+        t.commit(&db).unwrap();
+    }
+
     #[test]
     fn check_phantom3() {
         // regression test
@@ -335,8 +953,8 @@ mod tests {
         assert_matches!(txn1.write(&"/test/3".into(), "3".into()), Err(..));
         assert_matches!(txn2.write(&"/test/4".into(), "3".into()), Ok(..));
 
-        txn1.commit();
-        txn2.commit();
+        txn1.commit().unwrap();
+        txn2.commit().unwrap();
         println!("{}", db.db.printdb());
     }
 
@@ -345,8 +963,8 @@ mod tests {
         let ctx = create_replicated_context();
         let mut txn = ReplicatedTxn::new(&ctx);
         let key = "test".into();
-        txn.write(&key, "fdsvc".into());
-        txn.read(&key);
+        txn.write(&key, "fdsvc".into()).unwrap();
+        txn.read(&key).unwrap();
         txn.commit().unwrap();
     }
 
@@ -363,19 +981,19 @@ mod tests {
         let mut txn2 = ReplicatedTxn::new(ctx);
         let mut txn3 = ReplicatedTxn::new(ctx);
 
-        txn0.write(&a0, "key0value".into());
+        txn0.write(&a0, "key0value".into()).unwrap();
         txn0.commit().unwrap();
 
         txn1.write(&a1, TypedValue::from("key1value")).unwrap();
         txn2.write(&a2, TypedValue::from("key2value")).unwrap();
         txn3.write(&a3, TypedValue::from("key3value")).unwrap();
 
-        assert_eq!(txn1.read(&a0).unwrap().as_str(), "key0value");
+        assert_eq!(txn1.read(&a0).unwrap().as_str(), Some("key0value"));
         txn1.commit().unwrap();
-        assert_eq!(txn2.read(&a0).unwrap().as_str(), "key0value");
-        assert_eq!(txn2.read(&a1).unwrap().as_str(), "key1value");
+        assert_eq!(txn2.read(&a0).unwrap().as_str(), Some("key0value"));
+        assert_eq!(txn2.read(&a1).unwrap().as_str(), Some("key1value"));
         assert_matches!(txn2.read(&a3), Err(..));
-        assert_matches!(txn3.read(&a1).unwrap().as_str(), "key1value");
+        assert_matches!(txn3.read(&a1).unwrap().as_str(), Some("key1value"));
         assert_matches!(txn3.read(&a2), Err(..));
 
         txn2.commit().unwrap();
@@ -401,8 +1019,8 @@ mod tests {
         txn1.write(&b, TypedValue::from("key1value")).unwrap();
         assert_matches!(txn1.read(&a), Err(_err));
 
-        txn0.commit();
-        txn1.commit();
+        txn0.commit().unwrap();
+        txn1.commit().unwrap();
     }
 
     #[test]
@@ -412,7 +1030,7 @@ mod tests {
         let db = db!("k" = "v");
         let mut t1 = ReplicatedTxn::new(&db);
         let mut t2 = ReplicatedTxn::new(&db);
-        t1.write(&"k".into(), "v2".into());
+        t1.write(&"k".into(), "v2".into()).unwrap();
         match t2.write(&"k".into(), "v3".into()) {
             Err(x) => println!("(good) expected error: {}", x),
             _ => panic!("should've errored"),
@@ -425,7 +1043,366 @@ mod tests {
             Ok(x) => println!("(good) expected value: {:?}", x),
             _ => panic!("should've been ok"),
         }
-        t1.commit();
-        t2.commit();
+        t1.commit().unwrap();
+        t2.commit().unwrap();
+    }
+
+    fn intent_on(db: &DbContext, key: &str) -> Option<WriteIntent> {
+        let mut lock = db.db.get_raw_with_lock(&key.into());
+        lock.value().unwrap().as_inner().0.get_write_intents()
+    }
+
+    #[test]
+    fn commit_clears_intents() {
+        let db = create_empty_context();
+        let mut t = Transaction::new_with_time(&db, Timestamp::now());
+        t.write(&db, &"/test/a".into(), "1".into()).unwrap();
+        t.commit(&db).unwrap();
+
+        let mut t = Transaction::new_with_time(&db, Timestamp::now());
+        t.write(&db, &"/test/a".into(), "2".into()).unwrap();
+        t.write(&db, &"/test/b".into(), TypedValue::Deleted).unwrap();
+        assert!(intent_on(&db, "/test/a").is_some());
+        t.commit(&db).unwrap();
+
+        assert_eq!(intent_on(&db, "/test/a"), None);
+        assert_eq!(intent_on(&db, "/test/b"), None);
+    }
+
+    #[test]
+    fn abort_rolls_back_writes() {
+        let db = create_empty_context();
+        let mut t = Transaction::new_with_time(&db, Timestamp::now());
+        t.write(&db, &"/test/a".into(), "1".into()).unwrap();
+        t.commit(&db).unwrap();
+
+        let mut t = Transaction::new_with_time(&db, Timestamp::now());
+        t.write(&db, &"/test/a".into(), "2".into()).unwrap();
+        t.write(&db, &"/test/b".into(), "3".into()).unwrap();
+        t.abort(&db).unwrap();
+
+        assert_eq!(intent_on(&db, "/test/a"), None);
+        assert_eq!(intent_on(&db, "/test/b"), None);
+        assert_eq!(
+            db.db.get_mut_with_lock(&"/test/a".into()).value().unwrap().get_val(),
+            &"1".into()
+        );
+        assert!(db.db.get_mut_with_lock(&"/test/b".into()).value().is_none());
+    }
+
+    #[test]
+    fn wal_failure_aborts() {
+        let db = create_empty_context();
+        let status = |t: &Transaction| db.transaction_map.get_by_ref(&t.txn).unwrap().0;
+        let mut t = Transaction::new_with_time(&db, Timestamp::now());
+        t.write(&db, &"/test/a".into(), "1".into()).unwrap();
+        let mut prepared = Transaction::new_with_time(&db, Timestamp::now());
+        prepared.write(&db, &"/test/b".into(), "2".into()).unwrap();
+        prepared.prepare(&db).unwrap();
+
+        db.wallog.set_frozen(true);
+        assert_matches!(t.commit(&db), Err(TxnError::Wal(..)));
+        assert_eq!(status(&t), WriteIntentStatus::Aborted);
+        assert!(db.db.get_mut_with_lock(&"/test/a".into()).value().is_none());
+        // The coordinator decided, so it stays prepared until the decision is logged.
+        assert_matches!(prepared.commit(&db), Err(TxnError::Wal(..)));
+        assert_matches!(prepared.abort_decided(&db), Err(TxnError::Wal(..)));
+        assert_eq!(status(&prepared), WriteIntentStatus::Prepared);
+
+        db.wallog.set_frozen(false);
+        prepared.commit(&db).unwrap();
+        assert_eq!(
+            db.db.get_mut_with_lock(&"/test/b".into()).value().unwrap().get_val(),
+            &"2".into()
+        );
+    }
+
+    #[test]
+    fn only_the_coordinator_aborts_prepared_transactions() {
+        let db = create_empty_context();
+        let status = |t: &Transaction| db.transaction_map.get_by_ref(&t.txn).unwrap().0;
+
+        let mut t = Transaction::new_with_time(&db, Timestamp::now());
+        t.write(&db, &"/test/a".into(), "1".into()).unwrap();
+        t.commit(&db).unwrap();
+        assert_eq!(t.abort(&db), Err(TxnError::Committed));
+        assert_eq!(t.abort_decided(&db), Err(TxnError::Committed));
+        assert_eq!(status(&t), WriteIntentStatus::Committed);
+
+        let mut t = Transaction::new_with_time(&db, Timestamp::now());
+        t.write(&db, &"/test/a".into(), "2".into()).unwrap();
+        t.prepare(&db).unwrap();
+        assert_eq!(t.abort(&db), Err(TxnError::Prepared));
+        assert_eq!(status(&t), WriteIntentStatus::Prepared);
+        assert!(db
+            .transaction_map
+            .set_txn_status(t.txn, WriteIntentStatus::Aborted)
+            .is_err());
+
+        t.abort_decided(&db).unwrap();
+        assert_eq!(status(&t), WriteIntentStatus::Aborted);
+        assert_eq!(
+            db.db.get_mut_with_lock(&"/test/a".into()).value().unwrap().get_val(),
+            &"1".into()
+        );
+    }
+
+    mod replica_failures {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        use crate::db_context::create_empty_context;
+        use crate::local_replication_handler::LocalReplicationHandler;
+        use crate::object_path::ObjectPath;
+        use crate::range_scan::ScanOptions;
+        use crate::replicated_slave::SelfContainedDb;
+        use crate::rpc_handler::{DatabaseInterface, NetworkError, NetworkResult};
+        use crate::rwtransaction_wrapper::{LockDataRef, Savepoint, Transaction, ValueWithMVCC};
+        use crate::timestamp::Timestamp;
+        use crate::{DbContext, ReplicatedTxn, TxnError, TypedValue};
+
+        // A replica that can be told to become unreachable right before prepare or commit, or to reject writes.
+        #[derive(Default)]
+        struct Flaky {
+            db: SelfContainedDb,
+            fail_prepare: AtomicBool,
+            fail_commit: AtomicBool,
+            reject_writes: AtomicBool,
+        }
+
+        impl DatabaseInterface for Arc<Flaky> {
+            fn new_transaction(&self, txn: &LockDataRef) -> NetworkResult<(), TxnError> {
+                self.db.new_transaction(txn)
+            }
+            fn serve_read(
+                &self,
+                txn: LockDataRef,
+                key: &ObjectPath,
+            ) -> NetworkResult<ValueWithMVCC, TxnError> {
+                self.db.serve_read(txn, key)
+            }
+            fn serve_range_read(
+                &self,
+                txn: LockDataRef,
+                key: &ObjectPath,
+            ) -> NetworkResult<Vec<(ObjectPath, ValueWithMVCC)>, TxnError> {
+                self.db.serve_range_read(txn, key)
+            }
+            fn serve_write(
+                &self,
+                txn: LockDataRef,
+                key: &ObjectPath,
+                value: TypedValue,
+            ) -> NetworkResult<(), TxnError> {
+                if self.reject_writes.load(Ordering::SeqCst) {
+                    return NetworkResult::from(Err(TxnError::ReadTimestampConflict));
+                }
+                self.db.serve_write(txn, key, value)
+            }
+            fn prepare(&self, txn: LockDataRef) -> NetworkResult<(), TxnError> {
+                if self.fail_prepare.load(Ordering::SeqCst) {
+                    return NetworkResult(Err(NetworkError::new("replica is down")));
+                }
+                self.db.prepare(txn)
+            }
+            fn rollback_to(
+                &self,
+                txn: LockDataRef,
+                savepoint: Savepoint,
+            ) -> NetworkResult<(), TxnError> {
+                self.db.rollback_to(txn, savepoint)
+            }
+            fn commit(&self, txn: LockDataRef) -> NetworkResult<(), TxnError> {
+                if self.fail_commit.load(Ordering::SeqCst) {
+                    return NetworkResult(Err(NetworkError::new("replica is down")));
+                }
+                self.db.commit(txn)
+            }
+            fn abort(&self, txn: LockDataRef) -> NetworkResult<(), TxnError> {
+                self.db.abort(txn)
+            }
+        }
+
+        fn setup() -> (DbContext, Arc<Flaky>) {
+            let replica = Arc::new(Flaky::default());
+            let mut ctx = create_empty_context();
+            ctx.replicators = Some(Box::new(replica.clone()));
+            (ctx, replica)
+        }
+
+        fn read(ctx: &DbContext, key: &str) -> Result<TypedValue, TxnError> {
+            let mut txn = Transaction::new_with_time(ctx, Timestamp::now());
+            txn.read_mvcc(ctx, &key.into()).map(|a| a.into_inner().1)
+        }
+
+        #[test]
+        fn failed_prepare_aborts_everywhere() {
+            let (ctx, replica) = setup();
+            replica.fail_prepare.store(true, Ordering::SeqCst);
+
+            let mut txn = ReplicatedTxn::new(&ctx);
+            txn.write(&"/test/a".into(), "1".into()).unwrap();
+            assert_matches!(txn.commit(), Err(TxnError::Network(..)));
+
+            assert_eq!(read(&ctx, "/test/a"), Err(TxnError::NotFound));
+            assert_eq!(read(&replica.db.db, "/test/a"), Err(TxnError::NotFound));
+        }
+
+        #[test]
+        fn prepared_replica_waits_for_the_decision() {
+            let (ctx, replica) = setup();
+            replica.fail_commit.store(true, Ordering::SeqCst);
+
+            let mut txn = ReplicatedTxn::new(&ctx);
+            txn.write(&"/test/a".into(), "1".into()).unwrap();
+            let t = *txn.get_txn();
+            // We logged the commit, so the transaction is committed even though the replica didn't hear about it.
+            txn.commit().unwrap();
+            assert_eq!(read(&ctx, "/test/a"), Ok("1".into()));
+            assert_eq!(
+                read(&replica.db.db, "/test/a"),
+                Err(TxnError::WriteConflict(t))
+            );
+
+            assert_eq!(ctx.undelivered_decisions(), vec![t]);
+
+            replica.fail_commit.store(false, Ordering::SeqCst);
+            ctx.resend_decisions().unwrap();
+            assert_eq!(read(&replica.db.db, "/test/a"), Ok("1".into()));
+            assert!(ctx.undelivered_decisions().is_empty());
+        }
+
+        #[test]
+        fn one_replica_missing_the_decision_gets_it_again() {
+            let replicas = [Arc::new(Flaky::default()), Arc::new(Flaky::default())];
+            replicas[1].fail_commit.store(true, Ordering::SeqCst);
+            let next = AtomicUsize::new(0);
+            let handler = LocalReplicationHandler::new(2, || {
+                replicas[next.fetch_add(1, Ordering::SeqCst)].clone()
+            });
+            let mut ctx = create_empty_context();
+            ctx.replicators = Some(Box::new(handler));
+
+            let mut txn = ReplicatedTxn::new(&ctx);
+            txn.write(&"/test/a".into(), "1".into()).unwrap();
+            let t = *txn.get_txn();
+            txn.commit().unwrap();
+            assert_eq!(read(&replicas[0].db.db, "/test/a"), Ok("1".into()));
+            assert_eq!(
+                read(&replicas[1].db.db, "/test/a"),
+                Err(TxnError::WriteConflict(t))
+            );
+            assert_eq!(ctx.undelivered_decisions(), vec![t]);
+
+            replicas[1].fail_commit.store(false, Ordering::SeqCst);
+            ctx.resend_decisions().unwrap();
+            for replica in &replicas {
+                assert_eq!(read(&replica.db.db, "/test/a"), Ok("1".into()));
+            }
+            assert!(ctx.undelivered_decisions().is_empty());
+        }
+
+        #[test]
+        fn recovery_resends_the_decision() {
+            for checkpoint in [false, true] {
+                let dir = std::env::temp_dir()
+                    .join(format!("metastore-coordinator-{}", rand::random::<u64>()));
+                let replica = Arc::new(Flaky::default());
+                let mut ctx = DbContext::open(&dir).unwrap();
+                ctx.replicators = Some(Box::new(replica.clone()));
+                replica.fail_commit.store(true, Ordering::SeqCst);
+
+                let mut txn = ReplicatedTxn::new(&ctx);
+                txn.write(&"/test/a".into(), "1".into()).unwrap();
+                let t = *txn.get_txn();
+                txn.commit().unwrap();
+                if checkpoint {
+                    ctx.checkpoint().unwrap();
+                }
+                // The coordinator crashes before the replica is reachable again.
+                std::mem::forget(ctx);
+                replica.fail_commit.store(false, Ordering::SeqCst);
+                assert_eq!(
+                    read(&replica.db.db, "/test/a"),
+                    Err(TxnError::WriteConflict(t))
+                );
+
+                let mut ctx = DbContext::open(&dir).unwrap();
+                assert_eq!(ctx.undelivered_decisions(), vec![t]);
+                ctx.replicators = Some(Box::new(replica.clone()));
+                ctx.resend_decisions().unwrap();
+                assert_eq!(read(&replica.db.db, "/test/a"), Ok("1".into()));
+                std::mem::drop(ctx);
+
+                // Once acknowledged, it's not sent again.
+                let ctx = DbContext::open(&dir).unwrap();
+                assert!(ctx.undelivered_decisions().is_empty());
+                assert_eq!(read(&ctx, "/test/a"), Ok("1".into()));
+
+                std::mem::drop(ctx);
+                std::fs::remove_dir_all(dir).unwrap();
+            }
+        }
+
+        #[test]
+        fn diverging_write_aborts_everywhere() {
+            let (ctx, replica) = setup();
+            let mut txn = ReplicatedTxn::new(&ctx);
+            txn.write(&"/test/a".into(), "1".into()).unwrap();
+
+            replica.reject_writes.store(true, Ordering::SeqCst);
+            assert_eq!(
+                txn.write(&"/test/b".into(), "2".into()),
+                Err(TxnError::ReplicaDiverged("0".into()))
+            );
+            assert!(ctx.replica_diverged());
+            assert_eq!(ctx.diverged_replicas(), vec!["0".to_string()]);
+
+            // The transaction is aborted already, so the rest of it fails and aborting again does nothing.
+            assert_eq!(txn.write(&"/test/c".into(), "3".into()), Err(TxnError::Aborted));
+            assert_eq!(txn.read(&"/test/a".into()), Err(TxnError::Aborted));
+            let mut scan = txn.scan_iter(&"/test/".into(), ScanOptions::default());
+            assert_matches!(scan.next(), Some(Err(TxnError::Aborted)));
+            assert!(scan.next().is_none());
+            txn.abort();
+            txn.abort();
+            std::mem::drop(txn);
+
+            for db in [&ctx, &replica.db.db] {
+                assert_eq!(read(db, "/test/a"), Err(TxnError::NotFound));
+                assert_eq!(read(db, "/test/b"), Err(TxnError::NotFound));
+            }
+        }
+
+        #[test]
+        fn diverging_replica_is_named() {
+            let replicas = [Arc::new(Flaky::default()), Arc::new(Flaky::default())];
+            replicas[1].reject_writes.store(true, Ordering::SeqCst);
+            let next = AtomicUsize::new(0);
+            let handler = LocalReplicationHandler::new(2, || {
+                replicas[next.fetch_add(1, Ordering::SeqCst)].clone()
+            });
+            let mut ctx = create_empty_context();
+            ctx.replicators = Some(Box::new(handler));
+
+            let mut txn = ReplicatedTxn::new(&ctx);
+            assert_eq!(
+                txn.write(&"/test/a".into(), "1".into()),
+                Err(TxnError::ReplicaDiverged("1".into()))
+            );
+            assert_eq!(ctx.diverged_replicas(), vec!["1".to_string()]);
+        }
+
+        #[test]
+        fn diverging_write_in_run_transaction() {
+            let (ctx, replica) = setup();
+            replica.reject_writes.store(true, Ordering::SeqCst);
+            // The write aborts the transaction, then `run_transaction` aborts it again.
+            assert_eq!(
+                ctx.run_transaction(|txn| txn.write(&"/test/a".into(), "1".into())),
+                Err(TxnError::ReplicaDiverged("0".into()))
+            );
+            assert_eq!(read(&ctx, "/test/a"), Err(TxnError::NotFound));
+        }
     }
 }
@@ -1,6 +1,7 @@
 pub mod btreemap_kv_backend;
 mod lock_data_manager;
 mod mvcc_metadata;
+mod timestamp_cache;
 mod typed_value;
 pub mod value_with_mvcc;
 
@@ -8,6 +9,7 @@ pub use mvcc_metadata::MVCCMetadata;
 pub use typed_value::TypedValue;
 pub use value_with_mvcc::{UnlockedWritableMVCC, ValueWithMVCC};
 
+use crate::isolation::ReadView;
 use crate::object_path::ObjectPath;
 use crate::DbContext;
 
@@ -15,72 +17,88 @@ pub use lock_data_manager::{IntentMap, LockDataRef};
 pub use mvcc_metadata::{WriteIntent, WriteIntentStatus};
 
 use crate::rwtransaction_wrapper::MutBTreeMap;
-use std::cell::UnsafeCell;
-use std::collections::BTreeMap;
-use std::sync::RwLockReadGuard;
+use btreemap_kv_backend::ValueGuard;
 
-use crate::custom_error_impl;
-
-#[derive(Debug, PartialEq)]
-pub enum ReadError {
-    ValueNotFound,
-    PendingIntentErr(LockDataRef),
-    Other(String),
-}
-custom_error_impl!(ReadError);
+use crate::timestamp::Timestamp;
+use crate::TxnError;
 
+// `view` is where the transaction reads from: it can't overwrite a version it doesn't see, see `ReadView::sees`.
 pub(super) fn update(
     ctx: &DbContext,
     key: &ObjectPath,
     new_value: TypedValue,
     txn: LockDataRef,
-) -> Result<(), String> {
-    match get_latest_mvcc_value(&ctx.db, key) {
-        (lock, Some(res)) => {
-            let resl = match res.get_readable_fix_errors(ctx, txn) {
-                Ok(a) => a,
-                Err(ReadError::ValueNotFound) => {
-                    // Some other thread jumped in and deleted/modified this value before we could lock it.
-                    // Call this function again with exact same parameters so it chooses the other branch (insert value) instead.
-                    std::mem::drop(lock);
-                    return update(ctx, key, new_value, txn);
-                }
-                Err(err) => {
-                    return Err(format!("{:?}", err));
-                }
-            };
-            let mut resl = res.get_writable(txn, resl)?;
+    view: &ReadView,
+) -> Result<(), TxnError> {
+    loop {
+        let mut lock = get_latest_mvcc_value(&ctx.db, key);
+        match lock.value() {
+            Some(res) => {
+                // If the key is deleted, the tombstone becomes an older version like any other value.
+                let resl = res.get_readable_fix_errors(ctx, txn)?;
+                let mut resl = res.get_writable(txn, view, resl)?;
 
-            // Actually update the value with the desired new_value.
-            resl.inplace_update(ctx, txn, new_value).unwrap();
-
-            std::mem::drop(resl);
-        }
-        (lock, None) => {
-            std::mem::drop(lock);
-            // We're inserting a new key here.
-            ctx.db.insert(
-                key.clone(),
-                ValueWithMVCC::new(txn, new_value),
-                txn.timestamp,
-            )?;
+                // Actually update the value with the desired new_value.
+                resl.inplace_update(ctx, txn, new_value).unwrap();
+                return Ok(());
+            }
+            None => {
+                std::mem::drop(lock);
+                // We're inserting a new key here.
+                let value = ValueWithMVCC::new(txn, new_value.clone());
+                if ctx.db.insert(key.clone(), value, txn.timestamp)?.is_none() {
+                    return Ok(());
+                }
+                // Another transaction inserted the key in the meantime, so write over its value instead.
+            }
         }
-    };
-
-    Ok(())
+    }
 }
 
-fn check_has_value(db: &MutBTreeMap, key: &ObjectPath) -> bool {
-    db.get_mut(key).is_some()
+// Writes `f(current value)` to `key` and returns what was written. The current value is None if the key doesn't
+// exist at our timestamp. It stays locked until the new value is in place, so nothing can change in between, and
+// the read is recorded like any other, also when `f` refuses to write. `view` is as for `update`.
+pub(super) fn read_modify_write(
+    ctx: &DbContext,
+    key: &ObjectPath,
+    txn: LockDataRef,
+    view: &ReadView,
+    mut f: impl FnMut(Option<&TypedValue>) -> Result<TypedValue, TxnError>,
+) -> Result<TypedValue, TxnError> {
+    loop {
+        let mut lock = get_latest_mvcc_value(&ctx.db, key);
+        match lock.value() {
+            Some(res) => {
+                let resl = res.get_readable_fix_errors(ctx, txn)?;
+                resl.confirm_read(txn.timestamp);
+                let current = Some(resl.val).filter(|val| !MutBTreeMap::is_deleated(val));
+                let new_value = f(current)?;
+
+                let mut resl = res.get_writable(txn, view, resl)?;
+                resl.inplace_update(ctx, txn, new_value.clone()).unwrap();
+                return Ok(new_value);
+            }
+            None => {
+                let new_value = f(None);
+                if new_value.is_err() {
+                    ctx.db.record_missing_read(key, txn.timestamp);
+                }
+                std::mem::drop(lock);
+                let new_value = new_value?;
+                let value = ValueWithMVCC::new(txn, new_value.clone());
+                if ctx.db.insert(key.clone(), value, txn.timestamp)?.is_none() {
+                    return Ok(new_value);
+                }
+                // Another transaction inserted the key in the meantime, so look at its value instead.
+            }
+        }
+    }
 }
 
 fn get_latest_mvcc_value<'a>(
     db: &'a MutBTreeMap,
     key: &ObjectPath,
-) -> (
-    RwLockReadGuard<'a, UnsafeCell<BTreeMap<ObjectPath, ValueWithMVCC>>>,
-    Option<&'a mut ValueWithMVCC>,
-) {
+) -> ValueGuard<'a> {
     // todo! right now, we locking the whole database for each single read/write (not a transaction, which is good).
     // this because there's no atomic way to set an enum (WriteIntent) right now.
     // Also because I haven't thought of a way to do low-level per-value locking
@@ -89,52 +107,103 @@ fn get_latest_mvcc_value<'a>(
     // changing the String atomically. In these cases, we must lock the value for a brief moment to do operations, then unlock it.
     // Without this, readers might read invalid memory and will segfault.
 
-    db.get_mut_with_lock(key)
+    // Deleted keys are returned too: older versions may still be visible, and their reads must be recorded.
+    db.get_raw_with_lock(key)
 }
 
 pub fn read_reference(
     ctx: &DbContext,
     v: &ValueWithMVCC,
     txn: LockDataRef,
-) -> Result<ValueWithMVCC, ReadError> {
+) -> Result<ValueWithMVCC, TxnError> {
+    read_version(ctx, v, txn, true, &|_| false)
+}
+
+// Like `read_reference`, but doesn't record the read, so writers older than `txn` aren't affected.
+// Only consistent if no transaction older than `txn` can still write, see `ReadOnlyTxn`.
+pub(crate) fn read_snapshot(
+    ctx: &DbContext,
+    v: &ValueWithMVCC,
+    txn: LockDataRef,
+) -> Result<ValueWithMVCC, TxnError> {
+    read_version(ctx, v, txn, false, &|_| false)
+}
+
+// Like `read_snapshot`, but also skips the versions written at the timestamps `hidden` returns true for, even once
+// they're committed. Their pending intents are read around instead of failing.
+pub(crate) fn read_snapshot_hiding(
+    ctx: &DbContext,
+    v: &ValueWithMVCC,
+    txn: LockDataRef,
+    hidden: &dyn Fn(Timestamp) -> bool,
+) -> Result<ValueWithMVCC, TxnError> {
+    read_version(ctx, v, txn, false, hidden)
+}
+
+fn read_version(
+    ctx: &DbContext,
+    v: &ValueWithMVCC,
+    txn: LockDataRef,
+    record_read: bool,
+    hidden: &dyn Fn(Timestamp) -> bool,
+) -> Result<ValueWithMVCC, TxnError> {
     enum R<'a> {
         Result(ValueWithMVCC),
-        Recurse(&'a mut ValueWithMVCC),
+        // The previous version, and who to read it as.
+        Recurse(&'a mut ValueWithMVCC, LockDataRef),
     }
 
     fn do_read<'a>(
         res: &ValueWithMVCC,
         ctx: &'a DbContext,
         txn: LockDataRef,
-    ) -> Result<R<'a>, ReadError> {
-
-        let read_latest = res.get_readable_fix_errors(ctx, txn);
+        record_read: bool,
+        hidden: &dyn Fn(Timestamp) -> bool,
+    ) -> Result<R<'a>, TxnError> {
 
-        match read_latest {
-            Ok(resl) => {
-                resl.confirm_read(txn.timestamp);
+        // Doesn't keep the value locked past this statement, `get_mvcc_copy` locks it again.
+        let err = match res.get_readable_fix_errors(ctx, txn) {
+            Ok(resl) if !hidden(resl.meta.get_beg_time()) => {
+                if record_read {
+                    resl.confirm_read(txn.timestamp);
+                }
+                if MutBTreeMap::is_deleated(resl.val) {
+                    return Err(TxnError::NotFound);
+                }
                 let cloned = ValueWithMVCC::from_tuple(resl.meta.clone(), resl.val.clone());
-                Ok(R::Result(cloned))
+                return Ok(R::Result(cloned));
             }
-            Err(err) => {
-                let resl = res.get_mvcc_copy();
-                if resl.get_beg_time() >= txn.timestamp {
-                    return if let Ok(prevval) = resl.get_prev_mvcc(ctx) {
-                        Ok(R::Recurse(prevval))
-                    } else {
-                        // We've reached beginning of version chain, and yet the timestamp is smaller than the begin timestamp.
-                        Err(ReadError::ValueNotFound)
-                    };
+            Ok(_) => None,
+            Err(err) => Some(err),
+        };
+        let resl = res.get_mvcc_copy();
+        let begin = resl.get_beg_time();
+        match err {
+            Some(err) if begin < txn.timestamp && !hidden(begin) => Err(err),
+            _ => {
+                // A hidden version is read around like one written after us, so the previous one still counts as
+                // the latest.
+                let txn = if hidden(begin) && begin < txn.timestamp {
+                    IntentMap::generate_read_txn_with_time(Timestamp(begin.0 - 1))
                 } else {
-                    Err(err)
+                    txn
+                };
+                if let Ok(prevval) = resl.get_prev_mvcc(ctx) {
+                    Ok(R::Recurse(prevval, txn))
+                } else if txn.timestamp < ctx.vacuum_watermark() {
+                    // The version this transaction should see may have been removed by vacuum.
+                    Err(TxnError::SnapshotTooOld)
+                } else {
+                    // We've reached beginning of version chain, and yet the timestamp is smaller than the begin timestamp.
+                    Err(TxnError::NotFound)
                 }
             }
         }
     }
-    let mut res = do_read(v, ctx, txn)?;
+    let mut res = do_read(v, ctx, txn, record_read, hidden)?;
 
-    while let R::Recurse(recurse) = res {
-        res = do_read(recurse, ctx, txn)?;
+    while let R::Recurse(recurse, txn) = res {
+        res = do_read(recurse, ctx, txn, record_read, hidden)?;
     }
     if let R::Result(r) = res {
         Ok(r)
@@ -143,21 +212,11 @@ pub fn read_reference(
     }
 }
 
-pub fn read(
-    ctx: &DbContext,
-    key: &ObjectPath,
-    txn: LockDataRef,
-) -> Result<ValueWithMVCC, ReadError> {
-    let (_lock, res) = get_latest_mvcc_value(&ctx.db, key);
-    let res = res.ok_or_else(|| "Read value doesn't exist".to_string())?;
-
-    read_reference(ctx, res, txn)
-}
-
 #[cfg(test)]
 mod tests {
     use crate::db;
     use crate::rwtransaction_wrapper::ReplicatedTxn;
+    use crate::TxnError;
 
     #[test]
     fn regression_wrong_error_emitted() {
@@ -166,7 +225,7 @@ mod tests {
         let mut txn2 = ReplicatedTxn::new(&db);
         txn1.write(&"/test/a".into(), "a".into()).unwrap();
         assert_matches!(txn2.read_range_owned(&"/test/".into()), Err(..));
-        txn1.commit();
+        txn1.commit().unwrap();
         assert_matches!(txn2.read_range_owned(&"/test/".into()), Ok(..));
     }
 
@@ -176,11 +235,24 @@ mod tests {
         let mut r = ReplicatedTxn::new(&db);
         let mut w = ReplicatedTxn::new(&db);
 
-        w.write(&"k".into(), "v1".into());
+        w.write(&"k".into(), "v1".into()).unwrap();
         assert_eq!(r.read(&"k".into()).unwrap(), "v".into());
 
-        w.commit();
+        w.commit().unwrap();
         let mut r = ReplicatedTxn::new(&db);
         assert_eq!(r.read(&"k".into()).unwrap(), "v1".into());
     }
+
+    #[test]
+    fn reading_missing_key_blocks_older_inserts() {
+        let db = db!("k" = "v");
+        let mut old = ReplicatedTxn::new(&db);
+        let mut new = ReplicatedTxn::new(&db);
+
+        assert_eq!(new.read(&"k2".into()), Err(TxnError::NotFound));
+        assert_eq!(old.write(&"k2".into(), "v".into()), Err(TxnError::PhantomDetected));
+        assert_eq!(old.write(&"k3".into(), "v".into()), Ok(()));
+        new.commit().unwrap();
+        old.commit().unwrap();
+    }
 }
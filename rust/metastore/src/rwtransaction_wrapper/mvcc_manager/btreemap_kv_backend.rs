@@ -1,24 +1,37 @@
-use std::borrow::Borrow;
 use std::cell::UnsafeCell;
-use std::collections::btree_map::{BTreeMap, Range};
+use std::collections::btree_map::{BTreeMap, Entry, Range};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
 use std::ops::RangeBounds;
+use std::ptr::NonNull;
 
+use super::timestamp_cache::TimestampCache;
 use super::value_with_mvcc::ValueWithMVCC;
 use super::TypedValue;
 use crate::object_path::ObjectPath;
 use crate::timestamp::Timestamp;
+use crate::TxnError;
 use std::collections::Bound;
 use std::fmt::Write;
-use std::sync::atomic::AtomicU64;
-use std::sync::atomic::Ordering::SeqCst;
-use std::sync::{RwLock, RwLockReadGuard};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 type UnsafeMapType = UnsafeCell<MapType>;
 type MapType = BTreeMap<ObjectPath, ValueWithMVCC>;
 
+// Keys are spread over this many independently locked trees by their hash, so inserting a new key only
+// write-locks the tree it goes into and readers of all other trees carry on.
+const SHARDS: usize = 16;
+
+// Number of keys `scan_pages` reads before letting go of the locks.
+const SCAN_PAGE_SIZE: usize = 128;
+
 pub struct MutBTreeMap {
-    btree: RwLock<UnsafeMapType>,
-    time: AtomicU64,
+    shards: Vec<RwLock<UnsafeMapType>>,
+    // Spans of keys read by transactions, so inserts can detect phantoms, one cache per shard. Only locked while
+    // holding the lock of its shard: inserts hold it for writing, so they're never concurrent with a read of their
+    // shard and only check their own cache. A range read covers keys in every shard, so it's recorded in every
+    // cache, while a missing key is only recorded in the cache of the shard it would go into.
+    reads: Vec<Mutex<TimestampCache>>,
 }
 
 unsafe impl Sync for MutBTreeMap {}
@@ -28,47 +41,242 @@ unsafe impl Send for MutBTreeMap {}
 impl Default for MutBTreeMap {
     fn default() -> Self {
         Self {
-            btree: RwLock::new(UnsafeCell::new(MapType::new())),
-            time: AtomicU64::new(Timestamp::mintime().0),
+            shards: (0..SHARDS)
+                .map(|_| RwLock::new(UnsafeCell::new(MapType::new())))
+                .collect(),
+            reads: (0..SHARDS)
+                .map(|_| Mutex::new(TimestampCache::new()))
+                .collect(),
         }
     }
 }
 
+// Keeps the trees locked for reading while it's alive. Values returned together with it stay valid until it's dropped.
+pub struct ReadGuard<'a>(Vec<RwLockReadGuard<'a, UnsafeMapType>>);
+
+// The value of one key, with the tree it's in locked for reading. Values are changed in place under the read lock,
+// see `get_latest_mvcc_value`.
+pub struct ValueGuard<'a> {
+    _lock: RwLockReadGuard<'a, UnsafeMapType>,
+    value: Option<NonNull<ValueWithMVCC>>,
+}
+
+impl ValueGuard<'_> {
+    pub fn value(&mut self) -> Option<&mut ValueWithMVCC> {
+        // The tree can't change while `_lock` is held, so the value stays where it is.
+        self.value.map(|value| unsafe { &mut *value.as_ptr() })
+    }
+}
+
+// Merges the per-shard ranges, each sorted by key, into one range sorted by key. Works from both ends.
+pub struct MergedRange<'a> {
+    ranges: Vec<Range<'a, ObjectPath, ValueWithMVCC>>,
+    // Elements already taken out of `ranges` but not returned yet.
+    front: Vec<Option<(&'a ObjectPath, &'a ValueWithMVCC)>>,
+    back: Vec<Option<(&'a ObjectPath, &'a ValueWithMVCC)>>,
+}
+
+impl<'a> MergedRange<'a> {
+    fn new(ranges: Vec<Range<'a, ObjectPath, ValueWithMVCC>>) -> Self {
+        Self {
+            front: vec![None; ranges.len()],
+            back: vec![None; ranges.len()],
+            ranges,
+        }
+    }
+}
+
+impl<'a> Iterator for MergedRange<'a> {
+    type Item = (&'a ObjectPath, &'a ValueWithMVCC);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for i in 0..self.ranges.len() {
+            if self.front[i].is_none() {
+                // Once a range is used up, its last element may still be waiting in `back`.
+                self.front[i] = self.ranges[i].next().or_else(|| self.back[i].take());
+            }
+        }
+        let min = (0..self.front.len())
+            .filter(|i| self.front[*i].is_some())
+            .min_by(|a, b| self.front[*a].unwrap().0.cmp(self.front[*b].unwrap().0))?;
+        self.front[min].take()
+    }
+}
+
+impl<'a> DoubleEndedIterator for MergedRange<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        for i in 0..self.ranges.len() {
+            if self.back[i].is_none() {
+                self.back[i] = self.ranges[i].next_back().or_else(|| self.front[i].take());
+            }
+        }
+        let max = (0..self.back.len())
+            .filter(|i| self.back[*i].is_some())
+            .max_by(|a, b| self.back[*a].unwrap().0.cmp(self.back[*b].unwrap().0))?;
+        self.back[max].take()
+    }
+}
+
+// The whole map, with every tree locked for writing.
+pub(crate) struct ExclusiveMap<'a> {
+    _guards: Vec<RwLockWriteGuard<'a, UnsafeMapType>>,
+    shards: Vec<&'a mut MapType>,
+    reads: &'a [Mutex<TimestampCache>],
+}
+
+// Removes `key` from `shard`, remembering in the shard's `reads` when it was last read so older transactions still
+// can't insert it.
+fn remove_from(
+    shard: &mut MapType,
+    reads: &Mutex<TimestampCache>,
+    key: &ObjectPath,
+) -> Option<ValueWithMVCC> {
+    let value = shard.remove(key)?;
+    let last_read = value.get_mvcc_copy().get_last_read_time();
+    reads
+        .lock()
+        .unwrap()
+        .record(&(key.clone()..=key.clone()), last_read);
+    Some(value)
+}
+
+impl<'a> ExclusiveMap<'a> {
+    // All keys in order.
+    pub fn iter(&self) -> MergedRange<'_> {
+        MergedRange::new(
+            self.shards
+                .iter()
+                .map(|shard| shard.range::<ObjectPath, _>(..))
+                .collect(),
+        )
+    }
+    pub fn values(&self) -> impl Iterator<Item = &ValueWithMVCC> {
+        self.shards.iter().flat_map(|shard| shard.values())
+    }
+    pub fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut ValueWithMVCC> + '_> {
+        Box::new(self.shards.iter_mut().flat_map(|shard| shard.values_mut()))
+    }
+    pub fn iter_mut(
+        &mut self,
+    ) -> Box<dyn Iterator<Item = (&ObjectPath, &mut ValueWithMVCC)> + '_> {
+        Box::new(self.shards.iter_mut().flat_map(|shard| shard.iter_mut()))
+    }
+    // See `MutBTreeMap::remove_key`.
+    pub fn remove(&mut self, key: &ObjectPath) -> Option<ValueWithMVCC> {
+        let shard = MutBTreeMap::shard_of(key);
+        remove_from(self.shards[shard], &self.reads[shard], key)
+    }
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 impl MutBTreeMap {
-    pub fn remove_key(&self, _key: &ObjectPath) {}
+    // Removes the key with all its versions, as if it had never been written. Only for keys no transaction can
+    // see any version of anymore, e.g. ones deleted before every running transaction started.
+    pub fn remove_key(&self, key: &ObjectPath) -> Option<ValueWithMVCC> {
+        let shard = Self::shard_of(key);
+        let lock = self.shards[shard].write().unwrap();
+        remove_from(unsafe { &mut *lock.get() }, &self.reads[shard], key)
+    }
 
     pub fn new() -> Self {
         Self::default()
     }
 
-    pub fn range<'g, R: 'g>(&'g self, range: R) -> impl DoubleEndedIterator<Item = (&'g ObjectPath, &'g ValueWithMVCC)>
+    fn shard_of(key: &ObjectPath) -> usize {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key.as_str().as_bytes());
+        hasher.finish() as usize % SHARDS
+    }
+
+    fn read_all(&self) -> ReadGuard<'_> {
+        ReadGuard(self.shards.iter().map(|shard| shard.read().unwrap()).collect())
+    }
+
+    // Ranges over the trees held by `lock`, which must come from `read_all`.
+    fn merged_range<'a, R>(lock: &'a ReadGuard<'_>, range: R) -> MergedRange<'a>
     where
         R: RangeBounds<ObjectPath> + Clone,
     {
-        unsafe { &*self.btree.read().unwrap().get() }.range(range)
+        MergedRange::new(
+            lock.0
+                .iter()
+                .map(|shard| unsafe { &*shard.get() }.range(range.clone()))
+                .collect(),
+        )
     }
 
-    pub fn range_with_lock<'a, R>(
-        &'a self,
-        range: R,
-        time: Timestamp,
-    ) -> (
-        RwLockReadGuard<'_, UnsafeMapType>,
-        impl DoubleEndedIterator<Item = (&'a ObjectPath, &'a ValueWithMVCC)>,
-    )
-    where
-        R: RangeBounds<ObjectPath> + Clone + 'a,
-    {
-        let lock = self.btree.read().unwrap();
-        let range = unsafe { &*lock.get() }.range(range);
+    // Calls `f` on the keys in `range` in key order (from the largest if `reverse`) until it returns false, meaning
+    // it doesn't want the keys after this one. The trees are only locked for `SCAN_PAGE_SIZE` keys at a time, so a
+    // long scan never keeps inserts waiting for more than one page, and keys can be inserted between two pages.
+    // `on_page` is called with the part of the range each page covered while its locks are still held, e.g. to
+    // record it with `record_range_read`: an insert into it either happened before and was seen, or sees the read.
+    pub(crate) fn scan_pages<E>(
+        &self,
+        range: (Bound<ObjectPath>, Bound<ObjectPath>),
+        reverse: bool,
+        mut f: impl FnMut(&ObjectPath, &ValueWithMVCC) -> Result<bool, E>,
+        mut on_page: impl FnMut(&(Bound<ObjectPath>, Bound<ObjectPath>)),
+    ) -> Result<(), E> {
+        let (mut lower, mut upper) = range;
+        loop {
+            let lock = self.read_all();
+            let keys = Self::merged_range(&lock, (lower.clone(), upper.clone()));
+            let keys: Box<dyn Iterator<Item = _>> = if reverse {
+                Box::new(keys.rev())
+            } else {
+                Box::new(keys)
+            };
 
-        let prevtime = self.time.load(SeqCst);
+            // The last key of the page, unless the page reached the end of the range.
+            let mut last = None;
+            let mut more = true;
+            for (i, (key, value)) in keys.enumerate() {
+                more = f(key, value)?;
+                if !more || i + 1 == SCAN_PAGE_SIZE {
+                    last = Some(key.clone());
+                    break;
+                }
+            }
 
-        if time.0 > prevtime {
-            self.time.store(time.0, SeqCst);
+            let last = match last {
+                Some(last) => last,
+                None => {
+                    on_page(&(lower, upper));
+                    return Ok(());
+                }
+            };
+            if reverse {
+                on_page(&(Bound::Included(last.clone()), upper));
+                upper = Bound::Excluded(last);
+            } else {
+                on_page(&(lower, Bound::Included(last.clone())));
+                lower = Bound::Excluded(last);
+            }
+            if !more {
+                return Ok(());
+            }
         }
+    }
 
-        (lock, range)
+    // Records that a transaction at `time` read `key` and didn't find it. Must be called while holding the
+    // lock returned with the lookup.
+    pub(crate) fn record_missing_read(&self, key: &ObjectPath, time: Timestamp) {
+        let mut reads = self.reads[Self::shard_of(key)].lock().unwrap();
+        if reads.max_read(key) < time {
+            reads.record(&(key.clone()..=key.clone()), time);
+        }
+    }
+
+    // Records a range read done with `scan_pages`. Must be called from its `on_page`, while the page is still locked.
+    pub(crate) fn record_range_read<R: RangeBounds<ObjectPath>>(&self, range: &R, time: Timestamp) {
+        for reads in &self.reads {
+            reads.lock().unwrap().record(range, time);
+        }
     }
 
     pub fn is_deleated(a: &TypedValue) -> bool {
@@ -81,93 +289,82 @@ impl MutBTreeMap {
             Some(a)
         }
     }
-    pub fn get_mut<T>(&self, key: &T) -> Option<&mut ValueWithMVCC>
-    where
-        ObjectPath: Borrow<T> + Ord,
-        T: Ord + ?Sized + Clone,
-    {
-        unsafe { &mut *self.btree.read().unwrap().get() }
-            .get_mut(key)
-            .and_then(Self::null_value_mapper)
+    pub fn get_mut_with_lock(&self, key: &ObjectPath) -> ValueGuard<'_> {
+        let mut guard = self.get_raw_with_lock(key);
+        guard.value = guard.value().and_then(Self::null_value_mapper).map(NonNull::from);
+        guard
     }
-    pub fn get_mut_with_lock<T>(
-        &self,
-        key: &T,
-    ) -> (
-        RwLockReadGuard<'_, UnsafeMapType>,
-        Option<&mut ValueWithMVCC>,
-    )
-    where
-        ObjectPath: Borrow<T> + Ord,
-        T: Ord + ?Sized,
-    {
-        let lock = self.btree.read().unwrap();
-        let s = unsafe { &mut *lock.get() };
 
-        (lock, s.get_mut(key).and_then(Self::null_value_mapper))
+    // Like `get_mut_with_lock`, but also returns deleted values.
+    pub(crate) fn get_raw_with_lock(&self, key: &ObjectPath) -> ValueGuard<'_> {
+        let lock = self.shards[Self::shard_of(key)].read().unwrap();
+        let value = unsafe { &mut *lock.get() }.get_mut(key).map(NonNull::from);
+        ValueGuard { _lock: lock, value }
     }
 
-    // Checks for phantoms by making sure our write time is larger than their read times.
-    fn check_adjacent_keys(
-        btree: &MapType,
+    // Runs `f` on the value of `key`, deleted or not, with its tree locked for writing, so nobody else can look at
+    // the value while `f` changes it. Returns None if the key doesn't exist.
+    pub(crate) fn with_value_mut<R>(
+        &self,
         key: &ObjectPath,
-        writetime: Timestamp,
-    ) -> (Option<bool>, Option<bool>) {
-        let prev = btree.range(..key).next_back();
-
-        // maybe have to move this to mvcc_manager? btreemap shouldn't deal with concurrency-related issues at all.
-        let prevbool = prev.map(|x| x.1.get_mvcc_copy().get_last_read_time() <= writetime);
-        let next = btree.range(key..).next();
-        let nextbool = next.map(|x| x.1.get_mvcc_copy().get_last_read_time() <= writetime);
-
-        (prevbool, nextbool)
+        f: impl FnOnce(&mut ValueWithMVCC) -> R,
+    ) -> Option<R> {
+        let mut lock = self.shards[Self::shard_of(key)].write().unwrap();
+        lock.get_mut().get_mut(key).map(f)
     }
 
+    // Inserts a new key. If another thread inserted the key first, `value` is handed back instead.
     pub fn insert(
         &self,
         key: ObjectPath,
         value: ValueWithMVCC,
         time: Timestamp,
-    ) -> Result<Option<ValueWithMVCC>, String> {
+    ) -> Result<Option<ValueWithMVCC>, TxnError> {
         // todo: inserts should be handled by mvcc manager
-        let lock = self.btree.write().unwrap();
-        let btree = unsafe { &mut *lock.get() };
-
-        let (prev, next) = Self::check_adjacent_keys(btree, &key, time);
-
-        let should_insert =
-            // If there are no keys to the left and right (this is an empty database), then we must lock on the global timestamp instance.
-            if prev.or(next).is_none() {
-                assert!(btree.is_empty());
-                // If there's nowhere that we can lock, this would lead to an error (checked by test `check_phantom3`)
-                // Have to lock the global time instance instead.
-                let ret = self.time.load(SeqCst) <= time.0;
-                self.time.fetch_max(time.0, SeqCst);
-                ret
-            } else {
-                prev.unwrap_or(true) && next.unwrap_or(true)
-            };
-
-        if should_insert {
-            Ok(btree.insert(key, value))
-        } else {
-            Err("phantom detected".to_string())
+        let shard = Self::shard_of(&key);
+        let lock = self.shards[shard].write().unwrap();
+        // A newer transaction read the key's span and didn't see it, so it can't show up at an older time.
+        if self.reads[shard].lock().unwrap().max_read(&key) > time {
+            return Err(TxnError::PhantomDetected);
         }
+        match unsafe { &mut *lock.get() }.entry(key) {
+            Entry::Occupied(_) => Ok(Some(value)),
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                Ok(None)
+            }
+        }
+    }
+
+    // Inserts without any phantom checks. Only used when rebuilding the map from a trusted source (e.g. disk).
+    pub(crate) fn insert_raw(&self, key: ObjectPath, value: ValueWithMVCC) -> Option<ValueWithMVCC> {
+        let lock = self.shards[Self::shard_of(&key)].write().unwrap();
+        unsafe { &mut *lock.get() }.insert(key, value)
     }
 
-    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&'_ ObjectPath,&'_  ValueWithMVCC)> {
+    // Runs `f` while holding every write lock, so no other thread can read or write any key at the same time.
+    pub(crate) fn with_exclusive<R>(&self, f: impl FnOnce(&mut ExclusiveMap) -> R) -> R {
+        let guards: Vec<_> = self.shards.iter().map(|shard| shard.write().unwrap()).collect();
+        let shards = guards.iter().map(|lock| unsafe { &mut *lock.get() }).collect();
+        f(&mut ExclusiveMap {
+            _guards: guards,
+            shards,
+            reads: &self.reads,
+        })
+    }
+
+    fn all_keys() -> (Bound<ObjectPath>, Bound<ObjectPath>) {
         let min = Bound::Included(ObjectPath::new("\x01"));
         let max = Bound::Included(ObjectPath::new("\x7f"));
-
-        self.range((min, max))
+        (min, max)
     }
 
     // Prints the database to stdout
     pub fn printdb(&self) -> String {
-        let _lock = self.btree.read().unwrap();
+        let lock = self.read_all();
         let mut str: String = String::new();
 
-        for (key, value) in self.iter() {
+        for (key, value) in Self::merged_range(&lock, Self::all_keys()) {
             let (x, y) = value.as_inner();
 
             if !matches!(y, TypedValue::Deleted) && x.get_write_intents().is_none() {
@@ -183,3 +380,136 @@ impl MutBTreeMap {
         str
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rwtransaction_wrapper::LockDataRef;
+
+    fn value(s: &str) -> ValueWithMVCC {
+        ValueWithMVCC::new(LockDataRef::debug_new(10), s.into())
+    }
+
+    fn scan_keys(map: &MutBTreeMap, reverse: bool) -> Vec<String> {
+        let mut keys = Vec::new();
+        map.scan_pages::<()>(
+            (Bound::Unbounded, Bound::Unbounded),
+            reverse,
+            |key, _| {
+                keys.push(key.as_str().to_string());
+                Ok(true)
+            },
+            |_| {},
+        )
+        .unwrap();
+        keys
+    }
+
+    #[test]
+    fn range_is_sorted_across_shards() {
+        let map = MutBTreeMap::new();
+        let mut keys: Vec<String> = (0..300).map(|i| format!("/test/{}", i)).collect();
+        for key in &keys {
+            map.insert_raw(key.as_str().into(), value(key));
+        }
+        keys.sort();
+
+        // More keys than fit in one page, so this also checks that pages pick up where the last one stopped.
+        assert_eq!(scan_keys(&map, false), keys);
+        let mut backward = scan_keys(&map, true);
+        backward.reverse();
+        assert_eq!(backward, keys);
+
+        // Taking from both ends must meet in the middle without skipping or repeating keys.
+        let lock = map.read_all();
+        let mut range = MutBTreeMap::merged_range(&lock, ..);
+        let mut both = Vec::new();
+        let mut tail = Vec::new();
+        loop {
+            match range.next() {
+                Some((k, _)) => both.push(k.as_str().to_string()),
+                None => break,
+            }
+            match range.next_back() {
+                Some((k, _)) => tail.push(k.as_str().to_string()),
+                None => break,
+            }
+        }
+        both.extend(tail.into_iter().rev());
+        assert_eq!(both, keys);
+    }
+
+    #[test]
+    fn range_reads_detect_phantoms() {
+        let map = MutBTreeMap::new();
+        for i in 0..50 {
+            map.insert_raw(format!("/test/{:02}", i).into(), value("a"));
+        }
+        let record = |range: (Bound<ObjectPath>, Bound<ObjectPath>)| {
+            map.scan_pages::<()>(range, false, |_, _| Ok(true), |page| {
+                map.record_range_read(page, Timestamp(10))
+            })
+            .unwrap()
+        };
+        record(ObjectPath::from("/test/").get_prefix_ranges());
+        record((
+            Bound::Included("/empty/".into()),
+            Bound::Included("/empty/z".into()),
+        ));
+
+        // Writing a new key into a range read at time 10 before that must fail, wherever its neighbours are.
+        assert_matches!(map.insert("/test/10a".into(), value("b"), Timestamp(5)), Err(..));
+        assert_matches!(map.insert("/empty/a".into(), value("b"), Timestamp(5)), Err(..));
+        assert_matches!(map.insert("/other".into(), value("b"), Timestamp(5)), Ok(None));
+        assert_matches!(map.insert("/test/10a".into(), value("b"), Timestamp(20)), Ok(None));
+
+        // Scans that don't record their pages don't count as reads.
+        scan_keys(&map, false);
+        assert_matches!(map.insert("/other2".into(), value("b"), Timestamp(5)), Ok(None));
+    }
+
+    #[test]
+    fn missing_reads_only_touch_their_shard() {
+        let map = MutBTreeMap::new();
+        let key: ObjectPath = "/test/a".into();
+        map.record_missing_read(&key, Timestamp(10));
+        assert_matches!(map.insert(key.clone(), value("b"), Timestamp(5)), Err(..));
+
+        let shard = MutBTreeMap::shard_of(&key);
+        for (i, reads) in map.reads.iter().enumerate() {
+            let expected = if i == shard { Timestamp(10) } else { Timestamp::mintime() };
+            assert_eq!(reads.lock().unwrap().max_read(&key), expected);
+        }
+    }
+
+    #[test]
+    fn scans_stop_early_and_record_what_they_covered() {
+        let map = MutBTreeMap::new();
+        for i in 0..300 {
+            map.insert_raw(format!("/test/{:03}", i).into(), value("a"));
+        }
+        let mut seen = 0;
+        let mut pages = Vec::new();
+        map.scan_pages::<()>(
+            (Bound::Unbounded, Bound::Unbounded),
+            false,
+            |_, _| {
+                seen += 1;
+                Ok(seen < 200)
+            },
+            |page| pages.push(page.clone()),
+        )
+        .unwrap();
+        assert_eq!(seen, 200);
+        assert_eq!(
+            pages,
+            vec![
+                (Bound::Unbounded, Bound::Included("/test/127".into())),
+                (
+                    Bound::Excluded("/test/127".into()),
+                    Bound::Included("/test/199".into())
+                ),
+            ]
+        );
+    }
+}
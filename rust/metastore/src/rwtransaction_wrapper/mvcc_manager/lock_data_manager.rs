@@ -5,9 +5,10 @@ use crate::timestamp::Timestamp;
 use std::sync::RwLock;
 
 use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Formatter};
 
-#[derive(Eq, PartialEq, Hash, Debug, Copy, Clone)]
+#[derive(Eq, PartialEq, Hash, Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct LockDataRef {
     pub id: u64,
     pub timestamp: Timestamp,
@@ -26,6 +27,12 @@ impl TransactionLockData {
     pub fn get_write_intent(&self) -> WriteIntentStatus {
         self.0
     }
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.0,
+            WriteIntentStatus::Committed | WriteIntentStatus::Aborted
+        )
+    }
 }
 
 #[derive(Default)]
@@ -52,16 +59,37 @@ impl IntentMap {
         txn: LockDataRef,
         status: WriteIntentStatus,
     ) -> Result<(), String> {
-        let prev = self
-            .0
-            .write()
-            .unwrap()
-            .insert(txn, TransactionLockData(status))
-            .map(|a| a.0);
-        if prev == Some(WriteIntentStatus::Pending) || status == WriteIntentStatus::Aborted {
+        self.change_status(txn, status, false)
+    }
+
+    // Aborts a transaction because its coordinator decided so, which may abort a prepared one too.
+    pub(crate) fn abort_prepared(&self, txn: LockDataRef) -> Result<(), String> {
+        self.change_status(txn, WriteIntentStatus::Aborted, true)
+    }
+
+    fn change_status(
+        &self,
+        txn: LockDataRef,
+        status: WriteIntentStatus,
+        decided: bool,
+    ) -> Result<(), String> {
+        let mut map = self.0.write().unwrap();
+        let prev = map.get(&txn).map(|a| a.0);
+        let allowed = match (prev, status) {
+            (Some(WriteIntentStatus::Pending), _) => true,
+            (Some(WriteIntentStatus::Prepared), WriteIntentStatus::Committed) => true,
+            (Some(WriteIntentStatus::Prepared), WriteIntentStatus::Aborted) => decided,
+            // Aborting twice is fine, and a missing record was reclaimed after its transaction finished, when
+            // nobody can look at its status anymore.
+            (Some(WriteIntentStatus::Aborted) | None, WriteIntentStatus::Aborted) => true,
+            _ => false,
+        };
+        if allowed {
+            map.insert(txn, TransactionLockData(status));
             Ok(())
         } else {
-            Err("Previous wi is not pending".to_string())
+            // Leave it as it is, e.g. a transaction that committed must not become aborted.
+            Err(format!("Can't change status {:?} to {:?}", prev, status))
         }
     }
 
@@ -83,6 +111,61 @@ impl IntentMap {
         }
     }
 
+    pub(crate) fn all_statuses(&self) -> Vec<(LockDataRef, WriteIntentStatus)> {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(txn, data)| (*txn, data.0))
+            .collect()
+    }
+
+    // Timestamp of the oldest transaction that hasn't committed or aborted yet.
+    pub(crate) fn oldest_pending(&self) -> Option<Timestamp> {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, data)| !data.is_finished())
+            .map(|(txn, _)| txn.timestamp)
+            .min()
+    }
+
+    // Timestamps of the transactions older than `time` that haven't committed or aborted yet.
+    pub(crate) fn pending_before(&self, time: Timestamp) -> Vec<Timestamp> {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(txn, data)| txn.timestamp < time && !data.is_finished())
+            .map(|(txn, _)| txn.timestamp)
+            .collect()
+    }
+
+    // Drops the records of all committed and aborted transactions. Returns the number of dropped records.
+    // Only safe once no value has a write intent of a finished transaction left on it.
+    pub(crate) fn remove_finished(&self) -> usize {
+        let mut map = self.0.write().unwrap();
+        let before = map.len();
+        map.retain(|_, data| !data.is_finished());
+        before - map.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn insert_status(&self, txn: LockDataRef, status: WriteIntentStatus) {
+        self.0
+            .write()
+            .unwrap()
+            .insert(txn, TransactionLockData(status));
+    }
+
     pub fn get_by_ref(&self, l: &LockDataRef) -> Option<TransactionLockData> {
         self.0.read().unwrap().get(l).cloned()
     }
@@ -1,9 +1,10 @@
 use std::fmt::{Debug, Display, Formatter};
 
 use super::lock_data_manager::{IntentMap, LockDataRef};
+use crate::isolation::ReadView;
 use crate::rwtransaction_wrapper::ValueWithMVCC;
 use crate::timestamp::Timestamp;
-use crate::DbContext;
+use crate::{DbContext, TxnError};
 use serde::{Deserialize, Serialize};
 use std::cell::Cell;
 use std::sync::Mutex;
@@ -24,6 +25,17 @@ impl WriteIntentError {
     }
 }
 
+impl From<WriteIntentError> for TxnError {
+    fn from(err: WriteIntentError) -> Self {
+        match err {
+            WriteIntentError::PendingIntent(txn) => TxnError::WriteConflict(txn),
+            // We read a value written by an aborted transaction, so we're aborted too.
+            WriteIntentError::Aborted(_) => TxnError::Aborted,
+            err => TxnError::Other(err.tostring()),
+        }
+    }
+}
+
 impl MVCCMetadata {
     pub fn aborted_reset_write_intent(
         &mut self,
@@ -56,7 +68,24 @@ impl MVCCMetadata {
         }
     }
 
-    pub(super) fn get_write_intents(&self) -> Option<WriteIntent> {
+    // Rebuilds metadata that was previously taken apart (e.g. when loading from disk).
+    pub(crate) fn from_parts(
+        begin_ts: Timestamp,
+        end_ts: Timestamp,
+        last_read: Timestamp,
+        write_intent: Option<WriteIntent>,
+        previous_mvcc_value: Option<usize>,
+    ) -> Self {
+        Self {
+            begin_ts,
+            end_ts,
+            last_read: Cell::new(last_read),
+            cur_write_intent: WriteIntentMutex::new(write_intent),
+            previous_mvcc_value,
+        }
+    }
+
+    pub(crate) fn get_write_intents(&self) -> Option<WriteIntent> {
         self.cur_write_intent.get()
     }
 
@@ -73,10 +102,12 @@ impl MVCCMetadata {
                      associated_transaction,
                      was_commited,
                  }) => {
+                // A missing record belongs to a transaction that finished and got reclaimed. All intents of a
+                // finished transaction are resolved before its record is dropped, so this intent was left by
+                // a writer that kept going after it aborted.
                 match txnmap
                     .get_by_ref(&associated_transaction)
-                    .unwrap()
-                    .get_write_intent()
+                    .map_or(WriteIntentStatus::Aborted, |data| data.get_write_intent())
                 {
                     // If the transaction is committed already, then all good.
                     WriteIntentStatus::Committed => {
@@ -91,7 +122,7 @@ impl MVCCMetadata {
                         // Transaction has been aborted, so we are reading an aborted value. Therefore, we should also abort this current transaction.
                         Err(WriteIntentError::Aborted(associated_transaction))
                     }
-                    WriteIntentStatus::Pending => {
+                    WriteIntentStatus::Pending | WriteIntentStatus::Prepared => {
                         Err(WriteIntentError::PendingIntent(associated_transaction))
                     }
                 }
@@ -99,19 +130,23 @@ impl MVCCMetadata {
         }
     }
 
-    pub(super) fn check_write(&self, cur_txn: LockDataRef) -> Result<(), String> {
+    // `view` is where the writer read from. Unless that's at its own timestamp, it may not see the latest value
+    // (snapshot isolation), which must not be overwritten then, or we'd lose a change we never saw.
+    pub(super) fn check_write(&self, cur_txn: LockDataRef, view: &ReadView) -> Result<(), TxnError> {
         if self.end_ts != Timestamp::maxtime() {
             // This record is not the latest, so we can't write to it.
-            return Err("Trying to write on a historical MVCC record".to_string());
+            return Err("Trying to write on a historical MVCC record".into());
         }
 
-        if cur_txn.timestamp < self.last_read.get() {
-            Err("Timestamp smaller than last read".to_string())
-        } else if cur_txn.timestamp < self.begin_ts {
-            Err("Timestamp smaller than begin time".to_string())
-        } else {
-            Ok(())
+        // Either a newer transaction read this value, or it wrote it.
+        if cur_txn.timestamp < self.last_read.get() || cur_txn.timestamp < self.begin_ts {
+            return Err(TxnError::ReadTimestampConflict);
         }
+        let own = matches!(self.get_write_intents(), Some(wi) if wi.associated_transaction == cur_txn);
+        if !own && !view.sees(self.begin_ts) {
+            return Err(TxnError::SnapshotConflict);
+        }
+        Ok(())
     }
 
     pub(super) fn inplace_into_newer(&mut self, timestamp: Timestamp) -> Self {
@@ -151,12 +186,11 @@ impl MVCCMetadata {
         &self,
         txnmap: &IntentMap,
         cur_txn: LockDataRef,
-    ) -> Result<(), String> {
+    ) -> Result<(), TxnError> {
         if cur_txn.timestamp < self.begin_ts || cur_txn.timestamp > self.end_ts {
-            return Err("Timestamp not valid".to_string());
+            return Err("Timestamp not valid".into());
         }
-        self.check_write_intents(txnmap, cur_txn)
-            .map_err(WriteIntentError::tostring)?;
+        self.check_write_intents(txnmap, cur_txn)?;
         Ok(())
     }
 
@@ -277,6 +311,13 @@ impl MVCCMetadata {
     pub(crate) fn insert_prev_mvcc(&mut self, p0: usize) {
         self.previous_mvcc_value.replace(p0);
     }
+    pub(crate) fn get_prev_mvcc_index(&self) -> Option<usize> {
+        self.previous_mvcc_value
+    }
+    // Cuts off all older versions. They stay in `old_values_store` until vacuum removes them.
+    pub(crate) fn clear_prev_mvcc(&mut self) {
+        self.previous_mvcc_value = None;
+    }
 
     pub fn sorta_equal(&self, other: &Self) -> bool {
         self.begin_ts == other.begin_ts && self.end_ts == other.end_ts
@@ -329,7 +370,7 @@ mod tests {
         txn1.write(&key1, "value1".into()).unwrap();
         assert_matches!(txnread.read(&ObjectPath::from("key1")), Err(..));
 
-        txn1.commit();
+        txn1.commit().unwrap();
         assert_eq!(txnread.read(&"key1".into()), Ok("value1".into()));
     }
 
@@ -341,7 +382,7 @@ mod tests {
         {
             let mut txninit = ReplicatedTxn::new_with_time(&ctx, Timestamp::from(1));
             txninit.write(&key, "whatever".into()).unwrap();
-            txninit.commit();
+            txninit.commit().unwrap();
         }
 
         let mut txn1 = ReplicatedTxn::new_with_time(&ctx, Timestamp::from(5));
@@ -353,10 +394,12 @@ mod tests {
     }
 }
 
-#[derive(PartialEq, Copy, Clone, Debug)]
+#[derive(PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum WriteIntentStatus {
     Aborted,
     Pending,
+    // Promised to commit if the coordinator of a two-phase commit decides to. Can't be aborted by anyone else.
+    Prepared,
     Committed,
 }
 
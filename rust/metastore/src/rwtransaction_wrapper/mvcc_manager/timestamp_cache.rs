@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+
+use crate::object_path::ObjectPath;
+use crate::timestamp::Timestamp;
+
+// Once there are more spans than this, the older half is dropped and `floor` raised to cover them.
+const MAX_SPANS: usize = 4096;
+
+// The latest timestamp each span of keys was read at, including keys that didn't exist when they were read.
+// Inserting a key below that timestamp would change what the read should have returned (a phantom).
+// Spans are half-open and don't overlap. Keys outside of all spans were read at `floor` at most.
+pub(crate) struct TimestampCache {
+    // Start of each span -> its end (None if unbounded) and when it was last read.
+    spans: BTreeMap<ObjectPath, (Option<ObjectPath>, Timestamp)>,
+    floor: Timestamp,
+}
+
+// The smallest key after `key`.
+fn successor(key: &ObjectPath) -> ObjectPath {
+    ObjectPath::from(format!("{}\0", key.as_str()))
+}
+
+// Whether `key` comes before `end`, where None is the end of the keyspace.
+fn before(key: &ObjectPath, end: &Option<ObjectPath>) -> bool {
+    end.as_ref().map_or(true, |end| key < end)
+}
+
+impl TimestampCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            spans: BTreeMap::new(),
+            floor: Timestamp::mintime(),
+        }
+    }
+
+    // Latest timestamp `key` was read at.
+    pub(crate) fn max_read(&self, key: &ObjectPath) -> Timestamp {
+        match self.spans.range(..=key).next_back() {
+            Some((_, (end, time))) if before(key, end) => (*time).max(self.floor),
+            _ => self.floor,
+        }
+    }
+
+    pub(crate) fn record<R: RangeBounds<ObjectPath>>(&mut self, range: &R, time: Timestamp) {
+        if time <= self.floor {
+            return;
+        }
+        let start = match range.start_bound() {
+            Bound::Included(key) => key.clone(),
+            Bound::Excluded(key) => successor(key),
+            Bound::Unbounded => ObjectPath::new(""),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Some(successor(key)),
+            Bound::Excluded(key) => Some(key.clone()),
+            Bound::Unbounded => None,
+        };
+        if !before(&start, &end) {
+            return;
+        }
+
+        self.split_at(&start);
+        if let Some(end) = &end {
+            self.split_at(end);
+        }
+        // Every span is now either completely inside the range or completely outside of it.
+        let mut gaps = Vec::new();
+        let mut cursor = Some(start.clone());
+        for (span_start, (span_end, span_time)) in self.spans.range_mut(start..) {
+            if !before(span_start, &end) {
+                break;
+            }
+            if let Some(cursor) = cursor.filter(|cursor| cursor < span_start) {
+                gaps.push((cursor, Some(span_start.clone())));
+            }
+            *span_time = (*span_time).max(time);
+            cursor = span_end.clone();
+        }
+        if let Some(cursor) = cursor.filter(|cursor| before(cursor, &end)) {
+            gaps.push((cursor, end));
+        }
+        for (gap_start, gap_end) in gaps {
+            self.spans.insert(gap_start, (gap_end, time));
+        }
+
+        if self.spans.len() > MAX_SPANS {
+            self.evict();
+        }
+    }
+
+    // Splits the span containing `key` in two, so that one of them starts at `key`.
+    fn split_at(&mut self, key: &ObjectPath) {
+        let rest = match self.spans.range_mut(..key).next_back() {
+            Some((_, (end, time))) if before(key, end) => (end.replace(key.clone()), *time),
+            _ => return,
+        };
+        self.spans.insert(key.clone(), rest);
+    }
+
+    // Forgets the older half of the spans. Their keys then count as read at the new floor, which is at least as
+    // late as they were actually read, so inserts are only refused more often, never less.
+    fn evict(&mut self) {
+        let mut times: Vec<Timestamp> = self.spans.values().map(|(_, time)| *time).collect();
+        times.sort_unstable();
+        self.floor = self.floor.max(times[times.len() / 2]);
+        let floor = self.floor;
+        self.spans.retain(|_, (_, time)| *time > floor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(s: &str) -> ObjectPath {
+        s.into()
+    }
+
+    #[test]
+    fn overlapping_reads_keep_the_latest_time() {
+        let mut cache = TimestampCache::new();
+        cache.record(&(path("/b")..path("/d")), Timestamp(20));
+        cache.record(&(path("/a")..=path("/c")), Timestamp(10));
+        cache.record(&(path("/c/")..), Timestamp(30));
+
+        assert_eq!(cache.max_read(&path("/")), Timestamp::mintime());
+        assert_eq!(cache.max_read(&path("/a")), Timestamp(10));
+        assert_eq!(cache.max_read(&path("/b")), Timestamp(20));
+        assert_eq!(cache.max_read(&path("/c")), Timestamp(20));
+        assert_eq!(cache.max_read(&path("/c/1")), Timestamp(30));
+        assert_eq!(cache.max_read(&path("/d")), Timestamp(30));
+        assert_eq!(cache.max_read(&path("/zzz")), Timestamp(30));
+
+        // A point read covers only its own key.
+        cache.record(&(path("/e")..=path("/e")), Timestamp(40));
+        assert_eq!(cache.max_read(&path("/e")), Timestamp(40));
+        assert_eq!(cache.max_read(&path("/e/1")), Timestamp(30));
+    }
+
+    #[test]
+    fn eviction_only_raises_read_times() {
+        let mut cache = TimestampCache::new();
+        for i in 1..=MAX_SPANS as u64 + 1 {
+            let key = path(&format!("/test/{:05}", i));
+            cache.record(&(key.clone()..=key), Timestamp(i + 1));
+        }
+        assert!(cache.spans.len() <= MAX_SPANS / 2 + 1);
+        assert!(cache.floor > Timestamp::mintime());
+        for i in 1..=MAX_SPANS as u64 + 1 {
+            assert!(cache.max_read(&path(&format!("/test/{:05}", i))) >= Timestamp(i + 1));
+        }
+        assert_eq!(cache.max_read(&path("/other")), cache.floor);
+    }
+}
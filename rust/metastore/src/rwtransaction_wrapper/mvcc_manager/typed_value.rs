@@ -1,40 +1,159 @@
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
 
-impl TypedValue {
-    pub fn as_str(&self) -> &str {
-        match self {
-            TypedValue::String(s) => s,
-            _ => panic!(),
-        }
-    }
-}
+// New variants go at the end, so values that were already serialized keep their variant index.
+// `==` and the ordering compare numbers by value, so `Int(1) == Number(1.0)` even though they're stored differently.
+// Use `is_identical` to tell them apart.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TypedValue {
     String(String),
     Number(f64),
+    // Tombstone of a deleted key. Unlike `Null`, the key doesn't exist anymore.
     Deleted,
+    Bool(bool),
+    Int(i64),
+    Bytes(Vec<u8>),
+    // A key that exists, but holds no value.
+    Null,
 }
 
-impl Display for TypedValue {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl TypedValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            TypedValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+    // Both `Number` and `Int` are numbers. Large integers may be rounded.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            TypedValue::Number(n) => Some(*n),
+            TypedValue::Int(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            TypedValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            TypedValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            TypedValue::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+    pub fn is_null(&self) -> bool {
+        matches!(self, TypedValue::Null)
+    }
+    pub fn is_deleted(&self) -> bool {
+        matches!(self, TypedValue::Deleted)
+    }
+
+    // Same type and same bits. Unlike `==`, `Int(1)` isn't `Number(1.0)`, `-0.0` isn't `0.0` and a NaN is itself.
+    pub fn is_identical(&self, other: &Self) -> bool {
+        use TypedValue::*;
+        match (self, other) {
+            (Number(n), Number(o)) => n.to_bits() == o.to_bits(),
+            (Int(i), Int(o)) => i == o,
+            (String(s), String(o)) => s == o,
+            (Bool(b), Bool(o)) => b == o,
+            (Bytes(b), Bytes(o)) => b == o,
+            (Null, Null) | (Deleted, Deleted) => true,
+            _ => false,
+        }
+    }
+
+    // Values of different types are ordered by their type, in this order. `Number` and `Int` are the same type.
+    fn type_rank(&self) -> u8 {
         use TypedValue::*;
         match self {
-            String(s) => f.write_str(s),
-            Number(a) => a.fmt(f),
-            Deleted => f.write_str("null"),
+            Null => 0,
+            Bool(_) => 1,
+            Int(_) | Number(_) => 2,
+            String(_) => 3,
+            Bytes(_) => 4,
+            Deleted => 5,
         }
     }
 }
 
+// Orders floats by value, with -0.0 equal to 0.0 and NaNs before (if negative) or after all other numbers.
+fn cmp_f64(a: f64, b: f64) -> Ordering {
+    a.partial_cmp(&b).unwrap_or_else(|| a.total_cmp(&b))
+}
+
+// Compares exactly, `i as f64` could round.
+fn cmp_int_f64(i: i64, f: f64) -> Ordering {
+    // 2^63, the first float past i64::MAX.
+    const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+    if f.is_nan() {
+        return cmp_f64(i as f64, f);
+    }
+    if f >= LIMIT {
+        return Ordering::Less;
+    }
+    if f < -LIMIT {
+        return Ordering::Greater;
+    }
+    // In range, so the integer part converts exactly.
+    i.cmp(&(f.trunc() as i64))
+        .then_with(|| cmp_f64(0.0, f.fract()))
+}
+
+// A total order over all values, so they can be sorted and compared whatever their types.
+// Numbers compare by value, so `Int(1)` equals `Number(1.0)`.
+impl Ord for TypedValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        use TypedValue::*;
+        match (self, other) {
+            (String(s), String(o)) => s.cmp(o),
+            (Number(n), Number(o)) => cmp_f64(*n, *o),
+            (Int(i), Int(o)) => i.cmp(o),
+            (Int(i), Number(n)) => cmp_int_f64(*i, *n),
+            (Number(n), Int(i)) => cmp_int_f64(*i, *n).reverse(),
+            (Bool(b), Bool(o)) => b.cmp(o),
+            (Bytes(b), Bytes(o)) => b.cmp(o),
+            (Null, Null) | (Deleted, Deleted) => Ordering::Equal,
+            _ => self.type_rank().cmp(&other.type_rank()),
+        }
+    }
+}
+
+impl PartialOrd for TypedValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Consistent with `Ord`, so `Int` and `Number` holding the same value are equal.
 impl PartialEq for TypedValue {
     fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TypedValue {}
+
+impl Display for TypedValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         use TypedValue::*;
-        match (self, other) {
-            (String(s), String(o)) => s == o,
-            (Number(n), Number(o)) => (n - o).abs().le(&0.00001f64),
-            (Deleted, Deleted) => true,
-            _ => panic!("Can't compare two values of different types"),
+        match self {
+            String(s) => f.write_str(s),
+            Number(a) => a.fmt(f),
+            Int(a) => a.fmt(f),
+            Bool(a) => a.fmt(f),
+            Bytes(bytes) => bytes.iter().try_for_each(|b| write!(f, "{:02x}", b)),
+            Deleted | Null => f.write_str("null"),
         }
     }
 }
@@ -45,6 +164,24 @@ impl From<f64> for TypedValue {
     }
 }
 
+impl From<i64> for TypedValue {
+    fn from(a: i64) -> Self {
+        Self::Int(a)
+    }
+}
+
+impl From<bool> for TypedValue {
+    fn from(a: bool) -> Self {
+        Self::Bool(a)
+    }
+}
+
+impl From<Vec<u8>> for TypedValue {
+    fn from(a: Vec<u8>) -> Self {
+        Self::Bytes(a)
+    }
+}
+
 impl From<std::string::String> for TypedValue {
     fn from(a: std::string::String) -> Self {
         Self::String(a)
@@ -57,11 +194,70 @@ impl<'a> From<&'a str> for TypedValue {
     }
 }
 
-impl From<TypedValue> for String {
-    fn from(a: TypedValue) -> Self {
+// Hands the value back if it isn't a string.
+impl TryFrom<TypedValue> for String {
+    type Error = TypedValue;
+
+    fn try_from(a: TypedValue) -> Result<Self, TypedValue> {
         match a {
-            TypedValue::String(s) => s,
-            _ => panic!(),
+            TypedValue::String(s) => Ok(s),
+            other => Err(other),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_across_types() {
+        let sorted: Vec<TypedValue> = vec![
+            TypedValue::Null,
+            false.into(),
+            true.into(),
+            f64::NEG_INFINITY.into(),
+            (-3i64).into(),
+            (-2.5).into(),
+            0i64.into(),
+            0.5.into(),
+            i64::MAX.into(),
+            9.3e18.into(),
+            f64::NAN.into(),
+            "".into(),
+            "a".into(),
+            vec![0u8].into(),
+            TypedValue::Deleted,
+        ];
+        for (i, a) in sorted.iter().enumerate() {
+            for (j, b) in sorted.iter().enumerate() {
+                assert_eq!(a.cmp(b), i.cmp(&j), "{:?} {:?}", a, b);
+                assert_eq!(a.partial_cmp(b), Some(i.cmp(&j)), "{:?} {:?}", a, b);
+            }
+        }
+
+        assert_eq!(TypedValue::Int(1), TypedValue::Number(1.0));
+        assert_eq!(TypedValue::Number(-0.0), TypedValue::Number(0.0));
+        assert_ne!(TypedValue::from("1"), TypedValue::Int(1));
+        assert_ne!(TypedValue::Null, TypedValue::Deleted);
+    }
+
+    #[test]
+    fn accessors_dont_panic() {
+        assert_eq!(TypedValue::from("a").as_str(), Some("a"));
+        assert_eq!(TypedValue::Int(1).as_str(), None);
+        assert_eq!(TypedValue::Int(2).as_f64(), Some(2.0));
+        assert_eq!(TypedValue::Number(2.0).as_i64(), None);
+        assert_eq!(TypedValue::Bool(true).as_bool(), Some(true));
+        assert_eq!(TypedValue::from(vec![1, 2]).as_bytes(), Some(&[1u8, 2][..]));
+        assert!(TypedValue::Null.is_null() && !TypedValue::Null.is_deleted());
+        assert_eq!(
+            String::try_from(TypedValue::Int(1)),
+            Err(TypedValue::Int(1))
+        );
+        assert!(TypedValue::Number(f64::NAN).is_identical(&TypedValue::Number(f64::NAN)));
+        assert!(!TypedValue::Int(1).is_identical(&TypedValue::Number(1.0)));
+        assert!(!TypedValue::Number(-0.0).is_identical(&TypedValue::Number(0.0)));
+        assert_eq!(TypedValue::from(vec![0, 255]).to_string(), "00ff");
+    }
+}
@@ -3,10 +3,11 @@ use std::marker::PhantomData;
 
 use super::mvcc_metadata::WriteIntentError;
 use super::typed_value::TypedValue;
-use super::{LockDataRef, ReadError, WriteIntent};
+use super::{LockDataRef, WriteIntent, WriteIntentStatus};
+use crate::isolation::ReadView;
 use crate::rwtransaction_wrapper::{MVCCMetadata, MutBTreeMap};
 use crate::timestamp::Timestamp;
-use crate::DbContext;
+use crate::{DbContext, TxnError};
 
 type MyMutex<T> = parking_lot::Mutex<T>;
 type Guard<'a, T> = parking_lot::MutexGuard<'a, T>;
@@ -183,30 +184,45 @@ fn fixup_write_intents(
 }
 
 impl ValueWithMVCC {
-    pub fn clear_committed_intent(&self, txn: LockDataRef) {
-        self.meta.cur_write_intent.compare_swap_none(
-            Some(WriteIntent {
-                associated_transaction: txn,
-                was_commited: false,
-            }),
-            None,
-        );
+    // Clears (if `txn` committed) or rolls back (if it aborted) the write intent that `txn` left on this value.
+    // Does nothing if the intent is already gone, e.g. because a reader resolved it first.
+    // Takes `&mut self`, see `MutBTreeMap::with_value_mut`.
+    pub(crate) fn resolve_intent_of(&mut self, ctx: &DbContext, txn: LockDataRef, status: WriteIntentStatus) {
+        let wi = match self.meta.get_write_intents() {
+            Some(wi) if wi.associated_transaction == txn => wi,
+            _ => return,
+        };
+        match status {
+            WriteIntentStatus::Committed => self
+                .meta
+                .cur_write_intent
+                .compare_swap_none(Some(wi), None)
+                .unwrap(),
+            WriteIntentStatus::Aborted => rescue_previous_value(&mut self.meta, &mut self.val, ctx),
+            WriteIntentStatus::Pending | WriteIntentStatus::Prepared => {}
+        }
+    }
+    // Puts back a value that `txn` wrote to this key earlier, undoing its writes since. `txn` keeps its intent.
+    // Like `resolve_intent_of`, takes `&mut self`.
+    pub(crate) fn restore_own_write(&mut self, txn: LockDataRef, value: TypedValue) {
+        match self.meta.get_write_intents() {
+            Some(wi) if wi.associated_transaction == txn => self.val = value,
+            _ => {}
+        }
     }
     pub fn get_mvcc_copy(&self) -> MVCCMetadata {
         let _l = self.lock.lock();
         self.meta.clone()
     }
 
-    pub fn check_read(&self, ctx: &DbContext, txn: LockDataRef) -> Result<(), ReadError> {
-        self.meta
-            .check_read(&ctx.transaction_map, txn)
-            .map_err(ReadError::Other)?;
+    pub fn check_read(&self, ctx: &DbContext, txn: LockDataRef) -> Result<(), TxnError> {
+        self.meta.check_read(&ctx.transaction_map, txn)?;
 
         if MutBTreeMap::is_deleated(&self.val) {
             // On the second restart, they will be blocked by the MutBtreemap from reading this value.
             // Special case here because after we "fixed the abort/commit intents," this ValueWithMVCC doesn't
             // go through the MutBtreemap code path again to be checked.
-            Err(ReadError::ValueNotFound)
+            Err(TxnError::NotFound)
         } else {
             Ok(())
         }
@@ -217,15 +233,15 @@ impl ValueWithMVCC {
         &self,
         ctx: &DbContext,
         txn: LockDataRef,
-    ) -> Result<UnlockedReadableMVCC<'_>, ReadError> {
+    ) -> Result<UnlockedReadableMVCC<'_>, TxnError> {
         let lock = self.lock.lock();
 
         // We guarantee there's no other threads accessing this value because we hold the lock.
         let mut_meta = unsafe { &mut *(&self.meta as *const MVCCMetadata as *mut MVCCMetadata) };
         let mut_val = unsafe { &mut *(&self.val as *const TypedValue as *mut TypedValue) };
-        fixup_write_intents(mut_meta, mut_val, ctx, txn)
-            .map_err(|a| ReadError::Other(a.tostring()))?;
-        self.check_read(ctx, txn)?;
+        fixup_write_intents(mut_meta, mut_val, ctx, txn)?;
+        // Unlike `check_read`, a deleted value is readable here: the caller decides what a tombstone means.
+        self.meta.check_read(&ctx.transaction_map, txn)?;
         Ok(UnlockedReadableMVCC {
             meta: mut_meta,
             val: mut_val,
@@ -237,10 +253,11 @@ impl ValueWithMVCC {
     pub(super) fn get_writable<'a>(
         &'a self,
         txn: LockDataRef,
+        view: &ReadView,
         readable: UnlockedReadableMVCC<'a>,
-    ) -> Result<UnlockedWritableMVCC<'a>, String> {
+    ) -> Result<UnlockedWritableMVCC<'a>, TxnError> {
         let lock = readable.lock;
-        self.meta.check_write(txn)?;
+        self.meta.check_write(txn, view)?;
 
         let writable = UnlockedWritableMVCC {
             meta_ptr: &self.meta as *const MVCCMetadata as *mut MVCCMetadata,
@@ -269,6 +286,36 @@ impl ValueWithMVCC {
 
         Ok(writable)
     }
+    // Clears the write intent if its transaction has committed, or rolls the value back if it has aborted.
+    // Returns the transaction whose intent is still on the value afterwards, which must still be pending or prepared.
+    // Only for callers that have exclusive access to the whole database.
+    pub(crate) fn resolve_intent(&mut self, ctx: &DbContext) -> Option<LockDataRef> {
+        let wi = self.meta.get_write_intents()?;
+        let status = ctx
+            .transaction_map
+            .get_by_ref(&wi.associated_transaction)
+            .map_or(WriteIntentStatus::Aborted, |data| data.get_write_intent());
+        match status {
+            WriteIntentStatus::Pending | WriteIntentStatus::Prepared => {
+                Some(wi.associated_transaction)
+            }
+            WriteIntentStatus::Committed => {
+                self.meta
+                    .cur_write_intent
+                    .compare_swap_none(Some(wi), None)
+                    .unwrap();
+                None
+            }
+            WriteIntentStatus::Aborted => {
+                rescue_previous_value(&mut self.meta, &mut self.val, ctx);
+                None
+            }
+        }
+    }
+    // Only for callers that have exclusive access to the whole database (e.g. vacuum).
+    pub(crate) fn clear_prev_mvcc(&mut self) {
+        self.meta.clear_prev_mvcc();
+    }
     pub fn as_inner(&self) -> (MVCCMetadata, &TypedValue) {
         let _l = self.lock.lock();
         (self.meta.clone(), &self.val)
@@ -285,7 +332,7 @@ mod tests {
     fn test_fix() {
         let db = db!("adfs" = "value");
         let mut write = ReplicatedTxn::new(&db);
-        write.write(&"adfs".into(), "fdsvcx".into());
+        write.write(&"adfs".into(), "fdsvcx".into()).unwrap();
         write.abort();
 
         let mut read = ReplicatedTxn::new(&db);
@@ -296,12 +343,12 @@ mod tests {
     fn test_abort_2() {
         let db = db!("adfs" = "value");
         let mut write = ReplicatedTxn::new(&db);
-        write.write(&"adfs".into(), "fdsvcx".into());
+        write.write(&"adfs".into(), "fdsvcx".into()).unwrap();
         write.abort();
 
         let mut write = ReplicatedTxn::new(&db);
-        write.write(&"adfs".into(), "value2".into());
-        write.commit();
+        write.write(&"adfs".into(), "value2".into()).unwrap();
+        write.commit().unwrap();
 
         let mut read = ReplicatedTxn::new(&db);
         assert_eq!(read.read(&"adfs".into()).unwrap(), "value2".into());
@@ -313,7 +360,7 @@ mod tests {
 
         for _ in 0..20 {
             let mut write = ReplicatedTxn::new(&db);
-            write.write(&"adfs".into(), "fdsvcx".into());
+            write.write(&"adfs".into(), "fdsvcx".into()).unwrap();
             write.abort();
         }
 
@@ -321,8 +368,8 @@ mod tests {
         assert_eq!(read.read(&"adfs".into()).unwrap(), "value".into());
 
         let mut write = ReplicatedTxn::new(&db);
-        write.write(&"adfs".into(), "value2".into());
-        write.commit();
+        write.write(&"adfs".into(), "value2".into()).unwrap();
+        write.commit().unwrap();
 
         let mut read = ReplicatedTxn::new(&db);
         assert_eq!(read.read(&"adfs".into()).unwrap(), "value2".into());
@@ -336,13 +383,13 @@ mod tests {
         for i in begin_time..begin_time + 10 {
             let mut write = ReplicatedTxn::new_with_time(&db, Timestamp::from(i));
             write.write(&"k".into(), i.to_string().into()).unwrap();
-            write.commit();
+            write.commit().unwrap();
         }
 
         for i in begin_time..begin_time + 10 {
             let mut read = ReplicatedTxn::new_with_time(&db, Timestamp::from(i));
             assert_eq!(read.read(&"k".into()).unwrap(), i.to_string().into());
-            read.commit();
+            read.commit().unwrap();
         }
 
         // db.wallog.borrow().print();
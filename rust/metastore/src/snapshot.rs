@@ -0,0 +1,182 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter};
+use std::ops::Bound;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::db_context::create_empty_context;
+use crate::checkpoint::write_line;
+use crate::object_path::ObjectPath;
+use crate::rwtransaction_wrapper::{read_reference, IntentMap, Transaction};
+use crate::timestamp::Timestamp;
+use crate::{DbContext, TxnError, TypedValue};
+
+const FORMAT_VERSION: u32 = 1;
+
+// A snapshot is a newline separated JSON file:
+//   line 1:   SnapshotHeader
+//   line 2..: one (key, value) pair per key that is live at the snapshot time, in key order
+// Unlike the data file written by `CheckpointStore`, it has no MVCC metadata or old versions, only the values.
+#[derive(Serialize, Deserialize)]
+struct SnapshotHeader {
+    version: u32,
+    time: Timestamp,
+}
+
+// Writes every key visible at `time` to `path` and returns the number of keys written.
+// Reading the keys follows the usual MVCC rules, so writers older than `time` can't change what the snapshot saw
+// after the fact, and the snapshot fails if one of them still has a pending write intent on a key.
+pub fn write<P: AsRef<Path>>(ctx: &DbContext, time: Timestamp, path: P) -> Result<usize, String> {
+    let txn = IntentMap::generate_read_txn_with_time(time);
+    let path = path.as_ref();
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path).map_err(|err| err.to_string())?);
+    write_line(
+        &mut writer,
+        &SnapshotHeader {
+            version: FORMAT_VERSION,
+            time,
+        },
+    )?;
+
+    // Every page is recorded as read at `time` while it's locked, so keys inserted under it later can't have
+    // belonged in the snapshot.
+    let mut count = 0;
+    ctx.db.scan_pages(
+        (Bound::Unbounded, Bound::Unbounded),
+        false,
+        |key, value| {
+            match read_reference(ctx, value, txn) {
+                Ok(value) => {
+                    write_line(&mut writer, &(key, value.get_val()))?;
+                    count += 1;
+                }
+                // Didn't exist yet or was deleted at `time`.
+                Err(TxnError::NotFound) => {}
+                Err(err) => return Err(format!("Can't snapshot {}: {}", key.as_str(), err)),
+            }
+            Ok(true)
+        },
+        |page| ctx.db.record_range_read(page, time),
+    )?;
+
+    let file = writer.into_inner().map_err(|err| err.to_string())?;
+    file.sync_all().map_err(|err| err.to_string())?;
+    std::fs::rename(&tmp_path, path).map_err(|err| err.to_string())?;
+    Ok(count)
+}
+
+// Creates a new in-memory database containing the keys of the snapshot at `path`.
+// All keys are written by a single transaction at the snapshot's timestamp, so reads before it see nothing.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<DbContext, String> {
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let mut lines = BufReader::new(file).lines();
+
+    let header = lines
+        .next()
+        .ok_or_else(|| "Snapshot is missing its header".to_string())?
+        .map_err(|err| err.to_string())?;
+    let header: SnapshotHeader = serde_json::from_str(&header).map_err(|err| err.to_string())?;
+    if header.version != FORMAT_VERSION {
+        return Err(format!("Unsupported snapshot version {}", header.version));
+    }
+
+    let ctx = create_empty_context();
+    let mut txn = Transaction::new_with_time(&ctx, header.time);
+    for line in lines {
+        let line = line.map_err(|err| err.to_string())?;
+        let (key, value): (ObjectPath, TypedValue) =
+            serde_json::from_str(&line).map_err(|err| err.to_string())?;
+        txn.write(&ctx, &key, value)?;
+    }
+    txn.commit(&ctx)?;
+
+    Timestamp::advance_past(header.time);
+    Ok(ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::db_context::create_empty_context;
+    use crate::rwtransaction_wrapper::Transaction;
+    use crate::timestamp::Timestamp;
+    use crate::{DbContext, TypedValue};
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("metastore-snapshot-{}", rand::random::<u64>()))
+    }
+
+    fn write(ctx: &DbContext, kv: &[(&str, TypedValue)]) -> Timestamp {
+        let time = Timestamp::now();
+        let mut txn = Transaction::new_with_time(ctx, time);
+        for (k, v) in kv {
+            txn.write(ctx, &(*k).into(), v.clone()).unwrap();
+        }
+        txn.commit(ctx).unwrap();
+        time
+    }
+
+    fn read(ctx: &DbContext, key: &str) -> Option<TypedValue> {
+        let mut txn = Transaction::new_with_time(ctx, Timestamp::now());
+        txn.read_mvcc(ctx, &key.into())
+            .ok()
+            .map(|a| a.into_inner().1)
+    }
+
+    #[test]
+    fn snapshot_at_timestamp() {
+        let ctx = create_empty_context();
+        let first = write(&ctx, &[("/test/a", "1".into()), ("/test/d", "5".into())]);
+        let second = write(&ctx, &[("/test/a", "2".into()), ("/test/b", "3".into())]);
+        write(&ctx, &[("/test/d", TypedValue::Deleted)]);
+        let after_delete = Timestamp::now();
+        write(&ctx, &[("/test/c", "4".into())]);
+
+        let path = temp_path();
+        assert_eq!(ctx.snapshot_at(after_delete, &path).unwrap(), 2);
+        let loaded = DbContext::from_snapshot(&path).unwrap();
+        assert_eq!(read(&loaded, "/test/a"), Some("2".into()));
+        assert_eq!(read(&loaded, "/test/b"), Some("3".into()));
+        assert_eq!(read(&loaded, "/test/c"), None);
+        assert_eq!(read(&loaded, "/test/d"), None);
+
+        assert_eq!(ctx.snapshot_at(second - Timestamp(1), &path).unwrap(), 2);
+        let loaded = DbContext::from_snapshot(&path).unwrap();
+        assert_eq!(read(&loaded, "/test/a"), Some("1".into()));
+        assert_eq!(read(&loaded, "/test/b"), None);
+        assert_eq!(read(&loaded, "/test/d"), Some("5".into()));
+
+        let mut before = Transaction::new_with_time(&loaded, first);
+        assert_matches!(before.read_mvcc(&loaded, &"/test/a".into()), Err(..));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn snapshot_is_not_changed_by_older_writers() {
+        let ctx = create_empty_context();
+        write(&ctx, &[("/test/a", "1".into())]);
+
+        let pending_time = Timestamp::now();
+        let mut pending = Transaction::new_with_time(&ctx, pending_time);
+        pending.write(&ctx, &"/test/a".into(), "2".into()).unwrap();
+
+        let path = temp_path();
+        let time = Timestamp::now();
+        assert_matches!(ctx.snapshot_at(time, &path), Err(..));
+        pending.commit(&ctx).unwrap();
+        assert_eq!(ctx.snapshot_at(time, &path).unwrap(), 1);
+
+        // The snapshot saw "/test/a" as "2" at `time`, so an older writer can't change it anymore.
+        let mut late = Transaction::new_with_time(&ctx, pending_time + Timestamp(1));
+        assert_matches!(late.write(&ctx, &"/test/a".into(), "3".into()), Err(..));
+
+        let loaded = DbContext::from_snapshot(&path).unwrap();
+        assert_eq!(read(&loaded, "/test/a"), Some("2".into()));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -19,6 +19,7 @@ pub mod tests {
         let mut txn = ReplicatedTxn::new(&*ctx1);
         txn.write(&ObjectPath::from(format!("/test/{}", "1")), "1".into())
             .unwrap();
+        txn.write(&ObjectPath::new("/counter"), 1i64.into()).unwrap();
         txn.commit().unwrap();
 
         let clos = |ctx: Arc<DbContext>| {
@@ -30,16 +31,11 @@ pub mod tests {
 
             while i < 500 {
                 let mut txn = ReplicatedTxn::new(ctx);
-                let range = txn.read_range_owned(&ObjectPath::new("/test/"));
+                // Only the counter is read, so transactions only conflict on it.
+                let next = txn.increment(&ObjectPath::new("/counter"), 1i64.into());
 
-                if let Ok(range) = range {
-                    let max = range
-                        .into_iter()
-                        .map(|(_, v)| v.get_val().as_str().parse::<u64>().unwrap())
-                        .max()
-                        .unwrap()
-                        + 1;
-                    let maxnumstr = max.to_string();
+                if let Ok(next) = next {
+                    let maxnumstr = next.to_string();
 
                     let first = txn.write(
                         &ObjectPath::from(format!("/test/{}", maxnumstr)),
@@ -78,7 +74,7 @@ pub mod tests {
                         println!("progress {}", i);
                     }
                 } else {
-                    // println!("transaction conflict {}, {:?}", range.unwrap_err(), txn.get_txn().timestamp);
+                    // println!("transaction conflict {}, {:?}", next.unwrap_err(), txn.get_txn().timestamp);
                     std::thread::sleep(Duration::from_millis(2));
                 }
             }
@@ -105,7 +101,7 @@ pub mod tests {
             .unwrap()
             .iter()
             .all(|(_, v)| {
-                let val = v.get_val().as_str().parse::<u64>().unwrap();
+                let val = v.get_val().as_str().unwrap().parse::<u64>().unwrap();
                 assert!(val < BADVALUE);
                 uniq.insert(val)
             }));
@@ -132,7 +128,7 @@ pub mod tests {
                 let len = range.len();
                 let max = range
                     .into_iter()
-                    .map(|(_, v)| v.get_val().as_str().parse::<u64>().unwrap())
+                    .map(|(_, v)| v.get_val().as_str().unwrap().parse::<u64>().unwrap())
                     .max()
                     .unwrap_or(0)
                     + 1;
@@ -172,8 +168,8 @@ pub mod tests {
             for mut elem in a {
                 values_tested += 1;
                 let val = elem.1.into_inner();
-                if val.1.as_str().parse::<i64>().unwrap()
-                    <= prevvalue.as_str().parse::<i64>().unwrap()
+                if val.1.as_str().unwrap().parse::<i64>().unwrap()
+                    <= prevvalue.as_str().unwrap().parse::<i64>().unwrap()
                 {
                     println!("{:?}", a_);
                     panic!()
@@ -256,6 +252,7 @@ pub mod tests_walwatcher {
     use crate::rwtransaction_wrapper::auto_commit;
     use crate::rwtransaction_wrapper::{ReplicatedTxn, TypedValue};
     use crate::wal_watcher::wal_check_consistency::check_func;
+    use crate::TxnError;
 
     static COM: AtomicU64 = AtomicU64::new(0);
     static FAIL: AtomicU64 = AtomicU64::new(0);
@@ -269,8 +266,8 @@ pub mod tests_walwatcher {
             let mut iters = 0;
             while iters < n {
                 let mut txn = ReplicatedTxn::new(&db);
-                let res: Result<(), String> = try {
-                    let value = txn.read(&"k".into())?.as_str().parse::<u64>().unwrap() + 1;
+                let res: Result<(), TxnError> = try {
+                    let value = txn.read(&"k".into())?.as_str().unwrap().parse::<u64>().unwrap() + 1;
                     txn.write(&"k".into(), TypedValue::from("invalid value".to_string()))?;
                     txn.write(&"k".into(), value.to_string().into())?;
                 };
@@ -299,7 +296,7 @@ pub mod tests_walwatcher {
                 .into_inner()
                 .1
                 .as_str(),
-            "2500"
+            Some("2500")
         );
     }
 
@@ -326,20 +323,15 @@ pub mod tests_walwatcher {
                 let mut all_good = true;
                 for _ in 0..10 {
                     let res = txn.read(&key).and_then(|str| {
-                        let val = str.as_str().parse::<u64>().unwrap() + 1;
+                        let val = str.as_str().unwrap().parse::<u64>().unwrap() + 1;
                         txn.write(&key.as_str().into(), TypedValue::from(val.to_string()))
                     });
 
                     let res = match res {
-                        Err(err) => {
-                            if err == *"Other(\"Read value doesn't exist\")" {
-                                txn.write(&key.as_str().into(), TypedValue::from("1"))
-                                    .map(|_| ())
-                            } else {
-                                Err(format!("Txn error {}", err))
-                            }
+                        Err(TxnError::NotFound) => {
+                            txn.write(&key.as_str().into(), TypedValue::from("1"))
                         }
-                        _ => Ok(()),
+                        res => res,
                     };
                     all_good &= res.is_ok();
 
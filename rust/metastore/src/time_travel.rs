@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+
+use crate::object_path::ObjectPath;
+use crate::read_only_txn::ReadOnlyTxn;
+use crate::rwtransaction_wrapper::{ValueWithMVCC, WriteIntentStatus};
+use crate::timestamp::Timestamp;
+use crate::{DbContext, TxnError, TypedValue};
+
+// One committed version of a key. `end` is `Timestamp::maxtime()` for the current version.
+// A deleted key shows up as a version whose value is `TypedValue::Deleted`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Version {
+    pub begin: Timestamp,
+    pub end: Timestamp,
+    pub value: TypedValue,
+}
+
+impl Version {
+    fn from_value(value: &ValueWithMVCC) -> Self {
+        let (meta, value) = value.as_inner();
+        Self {
+            begin: meta.get_beg_time(),
+            end: meta.get_end_time(),
+            value: value.clone(),
+        }
+    }
+}
+
+pub fn read_as_of(ctx: &DbContext, key: &ObjectPath, time: Timestamp) -> Result<TypedValue, TxnError> {
+    ReadOnlyTxn::at(ctx, time)?.read(key)
+}
+
+pub fn range_as_of(
+    ctx: &DbContext,
+    prefix: &ObjectPath,
+    time: Timestamp,
+) -> Result<Vec<(ObjectPath, TypedValue)>, TxnError> {
+    let range = ReadOnlyTxn::at(ctx, time)?.read_range_owned(prefix)?;
+    Ok(range
+        .into_iter()
+        .map(|(key, value)| (key, value.into_inner().1))
+        .collect())
+}
+
+// Every committed version of `key` that vacuum hasn't removed yet, oldest first.
+pub fn history(ctx: &DbContext, key: &ObjectPath) -> Vec<Version> {
+    let mut lock = ctx.db.get_raw_with_lock(key);
+    let value = match lock.value() {
+        Some(value) => value,
+        None => return Vec::new(),
+    };
+
+    let mut versions = Vec::new();
+    let meta = value.as_inner().0;
+    // The latest version may still be a write that hasn't committed (or was aborted and not rolled back yet).
+    let latest_committed = meta.get_write_intents().map_or(true, |wi| {
+        matches!(
+            ctx.transaction_map
+                .get_by_ref(&wi.associated_transaction)
+                .map(|data| data.get_write_intent()),
+            Some(WriteIntentStatus::Committed)
+        )
+    });
+    if latest_committed {
+        versions.push(Version::from_value(value));
+    }
+
+    let mut next = meta.get_prev_mvcc(ctx).ok();
+    while let Some(version) = next {
+        versions.push(Version::from_value(version));
+        next = version.as_inner().0.get_prev_mvcc(ctx).ok();
+    }
+    versions.reverse();
+    // A tombstone with nothing before it is left behind by an insert that got rolled back.
+    if matches!(versions.first(), Some(Version { value: TypedValue::Deleted, .. })) {
+        versions.remove(0);
+    }
+    versions
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::time_travel::Version;
+    use crate::timestamp::Timestamp;
+    use crate::{ReplicatedTxn, TxnError, TypedValue};
+
+    #[test]
+    fn reads_old_versions() {
+        let db = db!("/test/a" = "1", "/test/b" = "1");
+        let first = Timestamp::now();
+        let mut times = Vec::new();
+        for i in 2..5 {
+            let mut txn = ReplicatedTxn::new(&db);
+            txn.write(&"/test/a".into(), i.to_string().into()).unwrap();
+            if i == 3 {
+                txn.write(&"/test/b".into(), TypedValue::Deleted).unwrap();
+            }
+            times.push(txn.get_txn().timestamp);
+            txn.commit().unwrap();
+        }
+
+        assert_eq!(db.read_as_of(&"/test/a".into(), first), Ok("1".into()));
+        assert_eq!(db.read_as_of(&"/test/a".into(), times[1]), Ok("3".into()));
+        assert_eq!(
+            db.read_as_of(&"/test/b".into(), times[1]),
+            Err(TxnError::NotFound)
+        );
+        assert_eq!(
+            db.range_as_of(&"/test/".into(), times[0]),
+            Ok(vec![("/test/a".into(), "2".into()), ("/test/b".into(), "1".into())])
+        );
+        assert_eq!(
+            db.range_as_of(&"/test/".into(), times[2]),
+            Ok(vec![("/test/a".into(), "4".into())])
+        );
+    }
+
+    #[test]
+    fn history_lists_committed_versions() {
+        let db = db!("/test/a" = "1");
+        let mut times = Vec::new();
+        for i in 2..4 {
+            let mut txn = ReplicatedTxn::new(&db);
+            txn.write(&"/test/a".into(), i.to_string().into()).unwrap();
+            times.push(txn.get_txn().timestamp);
+            txn.commit().unwrap();
+        }
+        let mut pending = ReplicatedTxn::new(&db);
+        pending.write(&"/test/a".into(), "4".into()).unwrap();
+
+        let values: Vec<TypedValue> = db
+            .history(&"/test/a".into())
+            .into_iter()
+            .map(|v| v.value)
+            .collect();
+        assert_eq!(values, vec!["1".into(), "2".into(), "3".into()]);
+        pending.abort();
+
+        let history = db.history(&"/test/a".into());
+        assert_eq!(history.len(), 3);
+        assert_eq!(
+            history[1..].to_vec(),
+            vec![
+                Version {
+                    begin: times[0],
+                    end: times[1],
+                    value: "2".into()
+                },
+                Version {
+                    begin: times[1],
+                    end: Timestamp::maxtime(),
+                    value: "3".into()
+                },
+            ]
+        );
+        assert!(db.history(&"/test/b".into()).is_empty());
+    }
+
+    #[test]
+    fn cant_read_before_the_vacuum_watermark() {
+        let db = db!("/test/a" = "1");
+        let first = Timestamp::now();
+        let mut txn = ReplicatedTxn::new(&db);
+        txn.write(&"/test/a".into(), "2".into()).unwrap();
+        txn.commit().unwrap();
+
+        let watermark = db.vacuum().watermark;
+        assert!(watermark > first);
+        assert_eq!(db.read_as_of(&"/test/a".into(), first), Err(TxnError::SnapshotTooOld));
+        assert_eq!(db.range_as_of(&"/test/".into(), first), Err(TxnError::SnapshotTooOld));
+        assert_eq!(db.read_as_of(&"/test/a".into(), watermark), Ok("2".into()));
+    }
+}
@@ -50,6 +50,14 @@ impl Timestamp {
     pub fn now() -> Self {
         Self(MONOTIC_COUNTER.fetch_add(10, AtomicOrdering::SeqCst))
     }
+
+    // Makes sure every future call to `now()` returns a timestamp strictly larger than `time`.
+    // Used when loading data written by a previous process.
+    pub fn advance_past(time: Timestamp) {
+        if time != Self::maxtime() {
+            MONOTIC_COUNTER.fetch_max(time.0 + 10, AtomicOrdering::SeqCst);
+        }
+    }
 }
 
 impl ToString for Timestamp {
@@ -149,7 +149,13 @@ use serde_json::Number;
 fn typed_value_to_json(t: TypedValue) -> Option<Value> {
     match t {
         TypedValue::String(s) => Some(Value::String(s)),
-        TypedValue::Number(s) => Some(Value::Number(Number::from_f64(s).unwrap())),
+        // JSON has no NaN or infinity.
+        TypedValue::Number(s) => Some(Number::from_f64(s).map_or(Value::Null, Value::Number)),
+        TypedValue::Int(i) => Some(Value::Number(i.into())),
+        TypedValue::Bool(b) => Some(Value::Bool(b)),
+        // As a hex string.
+        TypedValue::Bytes(_) => Some(Value::String(t.to_string())),
+        TypedValue::Null => Some(Value::Null),
         TypedValue::Deleted => None,
     }
 }
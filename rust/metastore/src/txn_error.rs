@@ -0,0 +1,153 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::rpc_handler::NetworkError;
+use crate::rwtransaction_wrapper::{LockDataRef, TypedValue};
+
+// Why a transactional operation failed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TxnError {
+    // The key doesn't exist (or was deleted) at the transaction's timestamp.
+    NotFound,
+    // Another transaction that hasn't finished yet has a write intent on the key.
+    WriteConflict(LockDataRef),
+    // A transaction with a newer timestamp already read or wrote the key, so we can't write to it anymore.
+    ReadTimestampConflict,
+    // Under snapshot isolation, another transaction wrote the key after our snapshot was taken.
+    SnapshotConflict,
+    // Inserting the key would change the result of a range read done by a newer transaction.
+    PhantomDetected,
+    // The read timestamp is older than the vacuum watermark, so the versions it should see may be gone.
+    SnapshotTooOld,
+    // The transaction was aborted before it could commit.
+    Aborted,
+    // The transaction already committed, so it can't be aborted anymore.
+    Committed,
+    // The transaction is prepared for a two-phase commit, so only its coordinator can decide to abort it.
+    Prepared,
+    // Couldn't reach a replica.
+    Network(String),
+    // Couldn't write to the WAL, e.g. because fsync failed or the WAL is frozen.
+    Wal(String),
+    // A replica didn't accept a write the primary accepted, or the other way around. Holds which one: its index
+    // among the replicators, or its address. The transaction was aborted everywhere and the replica flagged, see
+    // `DbContext::diverged_replicas`.
+    ReplicaDiverged(String),
+    // `DbContext::run_transaction` ran out of attempts. Holds the error of the last one.
+    RetriesExhausted(Box<TxnError>),
+    // A conditional write (e.g. `compare_and_set`) didn't write. Holds the value the key had instead, None if it
+    // didn't exist.
+    ConditionFailed(Option<TypedValue>),
+    Other(String),
+}
+
+crate::custom_error_impl!(TxnError);
+
+impl TxnError {
+    // Whether running the whole transaction again (with a new timestamp) may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            TxnError::WriteConflict(..)
+                | TxnError::ReadTimestampConflict
+                | TxnError::SnapshotConflict
+                | TxnError::PhantomDetected
+                | TxnError::SnapshotTooOld
+                | TxnError::Aborted
+        )
+    }
+}
+
+impl Display for TxnError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TxnError::NotFound => f.write_str("Value not found"),
+            TxnError::WriteConflict(txn) => {
+                write!(f, "Write conflict with pending transaction {}", txn.id)
+            }
+            TxnError::ReadTimestampConflict => {
+                f.write_str("Timestamp is older than the latest read or write of the key")
+            }
+            TxnError::SnapshotConflict => {
+                f.write_str("Key was written by another transaction after our snapshot")
+            }
+            TxnError::PhantomDetected => f.write_str("Phantom detected"),
+            TxnError::SnapshotTooOld => {
+                f.write_str("Read timestamp is older than the vacuum watermark")
+            }
+            TxnError::Aborted => f.write_str("Transaction was aborted"),
+            TxnError::Committed => f.write_str("Transaction already committed"),
+            TxnError::Prepared => f.write_str("Transaction is prepared, only its coordinator can abort it"),
+            TxnError::Network(err) => write!(f, "Network error: {}", err),
+            TxnError::Wal(err) => write!(f, "WAL error: {}", err),
+            TxnError::ReplicaDiverged(replica) => {
+                write!(f, "Replica {} disagreed with the primary", replica)
+            }
+            TxnError::RetriesExhausted(err) => write!(f, "Too many retries, last error: {}", err),
+            TxnError::ConditionFailed(Some(value)) => {
+                write!(f, "Condition failed, the current value is {}", value)
+            }
+            TxnError::ConditionFailed(None) => f.write_str("Condition failed, the key doesn't exist"),
+            TxnError::Other(err) => f.write_str(err),
+        }
+    }
+}
+
+impl std::error::Error for TxnError {}
+
+impl From<NetworkError> for TxnError {
+    fn from(err: NetworkError) -> Self {
+        TxnError::Network(err.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rwtransaction_wrapper::ReplicatedTxn;
+    use crate::TxnError;
+
+    #[test]
+    fn conflicts_are_typed() {
+        let db = db!("/test/a" = "a", "/test/c" = "c");
+        let mut w1 = ReplicatedTxn::new(&db);
+        let mut w2 = ReplicatedTxn::new(&db);
+        w1.write(&"/test/a".into(), "1".into()).unwrap();
+        let blocker = *w1.get_txn();
+        assert_eq!(
+            w2.write(&"/test/a".into(), "2".into()),
+            Err(TxnError::WriteConflict(blocker))
+        );
+        assert_eq!(
+            w2.read(&"/test/a".into()),
+            Err(TxnError::WriteConflict(blocker))
+        );
+        assert_eq!(w2.read(&"/test/b".into()), Err(TxnError::NotFound));
+        w1.commit().unwrap();
+        w2.commit().unwrap();
+
+        // A newer transaction read the key, so an older one can't write it anymore.
+        let mut old = ReplicatedTxn::new(&db);
+        let mut new = ReplicatedTxn::new(&db);
+        assert_eq!(new.read(&"/test/c".into()), Ok("c".into()));
+        let err = old.write(&"/test/c".into(), "1".into()).unwrap_err();
+        assert_eq!(err, TxnError::ReadTimestampConflict);
+        assert!(err.is_retryable());
+
+        let _range = new.read_range_owned(&"/test/".into()).unwrap();
+        assert_eq!(
+            old.write(&"/test/b".into(), "1".into()),
+            Err(TxnError::PhantomDetected)
+        );
+        new.commit().unwrap();
+        old.abort();
+    }
+
+    #[test]
+    fn not_found_is_not_retryable() {
+        assert!(!TxnError::NotFound.is_retryable());
+        assert!(!TxnError::Network("down".into()).is_retryable());
+        assert!(TxnError::Aborted.is_retryable());
+        assert!(TxnError::SnapshotTooOld.is_retryable());
+    }
+}
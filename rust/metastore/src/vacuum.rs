@@ -0,0 +1,280 @@
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+
+use crate::read_only_txn::closed_timestamp;
+use crate::rwtransaction_wrapper::{ValueWithMVCC, WriteIntentStatus};
+use crate::timestamp::Timestamp;
+use crate::{DbContext, TypedValue};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VacuumStats {
+    // No reader older than this can be served anymore.
+    pub watermark: Timestamp,
+    pub reclaimed_versions: usize,
+    // Deleted keys that were removed from the database altogether.
+    pub removed_keys: usize,
+    // Keys whose history was left alone because a transaction that hasn't finished yet has an intent on them.
+    pub skipped_keys: usize,
+    // Number of versions per key (including the latest one), after vacuuming.
+    pub max_chain_length: usize,
+    pub avg_chain_length: f64,
+}
+
+// Whether the latest version of this key is final, so rolling it back will never need the older versions.
+fn history_is_final(ctx: &DbContext, value: &ValueWithMVCC) -> bool {
+    match value.as_inner().0.get_write_intents() {
+        None => true,
+        Some(wi) => matches!(
+            ctx.transaction_map
+                .get_by_ref(&wi.associated_transaction)
+                .map(|data| data.get_write_intent()),
+            Some(WriteIntentStatus::Committed)
+        ),
+    }
+}
+
+// Removes old versions from `old_values_store` that no transaction can ever read again.
+// The watermark is the timestamp of the oldest read-only transaction that is still open, or the `closed_timestamp`
+// if that's older, so `ReadOnlyTxn::new` never picks a timestamp below it.
+// For every key, the newest version that began at or before the watermark is kept, because readers at the watermark
+// may still see it, and everything older than it is dropped. If that version is a tombstone, nobody can see the key
+// anymore and it's removed. Runs with the whole database locked.
+pub fn vacuum(ctx: &DbContext) -> VacuumStats {
+    ctx.db.with_exclusive(|map| {
+        // Publish the watermark before a read-only transaction can register an older timestamp.
+        let watermark = ctx.snapshots.with_oldest(|oldest_snapshot| {
+            let watermark = oldest_snapshot
+                .into_iter()
+                .fold(closed_timestamp(ctx), Timestamp::min);
+            ctx.vacuum_watermark.fetch_max(watermark.0, Ordering::SeqCst);
+            watermark
+        });
+
+        let mut skipped_keys = 0;
+        let mut dead_keys = Vec::new();
+        for (key, value) in map.iter_mut() {
+            if !history_is_final(ctx, value) {
+                skipped_keys += 1;
+                continue;
+            }
+
+            let meta = value.as_inner().0;
+            if meta.get_beg_time() <= watermark {
+                value.clear_prev_mvcc();
+                if matches!(value.get_val(), TypedValue::Deleted) {
+                    dead_keys.push(key.clone());
+                }
+                continue;
+            }
+            let mut next = meta.get_prev_mvcc_index();
+            while let Some(index) = next {
+                let version = ctx.old_values_store.get_mut(index);
+                let meta = version.as_inner().0;
+                if meta.get_beg_time() <= watermark {
+                    version.clear_prev_mvcc();
+                    break;
+                }
+                next = meta.get_prev_mvcc_index();
+            }
+        }
+
+        for key in &dead_keys {
+            map.remove(key);
+        }
+
+        // Sweep every version that isn't reachable from a key anymore. This also catches versions that were
+        // orphaned when an aborted write got rolled back.
+        let mut reachable = HashSet::new();
+        let mut max_chain_length = 0;
+        let mut total_versions = 0;
+        for value in map.values() {
+            let mut length = 1;
+            let mut next = value.as_inner().0.get_prev_mvcc_index();
+            while let Some(index) = next {
+                reachable.insert(index);
+                length += 1;
+                next = ctx
+                    .old_values_store
+                    .get_mut(index)
+                    .as_inner()
+                    .0
+                    .get_prev_mvcc_index();
+            }
+            max_chain_length = max_chain_length.max(length);
+            total_versions += length;
+        }
+        let reclaimed_versions = ctx
+            .old_values_store
+            .retain(|index| reachable.contains(&index));
+
+        VacuumStats {
+            watermark,
+            reclaimed_versions,
+            removed_keys: dead_keys.len(),
+            skipped_keys,
+            max_chain_length,
+            avg_chain_length: if map.is_empty() {
+                0.0
+            } else {
+                total_versions as f64 / map.len() as f64
+            },
+        }
+    })
+}
+
+// Resolves the write intents of every finished transaction, then drops the records of all finished transactions
+// from the intent map, since no value refers to them anymore. Returns the number of dropped records.
+// Runs with the whole database locked.
+pub fn reclaim_transactions(ctx: &DbContext) -> usize {
+    ctx.db.with_exclusive(|map| {
+        for value in map.values_mut() {
+            value.resolve_intent(ctx);
+        }
+        ctx.transaction_map.remove_finished()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db_context::create_empty_context;
+    use crate::rwtransaction_wrapper::Transaction;
+    use crate::timestamp::Timestamp;
+    use crate::{DbContext, TxnError, TypedValue};
+
+    fn write(ctx: &DbContext, key: &str, value: &str) -> Timestamp {
+        let time = Timestamp::now();
+        let mut txn = Transaction::new_with_time(ctx, time);
+        txn.write(ctx, &key.into(), value.into()).unwrap();
+        txn.commit(ctx).unwrap();
+        time
+    }
+
+    fn read_at(ctx: &DbContext, key: &str, time: Timestamp) -> Result<TypedValue, TxnError> {
+        let mut txn = Transaction::new_with_time(ctx, time);
+        let res = txn.read_mvcc(ctx, &key.into()).map(|a| a.into_inner().1);
+        txn.commit(ctx).unwrap();
+        res
+    }
+
+    #[test]
+    fn reclaims_unreachable_versions() {
+        let ctx = create_empty_context();
+        let first = write(&ctx, "/test/a", "0");
+        for i in 1..5 {
+            write(&ctx, "/test/a", &i.to_string());
+        }
+        write(&ctx, "/test/b", "0");
+        assert_eq!(ctx.old_values_store.len(), 4);
+
+        let stats = ctx.vacuum();
+        assert_eq!(stats.reclaimed_versions, 4);
+        assert_eq!(stats.max_chain_length, 1);
+        assert_eq!(stats.avg_chain_length, 1.0);
+        assert!(ctx.old_values_store.is_empty());
+
+        assert_eq!(read_at(&ctx, "/test/a", Timestamp::now()), Ok("4".into()));
+        // The version that was visible back then is gone, so reading it must fail instead of returning nothing.
+        assert_matches!(read_at(&ctx, "/test/a", first), Err(..));
+    }
+
+    #[test]
+    fn keeps_versions_visible_to_active_transactions() {
+        let ctx = create_empty_context();
+        write(&ctx, "/test/a", "0");
+        write(&ctx, "/test/a", "1");
+
+        let mut old = Transaction::new_with_time(&ctx, Timestamp::now());
+        write(&ctx, "/test/a", "2");
+        write(&ctx, "/test/a", "3");
+
+        let stats = ctx.vacuum();
+        assert_eq!(stats.watermark, Timestamp(old.txn.timestamp.0 - 1));
+        assert_eq!(stats.reclaimed_versions, 1);
+        assert_eq!(stats.max_chain_length, 3);
+        assert_eq!(
+            old.read_mvcc(&ctx, &"/test/a".into()).unwrap().get_val(),
+            &"1".into()
+        );
+        old.commit(&ctx).unwrap();
+
+        let stats = ctx.vacuum();
+        assert_eq!(stats.reclaimed_versions, 2);
+        assert_eq!(stats.max_chain_length, 1);
+    }
+
+    #[test]
+    fn reclaims_finished_transaction_records() {
+        let ctx = create_empty_context();
+        for i in 0..10 {
+            write(&ctx, &format!("/test/{}", i), "0");
+        }
+        let mut aborted = Transaction::new_with_time(&ctx, Timestamp::now());
+        aborted.write(&ctx, &"/test/0".into(), "1".into()).unwrap();
+        aborted.write(&ctx, &"/test/new".into(), "1".into()).unwrap();
+        aborted.abort(&ctx).unwrap();
+        let mut pending = Transaction::new_with_time(&ctx, Timestamp::now());
+        pending.write(&ctx, &"/test/1".into(), "1".into()).unwrap();
+        assert_eq!(ctx.transaction_map.len(), 12);
+
+        assert_eq!(ctx.reclaim_transactions(), 11);
+        assert_eq!(ctx.transaction_map.len(), 1);
+
+        assert_eq!(read_at(&ctx, "/test/0", Timestamp::now()), Ok("0".into()));
+        assert_matches!(read_at(&ctx, "/test/new", Timestamp::now()), Err(..));
+        assert_matches!(read_at(&ctx, "/test/1", Timestamp::now()), Err(..));
+        pending.commit(&ctx).unwrap();
+        assert_eq!(read_at(&ctx, "/test/1", Timestamp::now()), Ok("1".into()));
+    }
+
+    #[test]
+    fn removes_deleted_keys() {
+        let ctx = create_empty_context();
+        write(&ctx, "/test/a", "0");
+        write(&ctx, "/test/b", "0");
+        let mut delete = Transaction::new_with_time(&ctx, Timestamp::now());
+        delete.write(&ctx, &"/test/a".into(), TypedValue::Deleted).unwrap();
+        delete.commit(&ctx).unwrap();
+
+        let mut old = Transaction::new_with_time(&ctx, Timestamp::now());
+        let read = Timestamp::now();
+        assert_eq!(read_at(&ctx, "/test/a", read), Err(TxnError::NotFound));
+
+        let stats = ctx.vacuum();
+        assert_eq!(stats.removed_keys, 1);
+        assert!(ctx.old_values_store.is_empty());
+        assert!(ctx.db.get_raw_with_lock(&"/test/a".into()).value().is_none());
+        assert_eq!(read_at(&ctx, "/test/b", Timestamp::now()), Ok("0".into()));
+
+        // The key was read after `old` started, so `old` still can't write it.
+        assert_eq!(
+            old.write(&ctx, &"/test/a".into(), "1".into()),
+            Err(TxnError::PhantomDetected)
+        );
+        old.abort(&ctx).unwrap();
+        write(&ctx, "/test/a", "1");
+        assert_eq!(read_at(&ctx, "/test/a", Timestamp::now()), Ok("1".into()));
+    }
+
+    #[test]
+    fn skips_keys_with_pending_writes() {
+        let ctx = create_empty_context();
+        write(&ctx, "/test/a", "0");
+        write(&ctx, "/test/a", "1");
+
+        let mut pending = Transaction::new_with_time(&ctx, Timestamp::now());
+        pending
+            .write(&ctx, &"/test/a".into(), "2".into())
+            .unwrap();
+        let stats = ctx.vacuum();
+        assert_eq!(stats.skipped_keys, 1);
+        assert_eq!(stats.reclaimed_versions, 0);
+
+        // Rolling back the aborted write needs the previous version.
+        pending.abort(&ctx).unwrap();
+        assert_eq!(read_at(&ctx, "/test/a", Timestamp::now()), Ok("1".into()));
+
+        let stats = ctx.vacuum();
+        assert_eq!(stats.skipped_keys, 0);
+        assert!(ctx.old_values_store.is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::object_path::ObjectPath;
+use crate::rwtransaction_wrapper::LockDataRef;
+use crate::{DbContext, TxnError};
+
+// What a transaction does when it runs into another transaction's write intent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WaitPolicy {
+    // Fail right away with `TxnError::WriteConflict`.
+    NoWait,
+    // Wait up to `timeout` for the owner of the intent to commit or abort, but only if the owner is older than us.
+    // Like in wound-wait, only younger transactions wait for older ones, so there can't be a deadlock.
+    // Instead of wounding a younger owner, an older transaction fails right away: with timestamp ordering it
+    // couldn't write after the younger one commits anyway.
+    WaitForOlder { timeout: Duration },
+}
+
+impl Default for WaitPolicy {
+    fn default() -> Self {
+        WaitPolicy::NoWait
+    }
+}
+
+impl WaitPolicy {
+    fn waits_for(&self, txn: LockDataRef, owner: LockDataRef) -> Option<Duration> {
+        match self {
+            WaitPolicy::NoWait => None,
+            WaitPolicy::WaitForOlder { timeout } => {
+                ((owner.timestamp, owner.id) < (txn.timestamp, txn.id)).then(|| *timeout)
+            }
+        }
+    }
+}
+
+struct Queue {
+    // Bumped every time an intent on the key gets resolved.
+    generation: Mutex<u64>,
+    resolved: Condvar,
+}
+
+// Transactions waiting for the intent on a key to be resolved, per key. Keys nobody waits on have no entry.
+#[derive(Default)]
+pub(crate) struct WaitQueues {
+    queues: Mutex<BTreeMap<ObjectPath, Arc<Queue>>>,
+}
+
+struct Ticket<'a> {
+    queues: &'a WaitQueues,
+    key: &'a ObjectPath,
+    queue: Arc<Queue>,
+    seen: u64,
+}
+
+impl WaitQueues {
+    fn register<'a>(&'a self, key: &'a ObjectPath) -> Ticket<'a> {
+        let queue = self
+            .queues
+            .lock()
+            .entry(key.clone())
+            .or_insert_with(|| {
+                Arc::new(Queue {
+                    generation: Mutex::new(0),
+                    resolved: Condvar::new(),
+                })
+            })
+            .clone();
+        let seen = *queue.generation.lock();
+        Ticket {
+            queues: self,
+            key,
+            queue,
+            seen,
+        }
+    }
+
+    // Wakes up the transactions waiting on `key`. Called after an intent on it has been resolved.
+    pub(crate) fn notify(&self, key: &ObjectPath) {
+        if let Some(queue) = self.queues.lock().get(key) {
+            *queue.generation.lock() += 1;
+            queue.resolved.notify_all();
+        }
+    }
+}
+
+impl Ticket<'_> {
+    // Returns false if nothing got resolved on the key before `deadline`.
+    fn wait(&mut self, deadline: Instant) -> bool {
+        let mut generation = self.queue.generation.lock();
+        while *generation == self.seen {
+            if self.queue.resolved.wait_until(&mut generation, deadline).timed_out() {
+                return false;
+            }
+        }
+        self.seen = *generation;
+        true
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        let mut queues = self.queues.queues.lock();
+        // Only the map and us hold the queue, so nobody else is waiting on the key.
+        if Arc::strong_count(&self.queue) == 2 {
+            queues.remove(self.key);
+        }
+    }
+}
+
+// Runs `op` on `key` for `txn`, and if it fails because of another transaction's intent, waits for that
+// transaction to finish and tries again, as far as the wait policy of `ctx` allows.
+pub(crate) fn with_wait<R>(
+    ctx: &DbContext,
+    txn: LockDataRef,
+    key: &ObjectPath,
+    mut op: impl FnMut() -> Result<R, TxnError>,
+) -> Result<R, TxnError> {
+    let mut waiting: Option<(Ticket, Instant)> = None;
+    loop {
+        let res = op();
+        let owner = match res {
+            Err(TxnError::WriteConflict(owner)) => owner,
+            res => return res,
+        };
+        // Whoever owns the key now, which isn't necessarily the one we waited for.
+        let timeout = match ctx.wait_policy().waits_for(txn, owner) {
+            Some(timeout) => timeout,
+            None => return res,
+        };
+        match &mut waiting {
+            // Try once more right after getting in the queue, in case the owner finished in between.
+            None => waiting = Some((ctx.wait_queues.register(key), Instant::now() + timeout)),
+            Some((ticket, deadline)) => {
+                if !ticket.wait(*deadline) {
+                    return res;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::db_context::create_empty_context;
+    use crate::rwtransaction_wrapper::{LockDataRef, Transaction};
+    use crate::timestamp::Timestamp;
+    use crate::wait_queue::{with_wait, WaitPolicy};
+    use crate::TxnError;
+
+    #[test]
+    fn younger_transaction_waits_for_older() {
+        let mut ctx = create_empty_context();
+        ctx.set_wait_policy(WaitPolicy::WaitForOlder {
+            timeout: Duration::from_secs(10),
+        });
+        let ctx = &ctx;
+        let key = "/test/a".into();
+
+        let mut older = Transaction::new_with_time(ctx, Timestamp::now());
+        let mut younger = Transaction::new_with_time(ctx, Timestamp::now());
+        older.write(ctx, &key, "1".into()).unwrap();
+
+        crossbeam::scope(|s| {
+            let waiter = s.spawn(|_| {
+                younger.write(ctx, &key, "2".into()).unwrap();
+                younger.commit(ctx).unwrap();
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            older.commit(ctx).unwrap();
+            waiter.join().unwrap();
+        })
+        .unwrap();
+
+        let mut reader = Transaction::new_with_time(ctx, Timestamp::now());
+        assert_eq!(
+            reader.read_mvcc(ctx, &key).unwrap().into_inner().1,
+            "2".into()
+        );
+    }
+
+    #[test]
+    fn older_transaction_fails_right_away() {
+        let mut ctx = create_empty_context();
+        ctx.set_wait_policy(WaitPolicy::WaitForOlder {
+            timeout: Duration::from_secs(10),
+        });
+        let key = "/test/a".into();
+
+        let mut older = Transaction::new_with_time(&ctx, Timestamp::now());
+        let mut younger = Transaction::new_with_time(&ctx, Timestamp::now());
+        younger.write(&ctx, &key, "1".into()).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(
+            older.write(&ctx, &key, "2".into()),
+            Err(TxnError::WriteConflict(younger.txn))
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+        younger.commit(&ctx).unwrap();
+        older.abort(&ctx).unwrap();
+    }
+
+    #[test]
+    fn stops_waiting_when_a_younger_transaction_takes_over() {
+        let mut ctx = create_empty_context();
+        ctx.set_wait_policy(WaitPolicy::WaitForOlder {
+            timeout: Duration::from_secs(10),
+        });
+        let ctx = &ctx;
+        let key = "/test/a".into();
+        let (older, txn, younger) = (
+            LockDataRef::debug_new(10),
+            LockDataRef::debug_new(20),
+            LockDataRef::debug_new(30),
+        );
+
+        // The older owner finishes while we wait, but a younger one gets the key before we try again.
+        let mut attempts = 0;
+        let start = Instant::now();
+        let res: Result<(), _> = crossbeam::scope(|s| {
+            s.spawn(|_| {
+                std::thread::sleep(Duration::from_millis(50));
+                ctx.wait_queues.notify(&key);
+            });
+            with_wait(ctx, txn, &key, || {
+                attempts += 1;
+                let owner = if attempts <= 2 { older } else { younger };
+                Err(TxnError::WriteConflict(owner))
+            })
+        })
+        .unwrap();
+        assert_eq!(res, Err(TxnError::WriteConflict(younger)));
+        assert_eq!(attempts, 3);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn waiting_times_out() {
+        let mut ctx = create_empty_context();
+        ctx.set_wait_policy(WaitPolicy::WaitForOlder {
+            timeout: Duration::from_millis(50),
+        });
+        let key = "/test/a".into();
+
+        let mut older = Transaction::new_with_time(&ctx, Timestamp::now());
+        let mut younger = Transaction::new_with_time(&ctx, Timestamp::now());
+        older.write(&ctx, &key, "1".into()).unwrap();
+
+        assert_eq!(
+            younger.read_mvcc(&ctx, &key).map(|_| ()),
+            Err(TxnError::WriteConflict(older.txn))
+        );
+        assert!(ctx.wait_queues.queues.lock().is_empty());
+        older.commit(&ctx).unwrap();
+        younger.abort(&ctx).unwrap();
+    }
+}
@@ -1,21 +1,24 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize};
 
-use wal_apply::apply_wal_txn_checked;
+use wal_apply::{apply_prepared, apply_wal_txn_checked};
 
 use crate::object_path::ObjectPath;
-use crate::rpc_handler::{DatabaseInterface, NetworkResult};
 use crate::rwtransaction_wrapper::{LockDataRef, ValueWithMVCC};
 use crate::timestamp::Timestamp;
 use crate::{DbContext, TypedValue};
-use rand::distributions::Alphanumeric;
-use rand::Rng;
-use std::fs::{File, OpenOptions};
+use std::fs::File;
+use std::path::{Path, PathBuf};
 
+mod record_format;
+mod segments;
 mod serialize_deserialize;
 mod test;
 mod wal_apply;
@@ -36,10 +39,30 @@ impl PartialEq for Operation<ObjectPath, ValueWithMVCC> {
     }
 }
 
+// What a WAL record says about its transaction.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum WalRecordKind {
+    // The transaction committed with these writes.
+    Commit,
+    // The transaction is prepared with these writes, and will commit or abort when the coordinator decides to.
+    Prepare,
+    // The decision for a transaction that was prepared earlier. These records have no operations.
+    CommitPrepared,
+    AbortPrepared,
+    // We're about to prepare the transaction on the replicas, as the coordinator of its two-phase commit. Unless
+    // there's also a commit record for it, it aborted. See `coordinator::Decisions`.
+    Coordinating,
+    // The replicas acknowledged the outcome of a transaction we coordinated, so it doesn't have to be resent.
+    Acknowledged,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WalTxn {
     ops: Vec<Operation<ObjectPath, TypedValue>>,
     timestamp: Timestamp,
+    kind: WalRecordKind,
+    // Id of the transaction, so a prepared transaction can be matched with its decision.
+    id: u64,
 }
 
 impl PartialEq for WalTxn {
@@ -51,12 +74,67 @@ impl PartialEq for WalTxn {
     }
 }
 
+// Where a record ends in the WAL: the segment it's in, and the byte offset just past it. Records are appended in
+// the order their transactions committed, so that's the order of their positions too.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WalPosition {
+    pub segment: u64,
+    pub offset: u64,
+}
+
+// How hard `WalStorer::store` tries to make a transaction durable before returning.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WalDurability {
+    // Hand the record to the OS and return. A machine crash can lose recently committed transactions.
+    NoSync,
+    // fsync the log after every record.
+    SyncPerCommit,
+    // Concurrent commits share one fsync. The first committer to need a sync waits up to `max_delay`
+    // (or until `max_batch` records are waiting) for others to join, then syncs for all of them.
+    GroupCommit { max_delay: Duration, max_batch: usize },
+}
+
+impl Default for WalDurability {
+    fn default() -> Self {
+        WalDurability::SyncPerCommit
+    }
+}
+
+// Records are numbered in the order they were written to the file.
+#[derive(Default)]
+struct SyncState {
+    written: u64,
+    synced: u64,
+    // Whether some committer is currently collecting a batch or syncing it.
+    leader_active: bool,
+}
+
+// The segment that records are currently appended to.
+struct Segment {
+    id: u64,
+    file: File,
+    len: u64,
+}
+
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
 #[allow(clippy::mutex_atomic)]
 pub struct ByteBufferWAL {
     buf: RefCell<Vec<u8>>,
     json_lock: Mutex<()>,
-    file: Mutex<File>,
+    dir: PathBuf,
+    file: Mutex<Segment>,
     frozen: Mutex<bool>,
+    durability: WalDurability,
+    // A new segment is started once the current one grows past this many bytes.
+    segment_size: u64,
+    // Separate handle to the current segment so syncing doesn't block writers.
+    sync_file: Mutex<File>,
+    sync_state: parking_lot::Mutex<SyncState>,
+    sync_cond: parking_lot::Condvar,
+    sync_count: AtomicU64,
+    // Whether `dir` is ours alone and goes away with us, see `new`.
+    temporary: bool,
 }
 
 // impl Clone for ByteBufferWAL {
@@ -67,30 +145,228 @@ pub struct ByteBufferWAL {
 //     }
 // }
 
+impl Drop for ByteBufferWAL {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+// Prints the whole log as JSON (one transaction per line), for debugging.
 impl Display for ByteBufferWAL {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str(unsafe { std::str::from_utf8_unchecked(&self.buf.borrow()) })
+        for txn in self.load().map_err(|_| std::fmt::Error)? {
+            f.write_str(&serde_json::to_string(&txn).unwrap())?;
+            f.write_str("\n")?;
+        }
+        Ok(())
     }
 }
 
 impl ByteBufferWAL {
+    // A WAL in a new temporary directory, which is deleted again when the WAL is dropped. Nothing is synced, it's
+    // for databases that don't outlive the process.
     pub fn new() -> Self {
-        use rand::thread_rng;
-        let s = thread_rng().gen::<u16>().to_string();
-        let path = "/tmp/wallog-".to_string() + &s;
-        let file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .truncate(true)
-            .create(true)
-            .open(path)
-            .unwrap();
-        let file = Mutex::new(file);
-        Self {
+        let dir = std::env::temp_dir().join(format!("wallog-{}", rand::random::<u64>()));
+        let mut wal = Self::open(dir, 0).unwrap();
+        wal.durability = WalDurability::NoSync;
+        wal.temporary = true;
+        wal
+    }
+
+    // Opens the segmented log in directory `dir` for appending, keeping everything already in it so it can be replayed.
+    // Segments before `first_segment` are already covered by a checkpoint and get deleted.
+    // If the last segment ends with a partially written or corrupted record (e.g. the writer crashed while writing
+    // it), that record and everything after it is cut off. Damage to any earlier segment is an error.
+    pub fn open<P: AsRef<Path>>(dir: P, first_segment: u64) -> Result<Self, String> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+
+        let mut ids = segments::list(&dir)?;
+        // Left over if we crashed between writing a checkpoint and deleting the segments it covers.
+        for id in ids.iter().filter(|id| **id < first_segment) {
+            segments::remove(&dir, *id)?;
+        }
+        ids.retain(|id| *id >= first_segment);
+
+        let mut current = None;
+        let mut current_version = record_format::FORMAT_VERSION;
+        for (i, id) in ids.iter().enumerate() {
+            let (file, len, version) = segments::open(&dir, *id, i + 1 == ids.len())?;
+            current = Some(Segment { id: *id, file, len });
+            current_version = version;
+        }
+        let current = match current {
+            // Records of an older format stay in their segment, new ones go into a new segment.
+            Some(segment) if current_version != record_format::FORMAT_VERSION => Segment {
+                id: segment.id + 1,
+                file: segments::create(&dir, segment.id + 1)?,
+                len: record_format::HEADER_LEN as u64,
+            },
+            Some(segment) => segment,
+            None => Segment {
+                id: first_segment,
+                file: segments::create(&dir, first_segment)?,
+                len: record_format::HEADER_LEN as u64,
+            },
+        };
+        segments::sync_dir(&dir)?;
+
+        Ok(Self {
             buf: RefCell::new(Vec::new()),
             json_lock: Mutex::new(()),
+            dir,
             frozen: Mutex::new(false),
+            sync_file: Mutex::new(current.file.try_clone().map_err(|err| err.to_string())?),
+            file: Mutex::new(current),
+            durability: WalDurability::default(),
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            sync_state: parking_lot::Mutex::new(SyncState::default()),
+            sync_cond: parking_lot::Condvar::new(),
+            sync_count: AtomicU64::new(0),
+            temporary: false,
+        })
+    }
+
+    pub fn set_segment_size(&mut self, bytes: u64) {
+        self.segment_size = bytes;
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // While frozen, storing a transaction fails, so none can commit.
+    pub fn set_frozen(&self, frozen: bool) {
+        *self.frozen.lock().unwrap() = frozen;
+    }
+
+    // Where the log currently ends. Records stored from now on come after it.
+    pub fn end(&self) -> WalPosition {
+        let segment = self.file.lock().unwrap();
+        WalPosition {
+            segment: segment.id,
+            offset: segment.len,
+        }
+    }
+
+    // Id of the segment that records are currently appended to.
+    pub fn current_segment(&self) -> u64 {
+        self.file.lock().unwrap().id
+    }
+
+    // Switches to a new segment and returns its id. Every record stored before this call is in an earlier segment.
+    pub fn rotate(&self) -> Result<u64, String> {
+        let _guard = self.json_lock.lock().unwrap();
+        let mut segment = self.file.lock().unwrap();
+        let next = segment.id + 1;
+        self.start_segment(&mut segment, next)?;
+        Ok(next)
+    }
+
+    // Deletes the segments before `first_segment`, once a checkpoint covers all of them.
+    pub fn remove_segments_before(&self, first_segment: u64) -> Result<(), String> {
+        let _guard = self.json_lock.lock().unwrap();
+        for id in segments::list(&self.dir)? {
+            if id < first_segment {
+                segments::remove(&self.dir, id)?;
+            }
+        }
+        segments::sync_dir(&self.dir)
+    }
+
+    fn start_segment(&self, segment: &mut Segment, id: u64) -> Result<(), String> {
+        // Only the last segment may have unsynced records, so recovery never has to look for holes.
+        segment.file.sync_data().map_err(|err| err.to_string())?;
+        let file = segments::create(&self.dir, id)?;
+        *self.sync_file.lock().unwrap() = file.try_clone().map_err(|err| err.to_string())?;
+        *segment = Segment {
+            id,
             file,
+            len: record_format::HEADER_LEN as u64,
+        };
+        Ok(())
+    }
+
+    pub fn set_durability(&mut self, durability: WalDurability) {
+        self.durability = durability;
+    }
+
+    pub fn durability(&self) -> WalDurability {
+        self.durability
+    }
+
+    // Number of fsyncs issued for committed records so far.
+    pub fn sync_count(&self) -> u64 {
+        self.sync_count.load(Ordering::SeqCst)
+    }
+
+    // Appends an encoded record to the current segment and returns its sequence number and position.
+    fn append(&self, record: &[u8]) -> Result<(u64, WalPosition), String> {
+        let _guard = self.json_lock.lock().unwrap();
+        let mut segment = self.file.lock().unwrap();
+        if segment.len > record_format::HEADER_LEN as u64
+            && segment.len + record.len() as u64 > self.segment_size
+        {
+            let next = segment.id + 1;
+            self.start_segment(&mut segment, next)?;
+        }
+        segment
+            .file
+            .write_all(record)
+            .and_then(|_| segment.file.flush())
+            .map_err(|err| err.to_string())?;
+        segment.len += record.len() as u64;
+        let position = WalPosition {
+            segment: segment.id,
+            offset: segment.len,
+        };
+
+        let mut state = self.sync_state.lock();
+        state.written += 1;
+        // Lets a group commit leader know its batch may be full.
+        self.sync_cond.notify_all();
+        Ok((state.written, position))
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        self.sync_count.fetch_add(1, Ordering::SeqCst);
+        self.sync_file.lock().unwrap().sync_data()
+    }
+
+    // Blocks until the record with sequence number `seq` has been synced to disk,
+    // either by this thread (as the leader of a batch) or by another committer.
+    fn wait_synced(&self, seq: u64, max_delay: Duration, max_batch: usize) -> std::io::Result<()> {
+        let mut state = self.sync_state.lock();
+        loop {
+            if state.synced >= seq {
+                return Ok(());
+            }
+            if state.leader_active {
+                self.sync_cond.wait(&mut state);
+                continue;
+            }
+
+            state.leader_active = true;
+            let deadline = Instant::now() + max_delay;
+            while ((state.written - state.synced) as usize) < max_batch {
+                if self.sync_cond.wait_until(&mut state, deadline).timed_out() {
+                    break;
+                }
+            }
+            let target = state.written;
+            drop(state);
+
+            let result = self.sync();
+
+            state = self.sync_state.lock();
+            state.leader_active = false;
+            if result.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.sync_cond.notify_all();
+            result?;
         }
     }
 }
@@ -102,12 +378,13 @@ impl Write for &ByteBufferWAL {
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        let l = self.json_lock.lock().unwrap();
+        let _guard = self.json_lock.lock().unwrap();
         let mut buf = self.buf.borrow_mut();
 
-        let mut file = self.file.lock().unwrap();
-        file.write_all(buf.as_slice())?;
-        file.flush();
+        let mut segment = self.file.lock().unwrap();
+        segment.file.write_all(buf.as_slice())?;
+        segment.file.flush()?;
+        segment.len += buf.len() as u64;
         buf.clear();
         Ok(())
     }
@@ -116,72 +393,136 @@ impl Write for &ByteBufferWAL {
 impl WalStorer for ByteBufferWAL {
     type K = ObjectPath;
     type V = ValueWithMVCC;
-    fn store(&self, waltxn: WalTxn) -> Result<(), String> {
+    fn store(&self, waltxn: WalTxn) -> Result<WalPosition, String> {
         if *self.frozen.lock().unwrap() {
             return Err("Wal log is currently frozen".to_string());
         }
 
-        {
-            let _guard = self.json_lock.lock().unwrap();
-            serde_json::to_writer(self, &waltxn).unwrap();
-        }
-        let mut k = self;
-        k.flush();
-        Ok(())
-    }
-    fn raw_data(&self) -> Vec<u8> {
-        self.buf.borrow().clone()
+        let (seq, position) = self.append(&record_format::encode_record(&waltxn))?;
+
+        match self.durability {
+            WalDurability::NoSync => Ok(()),
+            WalDurability::SyncPerCommit => self.sync().map_err(|err| err.to_string()),
+            WalDurability::GroupCommit {
+                max_delay,
+                max_batch,
+            } => self
+                .wait_synced(seq, max_delay, max_batch)
+                .map_err(|err| err.to_string()),
+        }?;
+        Ok(position)
     }
 }
 
 impl WalLoader for ByteBufferWAL {
-    fn load(&self) -> Vec<WalTxn> {
-        let buf: Vec<u8> = {
-            let _guard = self.json_lock.lock().unwrap();
-            let mut filebuf = Vec::new();
-            let mut file = self.file.lock().unwrap();
-            let prevpos = file.stream_position().unwrap();
-
-            file.seek(SeekFrom::Start(0));
-            file.read_to_end(&mut filebuf);
-
-            file.seek(SeekFrom::Start(prevpos));
-            filebuf.extend(self.buf.borrow().iter());
-            filebuf
-        };
-
-        let iter = serde_json::Deserializer::from_reader(buf.as_slice()).into_iter::<WalTxn>();
-        let mut vec: Vec<_> = iter.map(|a| a.unwrap()).collect();
+    fn load_positions_from(&self, first_segment: u64) -> Result<Vec<(WalPosition, WalTxn)>, String> {
+        let mut txns = Vec::new();
+        let _guard = self.json_lock.lock().unwrap();
+        let ids = segments::list(&self.dir)?;
+        let last = ids.last().copied();
+        for id in ids {
+            if id < first_segment {
+                continue;
+            }
+            let decoded = segments::read(&self.dir, id)
+                .map_err(|err| format!("WAL segment {}: {}", id, err))?;
+            for (end, txn) in decoded.ends.into_iter().zip(decoded.txns) {
+                let position = WalPosition {
+                    segment: id,
+                    offset: (record_format::HEADER_LEN + end) as u64,
+                };
+                txns.push((position, txn));
+            }
+            if let Some(err) = decoded.error {
+                // A writer that crashed halfway through a record leaves an incomplete record at the end of the
+                // log. That transaction never finished committing, so we stop there instead of failing. Anywhere
+                // else, the rest of the log would be lost.
+                if Some(id) != last {
+                    return Err(format!("WAL segment {} is corrupted: {}", id, err));
+                }
+                log::warn!("Ignoring WAL tail in segment {}: {}", id, err);
+            }
+        }
+        Ok(txns)
+    }
 
-        vec.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
-        vec
+    fn first_segment(&self) -> Result<u64, String> {
+        let _guard = self.json_lock.lock().unwrap();
+        let first = segments::list(&self.dir)?.into_iter().min();
+        Ok(first.unwrap_or_else(|| self.file.lock().unwrap().id))
     }
 }
 
 pub trait WalStorer {
     type K;
     type V;
-    fn store(&self, waltxn: WalTxn) -> Result<(), String>;
-
-    fn raw_data(&self) -> Vec<u8>;
+    // Returns where the record ended up in the log.
+    fn store(&self, waltxn: WalTxn) -> Result<WalPosition, String>;
 }
 
 pub trait WalLoader: WalStorer {
-    fn load(&self) -> Vec<WalTxn>;
+    // Loads all transactions stored in segment `first_segment` or later, in the order they were logged.
+    // Fails if a segment can't be read, or is corrupted anywhere but at the end of the log.
+    fn load_positions_from(&self, first_segment: u64) -> Result<Vec<(WalPosition, WalTxn)>, String>;
+
+    // Id of the oldest segment that's still in the log.
+    fn first_segment(&self) -> Result<u64, String>;
+
+    // Loads all transactions stored in segment `first_segment` or later, sorted by timestamp.
+    fn load_from(&self, first_segment: u64) -> Result<Vec<WalTxn>, String> {
+        let mut txns: Vec<WalTxn> = self
+            .load_positions_from(first_segment)?
+            .into_iter()
+            .map(|(_, txn)| txn)
+            .collect();
+        txns.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        Ok(txns)
+    }
+
+    fn load(&self) -> Result<Vec<WalTxn>, String> {
+        self.load_from(0)
+    }
 
     fn apply(&self, ctx: &DbContext) -> Result<Timestamp, String> {
-        let total = self.load();
+        self.apply_from(ctx, 0)
+    }
+
+    fn apply_from(&self, ctx: &DbContext, first_segment: u64) -> Result<Timestamp, String> {
+        let total = self.load_from(first_segment)?;
         let mut max_time = Timestamp::mintime();
+        let mut prepared = HashMap::new();
         for elem in &total {
             max_time = max_time.max(elem.timestamp);
-            apply_wal_txn_checked(elem.clone(), ctx);
+            match elem.kind {
+                WalRecordKind::Commit => {
+                    apply_wal_txn_checked(elem.clone(), ctx);
+                    ctx.decisions.decide(elem.txn(), true);
+                }
+                WalRecordKind::Prepare => {
+                    let txn = apply_prepared(elem.clone(), ctx);
+                    prepared.insert(elem.txn(), txn);
+                }
+                WalRecordKind::CommitPrepared | WalRecordKind::AbortPrepared => {
+                    let commit = elem.kind == WalRecordKind::CommitPrepared;
+                    match prepared.remove(&elem.txn()) {
+                        Some(mut txn) if commit => txn.commit(ctx)?,
+                        Some(mut txn) => txn.abort_decided(ctx)?,
+                        // Prepared before the checkpoint we loaded from.
+                        None => ctx.resolve_in_doubt(elem.txn(), commit)?,
+                    }
+                }
+                // Aborted, unless its commit record comes after this one.
+                WalRecordKind::Coordinating => ctx.decisions.restore(elem.txn(), false),
+                WalRecordKind::Acknowledged => ctx.decisions.acknowledged(elem.txn()),
+            }
         }
+        ctx.in_doubt.lock().extend(prepared.into_values());
         Ok(max_time)
     }
 }
 
 impl WalTxn {
-    fn log_write(&mut self, k: ObjectPath, v: TypedValue) {
+    pub(crate) fn log_write(&mut self, k: ObjectPath, v: TypedValue) {
         self.ops.push(Operation::Write(k, v));
     }
 
@@ -189,50 +530,85 @@ impl WalTxn {
         WalTxn {
             ops: vec![],
             timestamp,
+            kind: WalRecordKind::Commit,
+            id: timestamp.0,
+        }
+    }
+
+    pub(crate) fn for_txn(txn: LockDataRef) -> Self {
+        WalTxn {
+            id: txn.id,
+            ..Self::new(txn.timestamp)
+        }
+    }
+
+    // A record without operations that marks `txn` as having committed or aborted after it was prepared.
+    pub(crate) fn decision(txn: LockDataRef, commit: bool) -> Self {
+        WalTxn {
+            kind: if commit {
+                WalRecordKind::CommitPrepared
+            } else {
+                WalRecordKind::AbortPrepared
+            },
+            ..Self::for_txn(txn)
         }
     }
-}
 
-impl DatabaseInterface for &mut WalTxn {
-    fn new_transaction(&self, txn: &LockDataRef) -> NetworkResult<(), String> {
-        unreachable!()
+    pub(crate) fn coordinating(txn: LockDataRef) -> Self {
+        WalTxn {
+            kind: WalRecordKind::Coordinating,
+            ..Self::for_txn(txn)
+        }
+    }
+
+    pub(crate) fn acknowledged(txn: LockDataRef) -> Self {
+        WalTxn {
+            kind: WalRecordKind::Acknowledged,
+            ..Self::for_txn(txn)
+        }
     }
 
-    fn serve_read(
-        &self,
-        txn: LockDataRef,
-        key: &ObjectPath,
-    ) -> NetworkResult<ValueWithMVCC, String> {
-        unreachable!()
+    pub(crate) fn into_prepared(self) -> Self {
+        WalTxn {
+            kind: WalRecordKind::Prepare,
+            ..self
+        }
     }
 
-    fn serve_range_read(
-        &self,
-        txn: LockDataRef,
-        key: &ObjectPath,
-    ) -> NetworkResult<Vec<(ObjectPath, ValueWithMVCC)>, String> {
-        unreachable!()
+    pub(crate) fn txn(&self) -> LockDataRef {
+        LockDataRef {
+            id: self.id,
+            timestamp: self.timestamp,
+        }
     }
 
-    fn serve_write(
-        &self,
-        txn: LockDataRef,
-        key: &ObjectPath,
-        value: TypedValue,
-    ) -> NetworkResult<(), String> {
-        // safety: we implemented this trait for &mut WalTxn so we can cast.
-        let k = unsafe { &mut *(*self as *const WalTxn as *mut WalTxn) };
+    pub(crate) fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
 
-        k.log_write(key.clone(), value);
+    pub(crate) fn kind(&self) -> WalRecordKind {
+        self.kind
+    }
 
-        NetworkResult::default()
+    pub(crate) fn op_count(&self) -> usize {
+        self.ops.len()
     }
 
-    fn commit(&self, txn: LockDataRef) -> NetworkResult<(), String> {
-        unreachable!()
+    // Drops the operations after the first `len`, and returns the keys they wrote.
+    pub(crate) fn truncate(&mut self, len: usize) -> Vec<ObjectPath> {
+        self.ops
+            .drain(len..)
+            .filter_map(|op| match op {
+                Operation::Write(key, _) => Some(key),
+                Operation::Read(..) => None,
+            })
+            .collect()
     }
 
-    fn abort(&self, p0: LockDataRef) -> NetworkResult<(), String> {
-        unreachable!()
+    pub(crate) fn writes(&self) -> impl Iterator<Item = (&ObjectPath, &TypedValue)> {
+        self.ops.iter().filter_map(|op| match op {
+            Operation::Write(key, value) => Some((key, value)),
+            Operation::Read(..) => None,
+        })
     }
 }
@@ -0,0 +1,332 @@
+// Binary on-disk format of the WAL.
+//
+// The file starts with a header:
+//   [magic: 4 bytes "MWAL"][format version: u32]
+// followed by any number of records:
+//   [payload length: u32][crc32 of payload: u32][payload]
+// All integers are little endian. The payload is a `WalTxn`:
+//   [kind: u8 (0 = commit, 1 = prepare, 2 = commit prepared, 3 = abort prepared, 4 = coordinating,
+//             5 = acknowledged)][transaction id: u64]
+//   [timestamp: u64][number of ops: u32][op]*
+// Version 1 payloads don't have the kind and id; all of them are commits, and the id is the timestamp.
+//   op    = [kind: u8 (0 = write, 1 = read)][key: bytes][value]
+//   value = [kind: u8 (0 = string, 1 = number, 2 = deleted)][string: bytes | number: f64 | nothing]
+//   bytes = [length: u32][data]
+//
+// A record whose length or checksum doesn't match means the writer crashed halfway through writing it
+// (or the disk got corrupted). Everything from that record onwards is treated as garbage.
+
+use std::convert::TryInto;
+
+use super::{Operation, WalRecordKind, WalTxn};
+use crate::object_path::ObjectPath;
+use crate::timestamp::Timestamp;
+use crate::TypedValue;
+
+const MAGIC: &[u8; 4] = b"MWAL";
+pub const FORMAT_VERSION: u32 = 2;
+pub const HEADER_LEN: usize = 8;
+const RECORD_HEADER_LEN: usize = 8;
+
+pub fn encode_header() -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buf
+}
+
+// Returns the format version of the file.
+pub fn check_header(buf: &[u8]) -> Result<u32, String> {
+    if buf.len() < HEADER_LEN || &buf[0..4] != MAGIC {
+        return Err("Not a WAL file".to_string());
+    }
+    let version = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+    if version != 1 && version != FORMAT_VERSION {
+        return Err(format!("Unsupported WAL format version {}", version));
+    }
+    Ok(version)
+}
+
+pub fn encode_record(txn: &WalTxn) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.push(match txn.kind {
+        WalRecordKind::Commit => 0,
+        WalRecordKind::Prepare => 1,
+        WalRecordKind::CommitPrepared => 2,
+        WalRecordKind::AbortPrepared => 3,
+        WalRecordKind::Coordinating => 4,
+        WalRecordKind::Acknowledged => 5,
+    });
+    payload.extend_from_slice(&txn.id.to_le_bytes());
+    payload.extend_from_slice(&txn.timestamp.0.to_le_bytes());
+    payload.extend_from_slice(&(txn.ops.len() as u32).to_le_bytes());
+    for op in &txn.ops {
+        let (kind, key, value) = match op {
+            Operation::Write(k, v) => (0u8, k, v),
+            Operation::Read(k, v) => (1u8, k, v),
+        };
+        payload.push(kind);
+        encode_bytes(&mut payload, key.as_str().as_bytes());
+        encode_value(&mut payload, value);
+    }
+
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record
+}
+
+fn encode_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn encode_value(buf: &mut Vec<u8>, value: &TypedValue) {
+    match value {
+        TypedValue::String(s) => {
+            buf.push(0);
+            encode_bytes(buf, s.as_bytes());
+        }
+        TypedValue::Number(n) => {
+            buf.push(1);
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+        TypedValue::Deleted => buf.push(2),
+        TypedValue::Bool(b) => {
+            buf.push(3);
+            buf.push(*b as u8);
+        }
+        TypedValue::Int(i) => {
+            buf.push(4);
+            buf.extend_from_slice(&i.to_le_bytes());
+        }
+        TypedValue::Bytes(bytes) => {
+            buf.push(5);
+            encode_bytes(buf, bytes);
+        }
+        TypedValue::Null => buf.push(6),
+    }
+}
+
+// Result of decoding a stream of records.
+pub struct DecodedRecords {
+    pub txns: Vec<WalTxn>,
+    // Where each of `txns` ends, from the start of the decoded buffer.
+    pub ends: Vec<usize>,
+    // Number of bytes (from the start of the decoded buffer) that contain complete, valid records.
+    pub valid_len: usize,
+    // Why decoding stopped before the end of the buffer, if it did.
+    pub error: Option<String>,
+}
+
+pub fn decode_records(buf: &[u8], version: u32) -> DecodedRecords {
+    let mut txns = Vec::new();
+    let mut ends = Vec::new();
+    let mut pos = 0;
+
+    while pos < buf.len() {
+        match decode_record(&buf[pos..], version) {
+            Ok((txn, len)) => {
+                txns.push(txn);
+                pos += len;
+                ends.push(pos);
+            }
+            Err(err) => {
+                return DecodedRecords {
+                    txns,
+                    ends,
+                    valid_len: pos,
+                    error: Some(format!("{} at offset {}", err, pos)),
+                }
+            }
+        }
+    }
+
+    DecodedRecords {
+        txns,
+        ends,
+        valid_len: pos,
+        error: None,
+    }
+}
+
+// Decodes one record from the start of `buf`, returning the transaction and the record's total length.
+fn decode_record(buf: &[u8], version: u32) -> Result<(WalTxn, usize), String> {
+    if buf.len() < RECORD_HEADER_LEN {
+        return Err("Truncated record header".to_string());
+    }
+    let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+    let payload = buf
+        .get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)
+        .ok_or_else(|| "Truncated record".to_string())?;
+    if crc32fast::hash(payload) != crc {
+        return Err("Record checksum mismatch".to_string());
+    }
+
+    let mut reader = Reader(payload);
+    let (kind, id) = if version == 1 {
+        (WalRecordKind::Commit, None)
+    } else {
+        let kind = match reader.u8()? {
+            0 => WalRecordKind::Commit,
+            1 => WalRecordKind::Prepare,
+            2 => WalRecordKind::CommitPrepared,
+            3 => WalRecordKind::AbortPrepared,
+            4 => WalRecordKind::Coordinating,
+            5 => WalRecordKind::Acknowledged,
+            other => return Err(format!("Unknown record kind {}", other)),
+        };
+        (kind, Some(reader.u64()?))
+    };
+    let timestamp = Timestamp(reader.u64()?);
+    let num_ops = reader.u32()?;
+    let mut ops = Vec::new();
+    for _ in 0..num_ops {
+        let kind = reader.u8()?;
+        let key = ObjectPath::from(reader.string()?);
+        let value = match reader.u8()? {
+            0 => TypedValue::String(reader.string()?),
+            1 => TypedValue::Number(f64::from_le_bytes(reader.take(8)?.try_into().unwrap())),
+            2 => TypedValue::Deleted,
+            3 => TypedValue::Bool(reader.u8()? != 0),
+            4 => TypedValue::Int(i64::from_le_bytes(reader.take(8)?.try_into().unwrap())),
+            5 => TypedValue::Bytes(reader.bytes()?.to_vec()),
+            6 => TypedValue::Null,
+            other => return Err(format!("Unknown value kind {}", other)),
+        };
+        ops.push(match kind {
+            0 => Operation::Write(key, value),
+            1 => Operation::Read(key, value),
+            other => return Err(format!("Unknown operation kind {}", other)),
+        });
+    }
+    if !reader.0.is_empty() {
+        return Err("Trailing bytes in record".to_string());
+    }
+
+    let txn = WalTxn {
+        ops,
+        timestamp,
+        kind,
+        id: id.unwrap_or(timestamp.0),
+    };
+    Ok((txn, RECORD_HEADER_LEN + len))
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < len {
+            return Err("Record payload too short".to_string());
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+    fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(time: u64) -> WalTxn {
+        let mut txn = WalTxn::new(Timestamp(time));
+        txn.log_write("/test/a".into(), "value".into());
+        txn.log_write("/test/b".into(), TypedValue::Number(1.5));
+        txn.log_write("/test/c".into(), TypedValue::Deleted);
+        txn.log_write("/test/d".into(), TypedValue::Bool(true));
+        txn.log_write("/test/e".into(), TypedValue::Int(-7));
+        txn.log_write("/test/f".into(), TypedValue::Bytes(vec![0, 1, 255]));
+        txn.log_write("/test/g".into(), TypedValue::Null);
+        txn
+    }
+
+    fn encode_all(txns: &[WalTxn]) -> Vec<u8> {
+        txns.iter().flat_map(encode_record).collect()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut prepare = sample(120);
+        prepare.kind = WalRecordKind::Prepare;
+        prepare.id = 7;
+        let txns = vec![sample(100), sample(110), prepare, WalTxn::new(Timestamp(130))];
+        let buf = encode_all(&txns);
+        let decoded = decode_records(&buf, FORMAT_VERSION);
+
+        assert_eq!(decoded.error, None);
+        assert_eq!(decoded.valid_len, buf.len());
+        assert_eq!(decoded.txns.len(), 4);
+        for (a, b) in decoded.txns.iter().zip(&txns) {
+            assert_eq!(a.timestamp, b.timestamp);
+            assert_eq!((a.kind, a.id), (b.kind, b.id));
+            assert_eq!(format!("{:?}", a.ops), format!("{:?}", b.ops));
+        }
+    }
+
+    #[test]
+    fn truncated_tail() {
+        let buf = encode_all(&[sample(100), sample(110)]);
+        let first_len = encode_record(&sample(100)).len();
+
+        for cut in first_len..buf.len() {
+            let decoded = decode_records(&buf[..cut], FORMAT_VERSION);
+            assert_eq!(decoded.txns.len(), 1);
+            assert_eq!(decoded.valid_len, first_len);
+            assert_eq!(decoded.error.is_some(), cut != first_len);
+        }
+    }
+
+    #[test]
+    fn corrupted_tail() {
+        let mut buf = encode_all(&[sample(100), sample(110)]);
+        let last = buf.len() - 3;
+        buf[last] ^= 0xff;
+
+        let decoded = decode_records(&buf, FORMAT_VERSION);
+        assert_eq!(decoded.txns.len(), 1);
+        assert_matches!(decoded.error, Some(err) if err.contains("checksum"));
+    }
+
+    #[test]
+    fn version_1_records_are_commits() {
+        let txn = sample(100);
+        let mut buf = encode_record(&txn);
+        // Strip the kind and id, which version 1 didn't have.
+        buf.drain(RECORD_HEADER_LEN..RECORD_HEADER_LEN + 9);
+        let len = (buf.len() - RECORD_HEADER_LEN) as u32;
+        let crc = crc32fast::hash(&buf[RECORD_HEADER_LEN..]);
+        buf[0..4].copy_from_slice(&len.to_le_bytes());
+        buf[4..8].copy_from_slice(&crc.to_le_bytes());
+
+        let decoded = decode_records(&buf, 1);
+        assert_eq!(decoded.error, None);
+        assert_eq!(decoded.txns[0].kind, WalRecordKind::Commit);
+        assert_eq!(decoded.txns[0].id, 100);
+        assert_eq!(format!("{:?}", decoded.txns[0].ops), format!("{:?}", txn.ops));
+    }
+
+    #[test]
+    fn header() {
+        assert_matches!(check_header(&encode_header()), Ok(FORMAT_VERSION));
+        assert_matches!(check_header(b"{\"ops\":[]}"), Err(..));
+    }
+}
@@ -0,0 +1,117 @@
+// The WAL is stored as a directory of numbered segment files (wal-000000.log, wal-000001.log, ...).
+// Records are only ever appended to the segment with the highest number. Once a checkpoint covers every
+// record in a segment, the segment is deleted.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::record_format;
+
+const PREFIX: &str = "wal-";
+const SUFFIX: &str = ".log";
+
+pub fn path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}{:06}{}", PREFIX, id, SUFFIX))
+}
+
+fn parse_id(name: &str) -> Option<u64> {
+    name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?.parse().ok()
+}
+
+// Ids of all segments in `dir`, in ascending order.
+pub fn list(dir: &Path) -> Result<Vec<u64>, String> {
+    let mut ids = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|err| err.to_string())? {
+        let entry = entry.map_err(|err| err.to_string())?;
+        if let Some(id) = entry.file_name().to_str().and_then(parse_id) {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+pub fn sync_dir(dir: &Path) -> Result<(), String> {
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|err| err.to_string())
+}
+
+// Creates a new, empty segment. The header and the directory entry are synced before returning.
+pub fn create(dir: &Path, id: u64) -> Result<File, String> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .truncate(false)
+        .open(path(dir, id))
+        .map_err(|err| err.to_string())?;
+    file.set_len(0)
+        .and_then(|_| file.write_all(&record_format::encode_header()))
+        .and_then(|_| file.sync_all())
+        .map_err(|err| err.to_string())?;
+    sync_dir(dir)?;
+    Ok(file)
+}
+
+// Opens an existing segment for appending. Returns the file, the number of valid bytes in it and its format version.
+// Only the `last` segment can end in a partially written header or record (the writer crashed while writing it):
+// that part is cut off. Every earlier segment was synced before the next one was created, so anything wrong with
+// one of them is corruption, and an error.
+pub fn open(dir: &Path, id: u64, last: bool) -> Result<(File, u64, u32), String> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .open(path(dir, id))
+        .map_err(|err| err.to_string())?;
+
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)
+        .map_err(|err| err.to_string())?;
+    let header = record_format::encode_header();
+    if contents.len() < header.len() && header.starts_with(&contents) && last {
+        // Crashed right after creating the file, before its header was complete.
+        file.set_len(0)
+            .and_then(|_| file.write_all(&header))
+            .and_then(|_| file.sync_all())
+            .map_err(|err| err.to_string())?;
+        return Ok((
+            file,
+            record_format::HEADER_LEN as u64,
+            record_format::FORMAT_VERSION,
+        ));
+    }
+
+    let version = record_format::check_header(&contents)
+        .map_err(|err| format!("WAL segment {}: {}", id, err))?;
+    let decoded = record_format::decode_records(&contents[record_format::HEADER_LEN..], version);
+    let valid_len = (record_format::HEADER_LEN + decoded.valid_len) as u64;
+    if let Some(err) = decoded.error {
+        if !last {
+            return Err(format!("WAL segment {} is corrupted: {}", id, err));
+        }
+        log::warn!("Truncating WAL segment {}: {}", id, err);
+        file.set_len(valid_len)
+            .and_then(|_| file.sync_all())
+            .map_err(|err| err.to_string())?;
+    }
+    Ok((file, valid_len, version))
+}
+
+// Reads the records in a segment.
+pub fn read(dir: &Path, id: u64) -> Result<record_format::DecodedRecords, String> {
+    let contents = std::fs::read(path(dir, id)).map_err(|err| err.to_string())?;
+    if contents.is_empty() {
+        return Ok(record_format::decode_records(&[], 0));
+    }
+    let version = record_format::check_header(&contents)?;
+    Ok(record_format::decode_records(
+        &contents[record_format::HEADER_LEN..],
+        version,
+    ))
+}
+
+pub fn remove(dir: &Path, id: u64) -> Result<(), String> {
+    std::fs::remove_file(path(dir, id)).map_err(|err| err.to_string())
+}
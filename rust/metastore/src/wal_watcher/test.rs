@@ -13,7 +13,7 @@ pub mod tests {
     fn check(db: &DbContext) {
         let db2 = create_empty_context();
 
-        db.wallog.apply(&db2);
+        db.wallog.apply(&db2).unwrap();
 
         let s = SelfContainedDb::new(db2);
 
@@ -22,13 +22,22 @@ pub mod tests {
         std::mem::forget(s.db);
     }
 
+    #[test]
+    fn temporary_wal_is_deleted() {
+        let db = db!("/test/a" = "1");
+        let dir = db.wallog.dir().to_path_buf();
+        assert!(dir.exists());
+        std::mem::drop(db);
+        assert!(!dir.exists());
+    }
+
     #[test]
     fn test1() {
         let db = db!();
         assert_matches!(auto_commit::read(&db, &"a".into()), Err(..));
 
         let mut aborter = ReplicatedTxn::new(&db);
-        aborter.write(&"a".into(), "v".into());
+        aborter.write(&"a".into(), "v".into()).unwrap();
         aborter.abort();
         assert_matches!(auto_commit::read(&db, &"a".into()), Err(..));
         assert_matches!(auto_commit::read(&db, &"a".into()), Err(..));
@@ -61,17 +70,527 @@ pub mod tests {
                 txn.abort();
                 txn = ReplicatedTxn::new(&db);
             } else if decider % 20 == 1 {
-                txn.commit();
+                txn.commit().unwrap();
                 txn = ReplicatedTxn::new(&db);
             }
 
             let randv = r.gen::<u8>() % 20;
             let randv = randv.to_string();
 
-            txn.write(&randv.clone().into(), randv.into());
+            txn.write(&randv.clone().into(), randv.into()).unwrap();
         }
-        txn.commit();
+        txn.commit().unwrap();
 
         check(&db);
     }
+
+    mod recovery {
+        use std::path::{Path, PathBuf};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        use crate::rwtransaction_wrapper::{LockDataRef, Transaction};
+        use crate::timestamp::Timestamp;
+        use crate::wal_watcher::WalLoader;
+        use crate::{DbContext, TypedValue};
+
+        fn temp_dir() -> PathBuf {
+            std::env::temp_dir().join(format!("metastore-recovery-{}", rand::random::<u64>()))
+        }
+
+        fn read(ctx: &DbContext, key: &str) -> Option<TypedValue> {
+            let mut txn = Transaction::new_with_time(ctx, Timestamp::now());
+            txn.read_mvcc(ctx, &key.into())
+                .ok()
+                .map(|a| a.into_inner().1)
+        }
+
+        fn write(ctx: &DbContext, kv: &[(&str, &str)]) -> Timestamp {
+            let time = Timestamp::now();
+            let mut txn = Transaction::new_with_time(ctx, time);
+            for (k, v) in kv {
+                txn.write(ctx, &(*k).into(), (*v).into()).unwrap();
+            }
+            txn.commit(ctx).unwrap();
+            time
+        }
+
+        // Simulates the process getting killed: nothing runs on drop.
+        fn crash(ctx: DbContext) {
+            std::mem::forget(ctx);
+        }
+
+        fn segments(dir: &Path) -> Vec<PathBuf> {
+            let mut paths: Vec<PathBuf> = std::fs::read_dir(dir.join("wal"))
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            paths.sort();
+            paths
+        }
+
+        fn last_segment(dir: &Path) -> PathBuf {
+            segments(dir).pop().unwrap()
+        }
+
+        #[test]
+        fn committed_transactions_survive_crash() {
+            let dir = temp_dir();
+            let ctx = DbContext::open(&dir).unwrap();
+            write(&ctx, &[("/test/a", "1"), ("/test/b", "2")]);
+            let last_commit = write(&ctx, &[("/test/a", "3")]);
+
+            let mut uncommitted = Transaction::new_with_time(&ctx, Timestamp::now());
+            uncommitted
+                .write(&ctx, &"/test/c".into(), "4".into())
+                .unwrap();
+            crash(ctx);
+
+            let ctx = DbContext::open(&dir).unwrap();
+            assert_eq!(read(&ctx, "/test/a"), Some("3".into()));
+            assert_eq!(read(&ctx, "/test/b"), Some("2".into()));
+            assert_eq!(read(&ctx, "/test/c"), None);
+            assert!(Timestamp::now() > last_commit);
+
+            // New transactions are appended to the same log.
+            write(&ctx, &[("/test/d", "5")]);
+            crash(ctx);
+
+            let ctx = DbContext::open(&dir).unwrap();
+            assert_eq!(read(&ctx, "/test/a"), Some("3".into()));
+            assert_eq!(read(&ctx, "/test/d"), Some("5".into()));
+
+            std::mem::drop(ctx);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn commit_after_abort_isnt_logged() {
+            let dir = temp_dir();
+            let ctx = DbContext::open(&dir).unwrap();
+            let mut txn = Transaction::new_with_time(&ctx, Timestamp::now());
+            txn.write(&ctx, &"/test/a".into(), "1".into()).unwrap();
+            txn.abort(&ctx).unwrap();
+            assert!(txn.commit(&ctx).is_err());
+            assert_eq!(read(&ctx, "/test/a"), None);
+            crash(ctx);
+
+            let ctx = DbContext::open(&dir).unwrap();
+            assert_eq!(read(&ctx, "/test/a"), None);
+
+            std::mem::drop(ctx);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn checkpoint_then_replay_remaining_log() {
+            let dir = temp_dir();
+            let ctx = DbContext::open(&dir).unwrap();
+            write(&ctx, &[("/test/a", "1")]);
+            ctx.checkpoint().unwrap();
+            write(&ctx, &[("/test/a", "2"), ("/test/b", "3")]);
+            crash(ctx);
+
+            let ctx = DbContext::open(&dir).unwrap();
+            assert_eq!(read(&ctx, "/test/a"), Some("2".into()));
+            assert_eq!(read(&ctx, "/test/b"), Some("3".into()));
+
+            std::mem::drop(ctx);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn torn_tail_is_truncated() {
+            use std::io::Write;
+
+            let dir = temp_dir();
+            let ctx = DbContext::open(&dir).unwrap();
+            write(&ctx, &[("/test/a", "1")]);
+            write(&ctx, &[("/test/b", "2")]);
+            crash(ctx);
+
+            // Half of a record header, as if the process got killed while appending.
+            let log_path = last_segment(&dir);
+            let valid_len = std::fs::metadata(&log_path).unwrap().len();
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(&log_path)
+                .unwrap()
+                .write_all(&[7, 0, 0])
+                .unwrap();
+
+            let ctx = DbContext::open(&dir).unwrap();
+            assert_eq!(std::fs::metadata(&log_path).unwrap().len(), valid_len);
+            assert_eq!(read(&ctx, "/test/a"), Some("1".into()));
+            assert_eq!(read(&ctx, "/test/b"), Some("2".into()));
+
+            write(&ctx, &[("/test/c", "3")]);
+            crash(ctx);
+            let ctx = DbContext::open(&dir).unwrap();
+            assert_eq!(read(&ctx, "/test/c"), Some("3".into()));
+
+            std::mem::drop(ctx);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn corrupted_record_is_truncated() {
+            let dir = temp_dir();
+            let ctx = DbContext::open(&dir).unwrap();
+            write(&ctx, &[("/test/a", "1")]);
+            write(&ctx, &[("/test/b", "2")]);
+            crash(ctx);
+
+            let log_path = last_segment(&dir);
+            let mut contents = std::fs::read(&log_path).unwrap();
+            let last = contents.len() - 1;
+            contents[last] ^= 0xff;
+            std::fs::write(&log_path, contents).unwrap();
+
+            let ctx = DbContext::open(&dir).unwrap();
+            assert_eq!(read(&ctx, "/test/a"), Some("1".into()));
+            assert_eq!(read(&ctx, "/test/b"), None);
+            assert!(ctx.wallog.to_string().contains("/test/a"));
+
+            std::mem::drop(ctx);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn torn_segment_header_is_rewritten() {
+            let dir = temp_dir();
+            let ctx = DbContext::open(&dir).unwrap();
+            write(&ctx, &[("/test/a", "1")]);
+            crash(ctx);
+
+            // As if we crashed while creating the next segment.
+            let last = last_segment(&dir);
+            let next = crate::wal_watcher::segments::path(&dir.join("wal"), segments(&dir).len() as u64);
+            assert!(next > last);
+            std::fs::write(&next, &std::fs::read(&last).unwrap()[..3]).unwrap();
+
+            let ctx = DbContext::open(&dir).unwrap();
+            assert_eq!(read(&ctx, "/test/a"), Some("1".into()));
+            write(&ctx, &[("/test/b", "2")]);
+            crash(ctx);
+
+            let ctx = DbContext::open(&dir).unwrap();
+            assert_eq!(read(&ctx, "/test/b"), Some("2".into()));
+
+            std::mem::drop(ctx);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn corruption_before_the_last_segment_is_an_error() {
+            let dir = temp_dir();
+            let mut ctx = DbContext::open(&dir).unwrap();
+            ctx.wallog.set_segment_size(200);
+            for i in 0..20 {
+                write(&ctx, &[(&format!("/test/{}", i), "value")]);
+            }
+            crash(ctx);
+
+            let first = segments(&dir).remove(0);
+            let mut contents = std::fs::read(&first).unwrap();
+            let last = contents.len() - 1;
+            contents[last] ^= 0xff;
+            std::fs::write(&first, contents).unwrap();
+
+            // The later segments hold committed transactions, they must not be dropped silently.
+            let count = segments(&dir).len();
+            assert!(DbContext::open(&dir).is_err());
+            assert_eq!(segments(&dir).len(), count);
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn loading_a_broken_log_is_an_error() {
+            let dir = temp_dir();
+            let mut ctx = DbContext::open(&dir).unwrap();
+            ctx.wallog.set_segment_size(200);
+            for i in 0..20 {
+                write(&ctx, &[(&format!("/test/{}", i), "value")]);
+            }
+
+            let first = segments(&dir).remove(0);
+            let mut contents = std::fs::read(&first).unwrap();
+            let last = contents.len() - 1;
+            contents[last] ^= 0xff;
+            std::fs::write(&first, contents).unwrap();
+            assert!(ctx.wallog.load().is_err());
+
+            std::fs::remove_dir_all(&dir).unwrap();
+            assert!(ctx.wallog.load().is_err());
+            crash(ctx);
+        }
+
+        fn copy_log(from: &Path, to: &Path) {
+            std::fs::create_dir_all(to.join("wal")).unwrap();
+            for segment in segments(from) {
+                std::fs::copy(&segment, to.join("wal").join(segment.file_name().unwrap())).unwrap();
+            }
+        }
+
+        #[test]
+        fn log_is_split_into_segments() {
+            let dir = temp_dir();
+            let mut ctx = DbContext::open(&dir).unwrap();
+            ctx.wallog.set_segment_size(200);
+            for i in 0..20 {
+                write(&ctx, &[(&format!("/test/{}", i), "value")]);
+            }
+            let count = segments(&dir).len();
+            assert!(count > 2, "only {} segments", count);
+            crash(ctx);
+
+            let ctx = DbContext::open(&dir).unwrap();
+            for i in 0..20 {
+                assert_eq!(read(&ctx, &format!("/test/{}", i)), Some("value".into()));
+            }
+
+            std::mem::drop(ctx);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn checkpoint_removes_covered_segments() {
+            let dir = temp_dir();
+            let mut ctx = DbContext::open(&dir).unwrap();
+            ctx.wallog.set_segment_size(200);
+            for i in 0..20 {
+                write(&ctx, &[(&format!("/test/{}", i), "1")]);
+            }
+            ctx.checkpoint().unwrap();
+            // Only the fresh segment started by the checkpoint is left.
+            assert_eq!(segments(&dir), vec![last_segment(&dir)]);
+
+            write(&ctx, &[("/test/0", "2")]);
+            let live = segments(&dir);
+            crash(ctx);
+
+            let ctx = DbContext::open(&dir).unwrap();
+            assert_eq!(segments(&dir), live);
+            assert_eq!(read(&ctx, "/test/0"), Some("2".into()));
+            assert_eq!(read(&ctx, "/test/19"), Some("1".into()));
+
+            std::mem::drop(ctx);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn segments_left_over_from_checkpoint_are_removed() {
+            let dir = temp_dir();
+            let ctx = DbContext::open(&dir).unwrap();
+            write(&ctx, &[("/test/a", "1")]);
+            let old_segments = segments(&dir);
+            let backup = temp_dir();
+            copy_log(&dir, &backup);
+
+            ctx.checkpoint().unwrap();
+            write(&ctx, &[("/test/a", "2")]);
+            crash(ctx);
+
+            // As if we crashed after writing the manifest but before deleting the covered segments.
+            for segment in &old_segments {
+                std::fs::copy(backup.join("wal").join(segment.file_name().unwrap()), segment).unwrap();
+            }
+
+            let ctx = DbContext::open(&dir).unwrap();
+            assert!(old_segments.iter().all(|segment| !segment.exists()));
+            assert_eq!(read(&ctx, "/test/a"), Some("2".into()));
+
+            std::mem::drop(ctx);
+            std::fs::remove_dir_all(dir).unwrap();
+            std::fs::remove_dir_all(backup).unwrap();
+        }
+
+        #[test]
+        fn crash_in_the_middle_of_writing() {
+            // Copying the log while another thread is committing gives us the log as it would've been
+            // if the writer got killed at that moment, possibly in the middle of a record.
+            let dir = temp_dir();
+            let snapshots: Vec<PathBuf> = (0..5).map(|_| temp_dir()).collect();
+            let ctx = DbContext::open(&dir).unwrap();
+            let done = AtomicBool::new(false);
+
+            crossbeam::scope(|s| {
+                s.spawn(|_| {
+                    for i in 0..300 {
+                        let i = i.to_string();
+                        write(
+                            &ctx,
+                            &[(&format!("/x/{}", i), &i), (&format!("/y/{}", i), &i)],
+                        );
+                    }
+                    done.store(true, Ordering::SeqCst);
+                });
+                for snapshot in &snapshots {
+                    std::thread::sleep(std::time::Duration::from_millis(5));
+                    copy_log(&dir, snapshot);
+                }
+            })
+            .unwrap();
+            assert!(done.load(Ordering::SeqCst));
+
+            for snapshot in &snapshots {
+                let recovered = DbContext::open(snapshot).unwrap();
+                // Transactions commit in order, so the recovered ones must be a prefix, and each one all-or-nothing.
+                let count = (0..300)
+                    .take_while(|i| read(&recovered, &format!("/x/{}", i)).is_some())
+                    .count();
+                for i in 0..300 {
+                    let expected = if i < count {
+                        Some(TypedValue::from(i.to_string()))
+                    } else {
+                        None
+                    };
+                    assert_eq!(read(&recovered, &format!("/x/{}", i)), expected);
+                    assert_eq!(read(&recovered, &format!("/y/{}", i)), expected);
+                }
+                std::mem::drop(recovered);
+                std::fs::remove_dir_all(snapshot).unwrap();
+            }
+
+            std::mem::drop(ctx);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        fn prepare(ctx: &DbContext, kv: &[(&str, &str)]) -> LockDataRef {
+            let mut txn = Transaction::new_with_time(ctx, Timestamp::now());
+            for (k, v) in kv {
+                txn.write(ctx, &(*k).into(), (*v).into()).unwrap();
+            }
+            txn.prepare(ctx).unwrap();
+            txn.txn
+        }
+
+        #[test]
+        fn prepared_transactions_stay_in_doubt() {
+            for commit in [true, false] {
+                let dir = temp_dir();
+                let ctx = DbContext::open(&dir).unwrap();
+                write(&ctx, &[("/test/a", "1")]);
+                let txn = prepare(&ctx, &[("/test/a", "2"), ("/test/b", "3")]);
+                crash(ctx);
+
+                let ctx = DbContext::open(&dir).unwrap();
+                assert_eq!(ctx.in_doubt_transactions(), vec![txn]);
+                // Nobody can read past the intents until we know the outcome.
+                assert_eq!(read(&ctx, "/test/a"), None);
+                ctx.resolve_in_doubt(txn, commit).unwrap();
+                crash(ctx);
+
+                let ctx = DbContext::open(&dir).unwrap();
+                assert!(ctx.in_doubt_transactions().is_empty());
+                let (a, b) = if commit {
+                    (Some("2".into()), Some("3".into()))
+                } else {
+                    (Some("1".into()), None)
+                };
+                assert_eq!(read(&ctx, "/test/a"), a);
+                assert_eq!(read(&ctx, "/test/b"), b);
+
+                std::mem::drop(ctx);
+                std::fs::remove_dir_all(dir).unwrap();
+            }
+        }
+
+        #[test]
+        fn prepared_transactions_survive_checkpoint() {
+            let dir = temp_dir();
+            let ctx = DbContext::open(&dir).unwrap();
+            let txn = prepare(&ctx, &[("/test/a", "1")]);
+            ctx.checkpoint().unwrap();
+            crash(ctx);
+
+            let ctx = DbContext::open(&dir).unwrap();
+            assert_eq!(ctx.in_doubt_transactions(), vec![txn]);
+            ctx.resolve_in_doubt(txn, true).unwrap();
+            assert_eq!(read(&ctx, "/test/a"), Some("1".into()));
+            crash(ctx);
+
+            // The decision is replayed on top of the checkpoint.
+            let ctx = DbContext::open(&dir).unwrap();
+            assert!(ctx.in_doubt_transactions().is_empty());
+            assert_eq!(read(&ctx, "/test/a"), Some("1".into()));
+
+            std::mem::drop(ctx);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    mod durability {
+        use std::time::Duration;
+
+        use crate::rwtransaction_wrapper::Transaction;
+        use crate::timestamp::Timestamp;
+        use crate::wal_watcher::WalDurability;
+        use crate::DbContext;
+
+        fn write(ctx: &DbContext, key: &str) {
+            let mut txn = Transaction::new_with_time(ctx, Timestamp::now());
+            txn.write(ctx, &key.into(), "1".into()).unwrap();
+            txn.commit(ctx).unwrap();
+        }
+
+        fn open(durability: WalDurability) -> (DbContext, std::path::PathBuf) {
+            let dir =
+                std::env::temp_dir().join(format!("metastore-durability-{}", rand::random::<u64>()));
+            (DbContext::open_with_durability(&dir, durability).unwrap(), dir)
+        }
+
+        #[test]
+        fn sync_per_commit() {
+            for (durability, expected_syncs) in vec![
+                (WalDurability::NoSync, 0),
+                (WalDurability::SyncPerCommit, 10),
+            ] {
+                let (ctx, dir) = open(durability);
+                for i in 0..10 {
+                    write(&ctx, &format!("/test/{}", i));
+                }
+                assert_eq!(ctx.wallog.sync_count(), expected_syncs);
+
+                std::mem::drop(ctx);
+                std::fs::remove_dir_all(dir).unwrap();
+            }
+        }
+
+        #[test]
+        fn group_commit_shares_fsyncs() {
+            let (ctx, dir) = open(WalDurability::GroupCommit {
+                max_delay: Duration::from_millis(5),
+                max_batch: 8,
+            });
+
+            crossbeam::scope(|s| {
+                for thread in 0..8 {
+                    let ctx = &ctx;
+                    s.spawn(move |_| {
+                        for i in 0..25 {
+                            write(ctx, &format!("/test/{}/{}", thread, i));
+                        }
+                    });
+                }
+            })
+            .unwrap();
+
+            let syncs = ctx.wallog.sync_count();
+            assert!(syncs > 0 && syncs < 200, "{} fsyncs for 200 commits", syncs);
+
+            std::mem::forget(ctx);
+            let ctx = DbContext::open(&dir).unwrap();
+            let mut txn = Transaction::new_with_time(&ctx, Timestamp::now());
+            for thread in 0..8 {
+                for i in 0..25 {
+                    let key = format!("/test/{}/{}", thread, i);
+                    assert!(txn.read_mvcc(&ctx, &key.as_str().into()).is_ok());
+                }
+            }
+
+            std::mem::drop(ctx);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
 }
@@ -8,7 +8,21 @@ use super::WalTxn;
 pub fn apply_wal_txn_checked(waltxn: WalTxn, ctx: &DbContext) {
     assert!(ctx.replicators.is_none());
     let mut txn = Transaction::new_with_time(ctx, waltxn.timestamp);
+    replay_ops(waltxn, &mut txn, ctx);
+    txn.commit(&ctx).unwrap();
+}
 
+// Redoes the writes of a prepared transaction and prepares it again, leaving it to a later
+// commit or abort record (or to `DbContext::resolve_in_doubt`) to finish it.
+pub fn apply_prepared(waltxn: WalTxn, ctx: &DbContext) -> Transaction {
+    assert!(ctx.replicators.is_none());
+    let mut txn = Transaction::new_with_time_id(ctx, waltxn.timestamp, waltxn.id);
+    replay_ops(waltxn, &mut txn, ctx);
+    txn.prepare(ctx).unwrap();
+    txn
+}
+
+fn replay_ops(waltxn: WalTxn, txn: &mut Transaction, ctx: &DbContext) {
     for op in waltxn.ops {
         match op {
             Operation::Write(k, v) => {
@@ -27,6 +41,4 @@ pub fn apply_wal_txn_checked(waltxn: WalTxn, ctx: &DbContext) {
             }
         }
     }
-
-    txn.commit(&ctx).unwrap();
 }
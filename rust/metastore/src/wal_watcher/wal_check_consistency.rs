@@ -18,14 +18,13 @@ pub fn check_func1<A: DatabaseInterface + ?Sized>(
     db2.new_transaction(&txn2);
 
     let ret2 = db2.serve_range_read(txn2, &"/".into())??;
-    let mut ret: Result<_, String> = Err("a".into());
-    while ret.is_err() {
-        match ret.unwrap_err().as_str() {
-            "a" => {}
-            err => println!("check {}", err),
+    let mut ret = Err(None);
+    while let Err(err) = ret {
+        if let Some(err) = err {
+            println!("check {}", err);
         }
         std::thread::sleep(Duration::from_millis(1000));
-        ret = txn.read_range_owned(db, &"/".into());
+        ret = txn.read_range_owned(db, &"/".into()).map_err(Some);
     }
     let ret = ret.unwrap();
 